@@ -0,0 +1,181 @@
+use services::ledger::merkletree::proof::Proof;
+use services::ledger::merkletree::tree::TreeLeafData;
+use utils::crypto::hash::{Hash, MerkleHasher, eq_ct};
+use errors::common::CommonError;
+
+/// An absence proof represent the fact that a `value` is not a leaf
+/// of a `Tree` whose leaves are sorted in ascending order.
+///
+/// It holds the inclusion proofs of the leaves right below and right above
+/// `value`: if they are adjacent in the tree, no leaf can hold `value`.
+/// `lower` is `None` if `value` is lower than every leaf, and `upper` is `None`
+/// if it is greater than every leaf.
+#[derive(Clone, Debug)]
+pub struct AbsenceProof {
+    /// The value proved absent
+    pub value: TreeLeafData,
+
+    /// The proof of the greatest leaf lower than `value`
+    pub lower: Option<Proof>,
+
+    /// The proof of the lowest leaf greater than `value`
+    pub upper: Option<Proof>
+}
+
+impl AbsenceProof {
+
+    /// Constructs a new `AbsenceProof`
+    pub fn new(value: TreeLeafData, lower: Option<Proof>, upper: Option<Proof>) -> Self {
+        AbsenceProof {
+            value: value,
+            lower: lower,
+            upper: upper
+        }
+    }
+
+    /// Checks whether this proof shows that `value` is absent from the sorted tree
+    /// with the trusted root hash `root_hash` and `tree_size` leaves.
+    ///
+    /// Both neighbor proofs must lead to `root_hash` with their values bound
+    /// to their leaf hashes, must surround `value`, and their positions, derived
+    /// from their sibling hashes, must be consecutive. A missing neighbor must be
+    /// a missing first leaf for `lower`, and a missing last leaf for `upper`.
+    pub fn validate(&self, root_hash: &[u8], tree_size: usize) -> Result<bool, CommonError> {
+        self.validate_with_hasher(&Hash {}, root_hash, tree_size)
+    }
+
+    /// Same as `validate` but for a tree hashed with the given `hasher`.
+    pub fn validate_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8], tree_size: usize) -> Result<bool, CommonError> {
+        let lower_index = match self.lower {
+            Some(ref proof) => match AbsenceProof::neighbor_index(hasher, proof, root_hash, tree_size)? {
+                Some(index) if proof.value < self.value => Some(index),
+                _ => return Ok(false)
+            },
+            None => None
+        };
+
+        let upper_index = match self.upper {
+            Some(ref proof) => match AbsenceProof::neighbor_index(hasher, proof, root_hash, tree_size)? {
+                Some(index) if proof.value > self.value => Some(index),
+                _ => return Ok(false)
+            },
+            None => None
+        };
+
+        Ok(match (lower_index, upper_index) {
+            (Some(lower), Some(upper)) => lower + 1 == upper,
+            (Some(lower), None) => lower + 1 == tree_size,
+            (None, Some(upper)) => upper == 0,
+            // only an empty tree has no leaves around the value, and its root is the empty hash
+            (None, None) => tree_size == 0 && eq_ct(root_hash, &hasher.hash_empty()?)
+        })
+    }
+
+    /// Returns the position of the leaf proved by `proof`,
+    /// or `None` if the proof doesn't lead to `root_hash`.
+    fn neighbor_index<H: MerkleHasher>(hasher: &H, proof: &Proof, root_hash: &[u8], tree_size: usize) -> Result<Option<usize>, CommonError> {
+        if !proof.validate_value_with_hasher(hasher, &proof.value, root_hash)? {
+            return Ok(None);
+        }
+
+        Ok(proof.leaf_index(tree_size))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::tree::Tree;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i * 2 + 10).into_bytes()).collect()
+    }
+
+    #[test]
+    fn gen_absence_proof_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for i in 0..count + 1 {
+                let value = format!("value{:02}", i * 2 + 9).into_bytes();
+                let proof = tree.gen_absence_proof(&value).unwrap();
+
+                assert_eq!(proof.lower.is_some(), i > 0);
+                assert_eq!(proof.upper.is_some(), i < count);
+                assert!(proof.validate(tree.hash(), count).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn gen_absence_proof_works_for_present_value() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        for value in values(5) {
+            assert!(tree.gen_absence_proof(&value).is_none());
+        }
+    }
+
+    #[test]
+    fn gen_absence_proof_works_for_empty_tree() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+        let proof = tree.gen_absence_proof(&b"value".to_vec()).unwrap();
+
+        assert!(proof.lower.is_none() && proof.upper.is_none());
+        assert!(proof.validate(tree.hash(), 0).unwrap());
+        assert!(!proof.validate(tree.hash(), 1).unwrap());
+        assert!(!proof.validate(Tree::from_leaves(values(1)).unwrap().hash(), 0).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_non_adjacent_neighbors() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let mut proof = tree.gen_absence_proof(&b"value13".to_vec()).unwrap();
+        proof.upper = tree.gen_proof_by_index(3).ok();
+        assert!(!proof.validate(tree.hash(), 5).unwrap());
+
+        let mut proof = tree.gen_absence_proof(&b"value13".to_vec()).unwrap();
+        proof.upper = None;
+        assert!(!proof.validate(tree.hash(), 5).unwrap());
+
+        let mut proof = tree.gen_absence_proof(&b"value09".to_vec()).unwrap();
+        proof.upper = tree.gen_proof_by_index(1).ok();
+        assert!(!proof.validate(tree.hash(), 5).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_value_outside_neighbors() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let mut proof = tree.gen_absence_proof(&b"value13".to_vec()).unwrap();
+        proof.value = b"value12".to_vec();
+        assert!(!proof.validate(tree.hash(), 5).unwrap());
+
+        proof.value = b"value15".to_vec();
+        assert!(!proof.validate(tree.hash(), 5).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_other_root_or_size() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        let proof = tree.gen_absence_proof(&b"value13".to_vec()).unwrap();
+        assert!(!proof.validate(other.hash(), 5).unwrap());
+
+        let proof = tree.gen_absence_proof(&b"value99".to_vec()).unwrap();
+        assert!(proof.validate(tree.hash(), 5).unwrap());
+        assert!(!proof.validate(tree.hash(), 6).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_forged_neighbor_value() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let mut proof = tree.gen_absence_proof(&b"value13".to_vec()).unwrap();
+        proof.lower.as_mut().unwrap().value = b"value11".to_vec();
+        assert!(!proof.validate(tree.hash(), 5).unwrap());
+    }
+}
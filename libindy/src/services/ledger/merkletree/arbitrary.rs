@@ -0,0 +1,66 @@
+extern crate quickcheck;
+
+use services::ledger::merkletree::proof::Proof;
+use services::ledger::merkletree::tree::{Tree, TreeLeafData};
+
+use self::quickcheck::{Arbitrary, Gen};
+
+/// Generates well-formed trees with `from_leaves` over random leaves, so their hashes
+/// are consistent and `verify_root` always holds. Shrinking drops leaves.
+impl Arbitrary for Tree {
+
+    fn arbitrary<G: Gen>(g: &mut G) -> Tree {
+        Tree::from_leaves(Vec::<TreeLeafData>::arbitrary(g))
+            .expect("Hashing the leaves of an arbitrary tree failed")
+    }
+
+    fn shrink(&self) -> Box<Iterator<Item = Tree>> {
+        Box::new(self.clone().into_values().shrink()
+            .filter_map(|values| Tree::from_leaves(values).ok()))
+    }
+
+}
+
+/// Generates valid proofs of a random leaf of an arbitrary tree, with at least one leaf,
+/// so `validate` against their own root hash always holds.
+impl Arbitrary for Proof {
+
+    fn arbitrary<G: Gen>(g: &mut G) -> Proof {
+        let mut values = Vec::<TreeLeafData>::arbitrary(g);
+        if values.is_empty() {
+            values.push(TreeLeafData::arbitrary(g));
+        }
+
+        let index = usize::arbitrary(g) % values.len();
+
+        Tree::from_leaves(values)
+            .map_err(From::from)
+            .and_then(|tree| tree.gen_proof_by_index(index))
+            .expect("Generating the proof of an arbitrary tree failed")
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::quickcheck::quickcheck;
+
+    #[test]
+    fn arbitrary_tree_works() {
+        fn prop(tree: Tree) -> bool {
+            tree.verify_root().unwrap() && tree.shrink().all(|tree| tree.verify_root().unwrap())
+        }
+
+        quickcheck(prop as fn(Tree) -> bool);
+    }
+
+    #[test]
+    fn arbitrary_proof_works() {
+        fn prop(proof: Proof) -> bool {
+            proof.validate(&proof.root_hash).unwrap()
+        }
+
+        quickcheck(prop as fn(Proof) -> bool);
+    }
+}
@@ -0,0 +1,150 @@
+use services::ledger::merkletree::tree::{Tree, TreeLeafData};
+use utils::crypto::hash::{Hash, MerkleHasher};
+use errors::common::CommonError;
+
+/// Builds a `Tree` from a stream of values whose count isn't known upfront.
+///
+/// Pushed leaves are kept as perfect subtrees of decreasing sizes, the peaks of
+/// a Merkle mountain range: two peaks of the same size are merged as soon as they
+/// appear, so a push hashes O(log n) nodes at worst and no value is ever rehashed.
+/// `build` joins the peaks from right to left, which gives the same tree as `from_leaves`.
+#[allow(missing_debug_implementations)]
+pub struct MerkleTreeBuilder<H: MerkleHasher = Hash> {
+    hasher: H,
+    peaks: Vec<Tree>
+}
+
+impl MerkleTreeBuilder<Hash> {
+
+    /// Constructs a new builder hashing with the default `Hash`
+    pub fn new() -> Self {
+        MerkleTreeBuilder::with_hasher(Hash {})
+    }
+
+}
+
+impl Default for MerkleTreeBuilder<Hash> {
+
+    fn default() -> Self {
+        MerkleTreeBuilder::new()
+    }
+
+}
+
+impl<H: MerkleHasher> MerkleTreeBuilder<H> {
+
+    /// Constructs a new builder hashing with the given `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        MerkleTreeBuilder {
+            hasher: hasher,
+            peaks: Vec::new()
+        }
+    }
+
+    /// Returns the number of values pushed so far.
+    pub fn len(&self) -> usize {
+        self.peaks.iter().map(Tree::get_count).sum()
+    }
+
+    /// Returns whether no value was pushed yet.
+    pub fn is_empty(&self) -> bool {
+        self.peaks.is_empty()
+    }
+
+    /// Appends a new leaf.
+    pub fn push(&mut self, value: TreeLeafData) -> Result<(), CommonError> {
+        let mut tree = Tree::new_leaf_with_hasher(&self.hasher, value)?;
+
+        while self.peaks.last().map(Tree::get_count) == Some(tree.get_count()) {
+            let left = self.peaks.pop().unwrap();
+            tree = Tree::new_node_with_hasher(&self.hasher, left, tree)?;
+        }
+
+        self.peaks.push(tree);
+        Ok(())
+    }
+
+    /// Joins the pending peaks into the final tree.
+    /// Returns an `Empty` tree if no value was pushed.
+    pub fn build(self) -> Result<Tree, CommonError> {
+        let hasher = self.hasher;
+        let mut peaks = self.peaks;
+
+        let mut tree = match peaks.pop() {
+            Some(tree) => tree,
+            None => return Tree::empty_with_hasher(&hasher)
+        };
+
+        while let Some(left) = peaks.pop() {
+            tree = Tree::new_node_with_hasher(&hasher, left, tree)?;
+        }
+
+        Ok(tree)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn build_works_same_as_from_leaves() {
+        for count in 0..70 {
+            let mut builder = MerkleTreeBuilder::new();
+            for value in values(count) {
+                builder.push(value).unwrap();
+            }
+
+            assert_eq!(builder.len(), count);
+            assert_eq!(builder.build().unwrap(), Tree::from_leaves(values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn build_works_for_empty() {
+        let builder = MerkleTreeBuilder::new();
+        assert!(builder.is_empty());
+        assert_eq!(builder.build().unwrap(), Tree::from_leaves(vec![]).unwrap());
+    }
+
+    #[test]
+    fn build_works_with_hasher() {
+        struct PlainHasher {}
+
+        impl MerkleHasher for PlainHasher {
+            fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError> {
+                Ok(Hash::hash_nodes(&leaf, &leaf)?.to_vec())
+            }
+
+            fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError> {
+                Ok(Hash::hash_leaf(&[left, right].concat())?.to_vec())
+            }
+
+            fn hash_empty(&self) -> Result<Vec<u8>, CommonError> {
+                Ok(Vec::new())
+            }
+
+            fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError> {
+                Ok(Hash::hash_leaf(&[tag, root].concat())?.to_vec())
+            }
+
+            fn algorithm(&self) -> &'static str {
+                "plain"
+            }
+        }
+
+        for count in 0..20 {
+            let mut builder = MerkleTreeBuilder::with_hasher(PlainHasher {});
+            for value in values(count) {
+                builder.push(value).unwrap();
+            }
+
+            assert_eq!(builder.build().unwrap(), Tree::from_leaves_with_hasher(&PlainHasher {}, values(count)).unwrap());
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+
+use services::ledger::merkletree::tree::{Tree, TreeLeafData};
+use utils::crypto::hash::{Hash, MerkleHasher, eq_ct};
+use errors::common::CommonError;
+
+/// A consistency proof represent the fact that a tree of `new_size` leaves
+/// was built by appending leaves to a tree of `old_size` leaves.
+///
+/// Hashes follow the order of RFC 6962 (section 2.1.2), the same
+/// as the ledger nodes send them during catchup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsistencyProof {
+    /// The subtree hashes of the proof
+    pub hashes: Vec<Vec<u8>>
+}
+
+impl ConsistencyProof {
+
+    /// Constructs a new `ConsistencyProof`
+    pub fn new(hashes: Vec<Vec<u8>>) -> Self {
+        ConsistencyProof {
+            hashes: hashes
+        }
+    }
+
+    /// Checks whether the tree with root `new_root_hash` and `new_size` leaves
+    /// is an append-only extension of the tree with root `old_root_hash` and `old_size` leaves.
+    pub fn verify(&self,
+                  old_root_hash: &[u8], old_size: usize,
+                  new_root_hash: &[u8], new_size: usize) -> Result<bool, CommonError> {
+        self.verify_with_hasher(&Hash {}, old_root_hash, old_size, new_root_hash, new_size)
+    }
+
+    /// Same as `verify` but for trees hashed with the given `hasher`.
+    pub fn verify_with_hasher<H: MerkleHasher>(&self, hasher: &H,
+                                               old_root_hash: &[u8], old_size: usize,
+                                               new_root_hash: &[u8], new_size: usize) -> Result<bool, CommonError> {
+        if old_size == 0 {
+            // empty old tree
+            return Ok(true);
+        }
+        if old_size == new_size && old_root_hash == new_root_hash {
+            // identical trees
+            return Ok(true);
+        }
+        if old_size > new_size {
+            // old tree is bigger!
+            return Ok(false);
+        }
+
+        let mut old_node = old_size - 1;
+        let mut new_node = new_size - 1;
+
+        while old_node % 2 != 0 {
+            old_node = old_node / 2;
+            new_node = new_node / 2;
+        }
+
+        let mut proofs = self.hashes.iter();
+        let mut old_hash: Vec<u8>;
+        let mut new_hash: Vec<u8>;
+
+        if old_node != 0 {
+            new_hash = unwrap_opt_or_return!(proofs.next(), Ok(false)).to_vec();
+            old_hash = new_hash.clone();
+        } else {
+            new_hash = old_root_hash.to_vec();
+            old_hash = new_hash.clone();
+        }
+
+        while old_node != 0 {
+            if old_node % 2 != 0 {
+                let next_proof = unwrap_opt_or_return!(proofs.next(), Ok(false));
+                old_hash = hasher.hash_nodes(next_proof, &old_hash)?;
+                new_hash = hasher.hash_nodes(next_proof, &new_hash)?;
+            } else if old_node < new_node {
+                new_hash = hasher.hash_nodes(&new_hash,
+                                             unwrap_opt_or_return!(proofs.next(), Ok(false)))?;
+            }
+            old_node = old_node / 2;
+            new_node = new_node / 2;
+        }
+
+        while new_node != 0 {
+            let n = unwrap_opt_or_return!(proofs.next(), Ok(false));
+            new_hash = hasher.hash_nodes(&new_hash, n)?;
+            new_node = new_node / 2;
+        }
+
+        if !eq_ct(&new_hash, new_root_hash) {
+            // new hash differs
+            return Ok(false);
+        }
+
+        if !eq_ct(&old_hash, old_root_hash) {
+            // old hash differs
+            return Ok(false);
+        }
+
+        return Ok(true);
+    }
+
+}
+
+/// Checks a tree as its leaves stream in, without ever rebuilding it.
+///
+/// The verifier starts from the hashes of the perfect subtrees an old tree splits into,
+/// its peaks, and appends the incoming leaf hashes to them the same way `push` does,
+/// so each leaf costs O(log n) `hash_nodes` at worst. The roots expected at some sizes,
+/// e.g. the signed roots of a catchup target, are checked as soon as these sizes
+/// are reached: a leaf diverging from them is reported by the `push` reaching them.
+#[derive(Clone, Debug)]
+pub struct ConsistencyVerifier<H: MerkleHasher = Hash> {
+    /// The hasher of the leaves and nodes
+    hasher: H,
+
+    /// The sizes and hashes of the peaks, left to right
+    peaks: Vec<(usize, Vec<u8>)>,
+
+    /// The number of leaves so far
+    size: usize,
+
+    /// The roots expected at some sizes, not reached yet
+    expected: BTreeMap<usize, Vec<u8>>
+}
+
+impl ConsistencyVerifier<Hash> {
+
+    /// Starts from the tree of `old_size` leaves with the trusted root `old_root_hash`,
+    /// given its peak hashes left to right, one per bit set in `old_size` from the highest.
+    ///
+    /// Fails if the peaks don't lead to `old_root_hash`.
+    pub fn new(old_root_hash: &[u8], old_size: usize, old_peaks: Vec<Vec<u8>>) -> Result<Self, CommonError> {
+        ConsistencyVerifier::new_with_hasher(Hash {}, old_root_hash, old_size, old_peaks)
+    }
+
+    /// Starts from the given trusted `tree`, built by `from_leaves` or `push`.
+    pub fn from_tree(tree: &Tree) -> Result<Self, CommonError> {
+        ConsistencyVerifier::from_tree_with_hasher(Hash {}, tree)
+    }
+
+}
+
+impl<H: MerkleHasher> ConsistencyVerifier<H> {
+
+    /// Same as `new` but hashing with the given `hasher`
+    pub fn new_with_hasher(hasher: H, old_root_hash: &[u8], old_size: usize, old_peaks: Vec<Vec<u8>>) -> Result<Self, CommonError> {
+        if old_peaks.len() != old_size.count_ones() as usize {
+            return Err(CommonError::InvalidStructure(
+                format!("A tree of size {} has {} peaks, not {}", old_size, old_size.count_ones(), old_peaks.len())));
+        }
+
+        let mut remaining = old_size;
+        let mut peaks = Vec::with_capacity(old_peaks.len());
+
+        for hash in old_peaks {
+            // the highest power of two not above the leaves left
+            let size = (remaining + 1).next_power_of_two() / 2;
+            peaks.push((size, hash));
+            remaining -= size;
+        }
+
+        let verifier = ConsistencyVerifier {
+            hasher: hasher,
+            peaks: peaks,
+            size: old_size,
+            expected: BTreeMap::new()
+        };
+
+        if !eq_ct(&verifier.root_hash()?, old_root_hash) {
+            return Err(CommonError::InvalidStructure("Peaks don't match the old root hash".to_string()));
+        }
+
+        Ok(verifier)
+    }
+
+    /// Same as `from_tree` but for a `tree` hashed with the given `hasher`
+    pub fn from_tree_with_hasher(hasher: H, tree: &Tree) -> Result<Self, CommonError> {
+        let mut peaks = Vec::new();
+        let mut node = tree;
+
+        while let Tree::Node { ref left, ref right, count, .. } = *node {
+            if count.is_power_of_two() {
+                break;
+            }
+
+            peaks.push(left.hash().clone());
+            node = right;
+        }
+
+        if !node.is_empty() {
+            peaks.push(node.hash().clone());
+        }
+
+        ConsistencyVerifier::new_with_hasher(hasher, tree.hash(), tree.get_count(), peaks)
+    }
+
+    /// Expects the tree to have the root `root_hash` once it holds `size` leaves.
+    /// Fails if the tree already holds more leaves, or if it holds that many and the root differs.
+    pub fn expect_root(&mut self, size: usize, root_hash: Vec<u8>) -> Result<(), CommonError> {
+        if size < self.size {
+            return Err(CommonError::InvalidState(
+                format!("Tree already holds {} leaves, can't expect a root at size {}", self.size, size)));
+        }
+
+        self.expected.insert(size, root_hash);
+        self.check_expected()
+    }
+
+    /// Appends the leaf holding `value`.
+    pub fn push(&mut self, value: &TreeLeafData) -> Result<(), CommonError> {
+        let leaf_hash = self.hasher.hash_leaf(value)?;
+        self.push_leaf_hash(leaf_hash)
+    }
+
+    /// Appends a leaf given by its hash.
+    /// Fails if the tree doesn't have the root expected at its new size.
+    pub fn push_leaf_hash(&mut self, leaf_hash: Vec<u8>) -> Result<(), CommonError> {
+        let mut peak = (1, leaf_hash);
+
+        while self.peaks.last().map(|&(size, _)| size) == Some(peak.0) {
+            let (size, left) = self.peaks.pop().unwrap();
+            peak = (2 * size, self.hasher.hash_nodes(&left, &peak.1)?);
+        }
+
+        self.peaks.push(peak);
+        self.size += 1;
+        self.check_expected()
+    }
+
+    /// Returns the number of leaves so far.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the root hash of the tree so far.
+    pub fn root_hash(&self) -> Result<Vec<u8>, CommonError> {
+        let mut peaks = self.peaks.iter().rev();
+
+        let mut root = match peaks.next() {
+            Some(&(_, ref hash)) => hash.clone(),
+            None => return self.hasher.hash_empty()
+        };
+
+        for &(_, ref hash) in peaks {
+            root = self.hasher.hash_nodes(hash, &root)?;
+        }
+
+        Ok(root)
+    }
+
+    fn check_expected(&mut self) -> Result<(), CommonError> {
+        let expected = match self.expected.remove(&self.size) {
+            Some(expected) => expected,
+            None => return Ok(())
+        };
+
+        if !eq_ct(&self.root_hash()?, &expected) {
+            return Err(CommonError::InvalidStructure(
+                format!("Tree of size {} doesn't have the expected root", self.size)));
+        }
+
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::merkletree::MerkleTree;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn gen_consistency_proof_and_verify_work() {
+        for new_size in 1..20 {
+            let new_tree = Tree::from_leaves(values(new_size)).unwrap();
+
+            for old_size in 0..new_size + 1 {
+                let old_tree = Tree::from_leaves(values(old_size)).unwrap();
+                let proof = new_tree.gen_consistency_proof(old_size).unwrap();
+
+                assert!(proof.verify(old_tree.hash(), old_size, new_tree.hash(), new_size).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn gen_consistency_proof_works_with_merkle_tree_consistency_proof() {
+        for new_size in 2..20 {
+            let new_tree = Tree::from_leaves(values(new_size)).unwrap();
+
+            for old_size in 1..new_size {
+                let old_mt = MerkleTree::from_vec(values(old_size)).unwrap();
+                let proof = new_tree.gen_consistency_proof(old_size).unwrap();
+
+                assert!(old_mt.consistency_proof(new_tree.hash(), new_size, &proof.hashes).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn gen_consistency_proof_works_for_edge_sizes() {
+        let tree = Tree::from_leaves(values(8)).unwrap();
+
+        assert_eq!(tree.gen_consistency_proof(0).unwrap().hashes.len(), 0);
+        assert_eq!(tree.gen_consistency_proof(8).unwrap().hashes.len(), 0);
+        assert_eq!(tree.gen_consistency_proof(4).unwrap().hashes, {
+            match tree {
+                Tree::Node { ref right, .. } => vec![right.hash().clone()],
+                _ => unreachable!()
+            }
+        });
+        assert!(tree.gen_consistency_proof(9).is_none());
+    }
+
+    #[test]
+    fn consistency_verifier_works() {
+        for old_size in 0..12 {
+            let old_tree = Tree::from_leaves(values(old_size)).unwrap();
+
+            for new_size in old_size..20 {
+                let new_tree = Tree::from_leaves(values(new_size)).unwrap();
+
+                let mut verifier = ConsistencyVerifier::from_tree(&old_tree).unwrap();
+                verifier.expect_root(new_size, new_tree.hash().clone()).unwrap();
+
+                for (size, value) in values(new_size).iter().enumerate().skip(old_size) {
+                    verifier.push(value).unwrap();
+                    assert_eq!(verifier.size(), size + 1);
+                    assert_eq!(verifier.root_hash().unwrap(), *Tree::from_leaves(values(size + 1)).unwrap().hash());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_verifier_fails_on_diverging_leaf() {
+        let old_tree = Tree::from_leaves(values(5)).unwrap();
+        let mut verifier = ConsistencyVerifier::from_tree(&old_tree).unwrap();
+
+        verifier.expect_root(7, Tree::from_leaves(values(7)).unwrap().hash().clone()).unwrap();
+        verifier.expect_root(9, Tree::from_leaves(values(9)).unwrap().hash().clone()).unwrap();
+
+        verifier.push(&values(6)[5]).unwrap();
+        verifier.push(&values(7)[6]).unwrap();
+        verifier.push(&b"diverging".to_vec()).unwrap();
+        assert!(verifier.push(&values(9)[8]).is_err());
+    }
+
+    #[test]
+    fn consistency_verifier_works_for_wrong_old_root() {
+        let old_tree = Tree::from_leaves(values(6)).unwrap();
+        let other_tree = Tree::from_leaves(values(7)).unwrap();
+
+        let peaks = vec![Tree::from_leaves(values(4)).unwrap().hash().clone(), vec![0; 32]];
+        assert!(ConsistencyVerifier::new(old_tree.hash(), 6, peaks).is_err());
+        assert!(ConsistencyVerifier::new(other_tree.hash(), 7, vec![]).is_err());
+    }
+
+    #[test]
+    fn consistency_verifier_works_for_expected_roots() {
+        let mut verifier = ConsistencyVerifier::from_tree(&Tree::from_leaves(values(3)).unwrap()).unwrap();
+
+        assert!(verifier.expect_root(2, vec![]).is_err());
+        assert!(verifier.expect_root(3, Tree::from_leaves(values(3)).unwrap().hash().clone()).is_ok());
+        assert!(verifier.expect_root(3, vec![0; 32]).is_err());
+    }
+
+    #[test]
+    fn verify_works_for_other_trees() {
+        let new_tree = Tree::from_leaves(values(11)).unwrap();
+        let old_tree = Tree::from_leaves(values(6)).unwrap();
+        let other_tree = Tree::from_leaves(values(7)).unwrap();
+
+        let proof = new_tree.gen_consistency_proof(6).unwrap();
+
+        assert!(!proof.verify(other_tree.hash(), 6, new_tree.hash(), 11).unwrap());
+        assert!(!proof.verify(old_tree.hash(), 6, other_tree.hash(), 11).unwrap());
+        assert!(!proof.verify(old_tree.hash(), 12, new_tree.hash(), 11).unwrap());
+    }
+}
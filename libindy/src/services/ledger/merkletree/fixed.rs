@@ -0,0 +1,219 @@
+use std::cmp;
+use std::mem;
+use std::sync::Arc;
+
+use services::ledger::merkletree::tree::{Tree, TreeLeafData};
+use utils::crypto::hash::{Hash, HASHBYTES};
+use errors::common::CommonError;
+
+/// A SHA-256 digest stored inline.
+pub type FixedHash = [u8; HASHBYTES];
+
+/// A `Tree` hashed with the default `Hash`, whose nodes hold their hash inline
+/// as a `FixedHash` rather than in a `Vec<u8>` of its own.
+///
+/// `Tree` allocates every hash separately on the heap, so a tree of `n` leaves makes
+/// `2n - 1` allocations of `HASHBYTES` bytes plus the allocator overhead for its hashes
+/// alone. Here the hashes live in the nodes, which only leaves the allocations of the
+/// nodes themselves and of the leaf values. Digests of other lengths, e.g. from
+/// `Sha512Hash`, don't fit: use `Tree`. `into_tree` and `from_tree` convert between both
+/// to reach the rest of the `Vec<u8>` API, proofs included.
+///
+/// Children are owned through `Box`, so unlike `Tree` a clone copies the whole tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FixedTree {
+    Empty {
+        hash: FixedHash
+    },
+
+    Leaf {
+        hash: FixedHash,
+        value: TreeLeafData
+    },
+
+    Node {
+        hash: FixedHash,
+        count: usize,
+        left: Box<FixedTree>,
+        right: Box<FixedTree>
+    }
+}
+
+impl FixedTree {
+
+    /// Same as `Tree::from_leaves`, which gives the same shape and hashes.
+    pub fn from_leaves(values: Vec<TreeLeafData>) -> Result<FixedTree, CommonError> {
+        if values.is_empty() {
+            return Ok(FixedTree::Empty { hash: Hash::hash_empty()?.to_array() });
+        }
+
+        let mut cur = Vec::with_capacity(values.len());
+        for value in values {
+            cur.push(FixedTree::Leaf { hash: Hash::hash_leaf(&value)?.to_array(), value: value });
+        }
+
+        let take = |tree: &mut FixedTree| mem::replace(tree, FixedTree::Empty { hash: [0; HASHBYTES] });
+
+        while cur.len() > 1 {
+            let len = cur.len();
+
+            // the nodes at 2i and 2i + 1 are always read before the slot i is written
+            for i in 0..len / 2 {
+                let left = take(&mut cur[2 * i]);
+                let right = take(&mut cur[2 * i + 1]);
+                cur[i] = FixedTree::new_node(left, right)?;
+            }
+
+            if len % 2 == 1 {
+                cur[len / 2] = take(&mut cur[len - 1]);
+            }
+
+            cur.truncate((len + 1) / 2);
+        }
+
+        Ok(cur.remove(0))
+    }
+
+    /// Create a new node over the given children
+    pub fn new_node(left: FixedTree, right: FixedTree) -> Result<FixedTree, CommonError> {
+        Ok(FixedTree::Node {
+            hash: Hash::hash_nodes(left.hash(), right.hash())?.to_array(),
+            count: left.get_count() + right.get_count(),
+            left: Box::new(left),
+            right: Box::new(right)
+        })
+    }
+
+    /// Converts a `Tree` hashed with `Hash`, keeping its shape and hashes.
+    /// Fails if one of its hashes is not `HASHBYTES` long.
+    pub fn from_tree(tree: &Tree) -> Result<FixedTree, CommonError> {
+        Ok(match *tree {
+            Tree::Empty { ref hash } => FixedTree::Empty { hash: FixedTree::to_fixed(hash)? },
+            Tree::Leaf { ref hash, ref value } => FixedTree::Leaf { hash: FixedTree::to_fixed(hash)?, value: value.clone() },
+            Tree::Node { ref hash, ref left, ref right, count, .. } => FixedTree::Node {
+                hash: FixedTree::to_fixed(hash)?,
+                count: count,
+                left: Box::new(FixedTree::from_tree(left)?),
+                right: Box::new(FixedTree::from_tree(right)?)
+            }
+        })
+    }
+
+    /// Converts to a `Tree` with the same shape and hashes.
+    pub fn into_tree(self) -> Tree {
+        match self {
+            FixedTree::Empty { hash } => Tree::Empty { hash: hash.to_vec() },
+            FixedTree::Leaf { hash, value } => Tree::Leaf { hash: hash.to_vec(), value: value },
+            FixedTree::Node { hash, count, left, right } => {
+                let left = left.into_tree();
+                let right = right.into_tree();
+
+                Tree::Node {
+                    hash: hash.to_vec(),
+                    count: count,
+                    height: 1 + cmp::max(left.get_height(), right.get_height()),
+                    left: Arc::new(left),
+                    right: Arc::new(right)
+                }
+            }
+        }
+    }
+
+    /// Returns the hash of the root.
+    pub fn hash(&self) -> &FixedHash {
+        match *self {
+            FixedTree::Empty { ref hash } => hash,
+            FixedTree::Leaf { ref hash, .. } => hash,
+            FixedTree::Node { ref hash, .. } => hash
+        }
+    }
+
+    /// Returns the number of leaves in the tree, in O(1).
+    pub fn get_count(&self) -> usize {
+        match *self {
+            FixedTree::Empty { .. } => 0,
+            FixedTree::Leaf { .. } => 1,
+            FixedTree::Node { count, .. } => count
+        }
+    }
+
+    fn to_fixed(hash: &[u8]) -> Result<FixedHash, CommonError> {
+        if hash.len() != HASHBYTES {
+            return Err(CommonError::InvalidStructure(
+                format!("Hash of {} bytes doesn't fit a fixed-size tree of {} bytes hashes", hash.len(), HASHBYTES)));
+        }
+
+        let mut fixed = [0; HASHBYTES];
+        fixed.copy_from_slice(hash);
+        Ok(fixed)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::crypto::hash::Sha512Hash;
+    use std::time::Instant;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn from_leaves_works_same_as_tree() {
+        for count in 0..40 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let fixed = FixedTree::from_leaves(values(count)).unwrap();
+
+            assert_eq!(&fixed.hash().to_vec(), tree.hash());
+            assert_eq!(fixed.get_count(), count);
+            assert_eq!(FixedTree::from_tree(&tree).unwrap(), fixed);
+            assert_eq!(fixed.into_tree(), tree);
+        }
+    }
+
+    #[test]
+    fn from_tree_works_for_other_digest_lengths() {
+        let tree = Tree::from_leaves_with_hasher(&Sha512Hash {}, values(3)).unwrap();
+        assert!(FixedTree::from_tree(&tree).is_err());
+    }
+
+    /// Compares `Tree` and `FixedTree` over a million leaves, run it with
+    /// `cargo test measure_against_tree -- --ignored --nocapture`.
+    ///
+    /// The build times are measured, the memory figures are estimates computed from the type
+    /// sizes, not measured. They count the `Arc` or `Box` of every node but the root, with
+    /// the two reference counts of an `Arc`, the hash buffers of `Tree` and the leaf values,
+    /// but not the overhead of the allocator itself.
+    #[test]
+    #[ignore]
+    fn measure_against_tree() {
+        let count = 1 << 20;
+        let nodes = 2 * count - 1;
+        let values_bytes = values(count).iter().map(Vec::len).sum::<usize>();
+
+        let start = Instant::now();
+        let tree = Tree::from_leaves(values(count)).unwrap();
+        let tree_time = start.elapsed();
+
+        let start = Instant::now();
+        let fixed = FixedTree::from_leaves(values(count)).unwrap();
+        let fixed_time = start.elapsed();
+
+        assert_eq!(&fixed.hash().to_vec(), tree.hash());
+
+        // an `Arc` allocation holds a strong and a weak count before the node
+        let arc_node = 2 * mem::size_of::<usize>() + mem::size_of::<Tree>();
+        let tree_bytes = (nodes - 1) * arc_node + nodes * HASHBYTES + values_bytes;
+        let tree_allocations = (nodes - 1) + nodes + count;
+
+        let fixed_bytes = (nodes - 1) * mem::size_of::<FixedTree>() + values_bytes;
+        let fixed_allocations = (nodes - 1) + count;
+
+        println!("Tree:      built in {:?}, about {} bytes in {} allocations (estimated)",
+                 tree_time, tree_bytes, tree_allocations);
+        println!("FixedTree: built in {:?}, about {} bytes in {} allocations (estimated)",
+                 fixed_time, fixed_bytes, fixed_allocations);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use services::ledger::merkletree::tree::{Tree, left_count};
+
+/// A lookup table from leaf hashes to leaf positions, for `Tree::gen_proof_cached`.
+///
+/// `Tree::gen_proof` searches the whole tree for the leaf to prove, while with the
+/// index only the path from the root to the leaf is walked. The index is bound to
+/// the root hash and size of the tree it was built from: once the tree changes, e.g.
+/// after `push`, it is stale and has to be rebuilt with `Tree::build_proof_index`.
+#[derive(Clone, Debug)]
+pub struct ProofIndex {
+    root_hash: Vec<u8>,
+    tree_size: usize,
+    positions: HashMap<Vec<u8>, usize>
+}
+
+impl ProofIndex {
+
+    /// Builds the index of every leaf of `tree`.
+    /// A value found several times is indexed at its first position, as `Tree::gen_proof` finds it.
+    pub fn new(tree: &Tree) -> Self {
+        let mut positions = HashMap::with_capacity(tree.get_count());
+        ProofIndex::add_leaves(tree, 0, &mut positions);
+
+        ProofIndex {
+            root_hash: tree.hash().clone(),
+            tree_size: tree.get_count(),
+            positions: positions
+        }
+    }
+
+    /// Returns the position of the first leaf with hash `leaf_hash`, if any.
+    pub fn position(&self, leaf_hash: &[u8]) -> Option<usize> {
+        self.positions.get(leaf_hash).cloned()
+    }
+
+    /// Returns the number of distinct leaf hashes in the index.
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Returns whether the index has no leaves.
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Returns whether the index was built from a tree with the same root hash and size as `tree`.
+    pub fn is_valid_for(&self, tree: &Tree) -> bool {
+        self.tree_size == tree.get_count() && self.root_hash == *tree.hash()
+    }
+
+    /// Indexes in order the leaves of `tree`, whose first leaf is at position `offset`.
+    /// The `Empty` stubs of a pruned tree are skipped without shifting the positions.
+    fn add_leaves(tree: &Tree, offset: usize, positions: &mut HashMap<Vec<u8>, usize>) {
+        match *tree {
+            Tree::Empty { .. } => {},
+            Tree::Leaf { ref hash, .. } => {
+                if let Entry::Vacant(entry) = positions.entry(hash.clone()) {
+                    entry.insert(offset);
+                }
+            }
+            Tree::Node { ref left, ref right, count, .. } => {
+                ProofIndex::add_leaves(left, offset, positions);
+                ProofIndex::add_leaves(right, offset + left_count(count, left, right), positions);
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::tree::TreeLeafData;
+    use utils::crypto::hash::Hash;
+    use std::time::Instant;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn new_works() {
+        let tree = Tree::from_leaves(values(11)).unwrap();
+        let index = ProofIndex::new(&tree);
+
+        assert_eq!(index.len(), 11);
+        assert!(index.is_valid_for(&tree));
+
+        for (i, value) in values(11).iter().enumerate() {
+            assert_eq!(index.position(&Hash::hash_leaf(value).unwrap().to_vec()), Some(i));
+        }
+        assert_eq!(index.position(&Hash::hash_leaf(&b"unknown".to_vec()).unwrap().to_vec()), None);
+    }
+
+    #[test]
+    fn new_works_for_duplicate_values() {
+        let tree = Tree::from_leaves(vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec()]).unwrap();
+        let index = ProofIndex::new(&tree);
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.position(&Hash::hash_leaf(&b"a".to_vec()).unwrap().to_vec()), Some(0));
+    }
+
+    #[test]
+    fn gen_proof_cached_works_same_as_gen_proof() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let index = tree.build_proof_index();
+
+            for value in values(count + 1) {
+                assert_eq!(tree.gen_proof_cached(&index, value.clone()).unwrap(),
+                           tree.gen_proof(value).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn gen_proof_cached_works_for_stale_index() {
+        let mut tree = Tree::from_leaves(values(5)).unwrap();
+        let index = tree.build_proof_index();
+
+        tree.push(b"value5".to_vec()).unwrap();
+        assert!(!index.is_valid_for(&tree));
+        assert!(tree.gen_proof_cached(&index, b"value1".to_vec()).is_err());
+
+        let index = tree.build_proof_index();
+        assert!(tree.gen_proof_cached(&index, b"value5".to_vec()).unwrap().unwrap().validate(tree.hash()).unwrap());
+    }
+
+    /// Compares the proof throughput of `gen_proof` and `gen_proof_cached`, run it with
+    /// `cargo test measure_proof_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn measure_proof_throughput() {
+        let count = 1 << 16;
+        let proofs = 1000;
+        let tree = Tree::from_leaves(values(count)).unwrap();
+        let probes = (0..proofs).map(|i| format!("value{}", i * (count / proofs)).into_bytes()).collect::<Vec<_>>();
+
+        let start = Instant::now();
+        let uncached = probes.iter().map(|value| tree.gen_proof(value.clone()).unwrap().unwrap()).collect::<Vec<_>>();
+        let uncached_time = start.elapsed();
+
+        let start = Instant::now();
+        let index = tree.build_proof_index();
+        let index_time = start.elapsed();
+
+        let start = Instant::now();
+        let cached = probes.iter().map(|value| tree.gen_proof_cached(&index, value.clone()).unwrap().unwrap()).collect::<Vec<_>>();
+        let cached_time = start.elapsed();
+
+        assert_eq!(cached, uncached);
+
+        println!("gen_proof:        {} proofs over {} leaves in {:?}", proofs, count, uncached_time);
+        println!("gen_proof_cached: {} proofs over {} leaves in {:?}, plus {:?} to build the index",
+                 proofs, count, cached_time, index_time);
+    }
+}
@@ -0,0 +1,158 @@
+use std::error;
+use std::fmt;
+
+use services::ledger::merkletree::tree::TreeLeafData;
+use errors::common::CommonError;
+
+/// Rules a leaf value must follow to be accepted by `Tree::try_new_leaf`.
+///
+/// The default policy accepts any value, as `Tree::new_leaf` does. Validators that
+/// consider an empty or huge value as a bug can opt into rejecting them:
+///
+/// ```ignore
+/// let policy = LeafPolicy { allow_empty: false, max_len: Some(128 * 1024) };
+/// let leaf = Tree::try_new_leaf(&policy, value)?;
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeafPolicy {
+    /// Whether an empty value is accepted, `true` by default
+    pub allow_empty: bool,
+
+    /// The maximum length in bytes of a value, unbounded by default
+    pub max_len: Option<usize>
+}
+
+impl LeafPolicy {
+
+    /// Constructs the policy accepting any value
+    pub fn permissive() -> Self {
+        LeafPolicy {
+            allow_empty: true,
+            max_len: None
+        }
+    }
+
+    /// Checks that `value` follows this policy.
+    pub fn check(&self, value: &TreeLeafData) -> Result<(), LeafError> {
+        if value.is_empty() && !self.allow_empty {
+            return Err(LeafError::EmptyValue);
+        }
+
+        match self.max_len {
+            Some(max_len) if value.len() > max_len => Err(LeafError::ValueTooLong(value.len(), max_len)),
+            _ => Ok(())
+        }
+    }
+
+}
+
+impl Default for LeafPolicy {
+
+    fn default() -> Self {
+        LeafPolicy::permissive()
+    }
+
+}
+
+/// Reasons why `Tree::try_new_leaf` rejected a value.
+#[derive(Debug)]
+pub enum LeafError {
+    /// The value is empty while the policy doesn't allow it
+    EmptyValue,
+
+    /// The length of the value, and the maximum length allowed by the policy
+    ValueTooLong(usize, usize),
+
+    /// The policy accepted the value but hashing it failed
+    CommonError(CommonError)
+}
+
+impl fmt::Display for LeafError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LeafError::EmptyValue => write!(f, "Leaf value is empty"),
+            LeafError::ValueTooLong(len, max_len) =>
+                write!(f, "Leaf value of {} bytes is longer than {} bytes", len, max_len),
+            LeafError::CommonError(ref err) => err.fmt(f)
+        }
+    }
+}
+
+impl error::Error for LeafError {
+    fn description(&self) -> &str {
+        match *self {
+            LeafError::EmptyValue => "Leaf value is empty",
+            LeafError::ValueTooLong(..) => "Leaf value is too long",
+            LeafError::CommonError(ref err) => err.description()
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            LeafError::EmptyValue => None,
+            LeafError::ValueTooLong(..) => None,
+            LeafError::CommonError(ref err) => Some(err)
+        }
+    }
+}
+
+impl From<CommonError> for LeafError {
+    fn from(err: CommonError) -> Self {
+        LeafError::CommonError(err)
+    }
+}
+
+impl From<LeafError> for CommonError {
+    fn from(err: LeafError) -> Self {
+        match err {
+            LeafError::CommonError(err) => err,
+            err => CommonError::InvalidStructure(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::tree::Tree;
+
+    #[test]
+    fn try_new_leaf_works_for_default_policy() {
+        let policy = LeafPolicy::default();
+
+        for value in vec![Vec::new(), b"value".to_vec(), vec![0; 1024 * 1024]] {
+            assert_eq!(Tree::try_new_leaf(&policy, value.clone()).unwrap(), Tree::new_leaf(value).unwrap());
+        }
+    }
+
+    #[test]
+    fn try_new_leaf_works_for_empty_value() {
+        let policy = LeafPolicy { allow_empty: false, max_len: None };
+
+        match Tree::try_new_leaf(&policy, Vec::new()) {
+            Err(LeafError::EmptyValue) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+        assert!(Tree::try_new_leaf(&policy, b"v".to_vec()).is_ok());
+    }
+
+    #[test]
+    fn try_new_leaf_works_for_too_long_value() {
+        let policy = LeafPolicy { allow_empty: true, max_len: Some(5) };
+
+        match Tree::try_new_leaf(&policy, b"value0".to_vec()) {
+            Err(LeafError::ValueTooLong(6, 5)) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+        assert!(Tree::try_new_leaf(&policy, b"value".to_vec()).is_ok());
+        assert!(Tree::try_new_leaf(&policy, Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn leaf_error_converts_to_common_error() {
+        match CommonError::from(LeafError::EmptyValue) {
+            CommonError::InvalidStructure(_) => (),
+            err => panic!("unexpected error {:?}", err)
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use services::ledger::merkletree::tree::{ Tree, LeavesIterator, LeavesIntoIterator, TreeLeafData };
-use services::ledger::merkletree::proof::{ Proof, Lemma };
-use utils::crypto::hash::{Hash, HASHBYTES};
+use services::ledger::merkletree::proof::Proof;
+use utils::crypto::hash::{Hash, MerkleHasher};
 use errors::common::CommonError;
 
 /// A Merkle tree is a binary tree, with values of type `T` at the leafs,
@@ -18,7 +18,16 @@ pub struct MerkleTree {
     pub count: usize,
 
     /// The number of nodes in the tree
-    pub nodes_count: usize
+    pub nodes_count: usize,
+
+    /// The name of the hashing scheme of the tree, see `MerkleHasher::algorithm`.
+    /// Trees serialized without it were built with `Hash`.
+    #[serde(skip, default = "default_algorithm")]
+    pub algorithm: &'static str
+}
+
+fn default_algorithm() -> &'static str {
+    Hash {}.algorithm()
 }
 
 impl MerkleTree {
@@ -26,67 +35,50 @@ impl MerkleTree {
     /// Constructs a Merkle Tree from a vector of data blocks.
     /// Returns `None` if `values` is empty.
     pub fn from_vec(values: Vec<TreeLeafData>) -> Result<Self, CommonError> {
+        MerkleTree::from_vec_with_hasher(&Hash {}, values)
+    }
 
-        if values.is_empty() {
-            return Ok(MerkleTree {
-                root: Tree::empty(Hash::hash_empty()?),
-                height: 0,
-                count: 0,
-                nodes_count: 0
-            });
-        }
-
-        let count = values.len();
-        let mut nodes_count = 0;
-        let mut height = 0;
-        let mut cur    = Vec::with_capacity(count);
+    /// Same as `from_vec` but hashes with the given `hasher`, which the tree records.
+    pub fn from_vec_with_hasher<H: MerkleHasher>(hasher: &H, values: Vec<TreeLeafData>) -> Result<Self, CommonError> {
+        let root = Tree::from_leaves_with_hasher(hasher, values)?;
+        Ok(MerkleTree::from_root(hasher, root))
+    }
 
-        for v in values {
-            let leaf = Tree::new_leaf(v)?;
-            cur.push(leaf);
-        }
+    /// Combines two Merkle trees under a new root, see `Tree::merge`.
+    /// Fails if they weren't both built with `Hash`.
+    pub fn merge(left: MerkleTree, right: MerkleTree) -> Result<Self, CommonError> {
+        MerkleTree::merge_with_hasher(&Hash {}, left, right)
+    }
 
-        while cur.len() > 1 {
-            let mut next = Vec::new();
-            while !cur.is_empty() {
-                if cur.len() == 1 {
-                    next.push(cur.remove(0));
-                }
-                else {
-                    let left  = cur.remove(0);
-                    let right = cur.remove(0);
-
-                    let combined_hash = Hash::hash_nodes(
-                        left.hash(),
-                        right.hash()
-                    )?;
-
-                    let node = Tree::Node {
-                       hash: combined_hash.to_vec(),
-                       left: Box::new(left),
-                       right: Box::new(right)
-                    };
-
-                    next.push(node);
-                    nodes_count+=1;
-                }
-            }
-
-            height += 1;
-
-            cur = next;
-        }
+    /// Same as `merge` for trees built with the given `hasher`.
+    /// Fails if either tree was built with another algorithm.
+    pub fn merge_with_hasher<H: MerkleHasher>(hasher: &H, left: MerkleTree, right: MerkleTree) -> Result<Self, CommonError> {
+        left.check_algorithm(hasher)?;
+        right.check_algorithm(hasher)?;
 
-        debug_assert!(cur.len() == 1);
+        let root = Tree::merge_with_hasher(hasher, left.root, right.root)?;
+        Ok(MerkleTree::from_root(hasher, root))
+    }
 
-        let root = cur.remove(0);
+    fn from_root<H: MerkleHasher>(hasher: &H, root: Tree) -> Self {
+        let count = root.get_count();
 
-        Ok(MerkleTree {
-            root: root,
-            height: height,
+        MerkleTree {
+            height: root.get_height(),
             count: count,
-            nodes_count: nodes_count
-        })
+            nodes_count: count.saturating_sub(1),
+            root: root,
+            algorithm: hasher.algorithm()
+        }
+    }
+
+    /// Fails if the tree wasn't built with the algorithm of `hasher`.
+    pub(super) fn check_algorithm<H: MerkleHasher>(&self, hasher: &H) -> Result<(), CommonError> {
+        if self.algorithm != hasher.algorithm() {
+            return Err(CommonError::InvalidStructure(
+                format!("Tree was built with {}, not {}", self.algorithm, hasher.algorithm())));
+        }
+        Ok(())
     }
 
     /// Returns the root hash of Merkle tree
@@ -96,12 +88,12 @@ impl MerkleTree {
 
     /// Returns the hex root hash of Merkle tree
     pub fn root_hash_hex(&self) -> String {
-        let rh = self.root.hash();
-        let mut ret:String = String::with_capacity(HASHBYTES *2);
-        for i in rh {
-            ret.push_str(&format!("{:02x}", i));
-        }
-        return ret;
+        self.root.root_hash().to_hex()
+    }
+
+    /// Returns the name of the hashing scheme the tree was built with, e.g. `"sha256"`.
+    pub fn algorithm(&self) -> &'static str {
+        self.algorithm
     }
 
     /// Returns the height of Merkle tree
@@ -122,13 +114,7 @@ impl MerkleTree {
     /// Generate an inclusion proof for the given value.
     /// Returns `None` if the given value is not found in the tree.
     pub fn gen_proof(&self, value: TreeLeafData) -> Result<Option<Proof>, CommonError> {
-
-        let root_hash  = self.root_hash().clone();
-        let leaf_hash  = Hash::hash_leaf(&value)?;
-
-        Ok(Lemma::new(&self.root, leaf_hash.to_vec().as_slice()).map(|lemma|
-            Proof::new(root_hash, lemma, value)
-        ))
+        self.root.gen_proof(value)
     }
 
     /// Creates an `Iterator` over the values contained in this Merkle tree.
@@ -0,0 +1,277 @@
+use utils::crypto::hash::{Hash, MerkleHasher, eq_ct};
+use errors::common::CommonError;
+
+/// A Merkle mountain range: an append-only log of leaf hashes, for ledgers that
+/// never need the historical trees nor the leaf values back.
+///
+/// Only hashes are kept, level by level: the leaf hashes, then the hashes of every
+/// complete pair of them, and so on, which is less than two hashes per leaf and no
+/// `Tree` node at all. An append hashes one node per level the new leaf completes,
+/// so one node amortized. The perfect subtrees left unpaired at the end of each level
+/// are the peaks, and the root bags them from right to left, which gives the same root
+/// as `Tree::from_leaves`. Proofs are thus plain RFC 6962 audit paths of O(log n) hashes,
+/// which `proof::verify_inclusion` checks without any `Mmr`.
+#[allow(missing_debug_implementations)]
+pub struct Mmr<H: MerkleHasher = Hash> {
+    hasher: H,
+    levels: Vec<Vec<Vec<u8>>>
+}
+
+impl Mmr<Hash> {
+
+    /// Constructs a new empty range hashing with the default `Hash`
+    pub fn new() -> Self {
+        Mmr::with_hasher(Hash {})
+    }
+
+}
+
+impl Default for Mmr<Hash> {
+
+    fn default() -> Self {
+        Mmr::new()
+    }
+
+}
+
+impl<H: MerkleHasher> Mmr<H> {
+
+    /// Constructs a new empty range hashing with the given `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        Mmr {
+            hasher: hasher,
+            levels: Vec::new()
+        }
+    }
+
+    /// Returns the number of leaves appended so far.
+    pub fn len(&self) -> usize {
+        self.levels.first().map_or(0, Vec::len)
+    }
+
+    /// Returns whether no leaf was appended yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends the leaf holding `value` and returns its position.
+    pub fn append(&mut self, value: &[u8]) -> Result<usize, CommonError> {
+        let leaf_hash = self.hasher.hash_leaf(value)?;
+        self.append_leaf_hash(leaf_hash)
+    }
+
+    /// Same as `append` but takes an already hashed leaf.
+    pub fn append_leaf_hash(&mut self, leaf_hash: Vec<u8>) -> Result<usize, CommonError> {
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+
+        self.levels[0].push(leaf_hash);
+
+        let mut height = 0;
+        while self.levels[height].len() % 2 == 0 {
+            let node = {
+                let level = &self.levels[height];
+                self.hasher.hash_nodes(&level[level.len() - 2], &level[level.len() - 1])?
+            };
+
+            if self.levels.len() == height + 1 {
+                self.levels.push(Vec::new());
+            }
+
+            self.levels[height + 1].push(node);
+            height += 1;
+        }
+
+        Ok(self.len() - 1)
+    }
+
+    /// Returns the hashes of the peaks, from the highest and leftmost one.
+    pub fn peaks(&self) -> Vec<&[u8]> {
+        let size = self.len();
+        let mut peaks = Vec::new();
+        let mut offset = 0;
+
+        for height in (0..self.levels.len()).rev() {
+            if size & (1 << height) != 0 {
+                peaks.push(self.levels[height][offset >> height].as_slice());
+                offset += 1 << height;
+            }
+        }
+
+        peaks
+    }
+
+    /// Returns the root hash, bagging the peaks from right to left.
+    pub fn root(&self) -> Result<Vec<u8>, CommonError> {
+        self.bag(&self.peaks())
+    }
+
+    /// Generates the audit path of the leaf at position `index`: the sibling hashes
+    /// from the leaf up to the root, as `Tree::audit_path` does.
+    /// Returns `None` if `index` is out of range.
+    pub fn gen_proof(&self, index: usize) -> Result<Option<Vec<Vec<u8>>>, CommonError> {
+        if index >= self.len() {
+            return Ok(None);
+        }
+
+        let peaks = self.peaks();
+        let (peak, height) = peak_of(index, self.len());
+
+        let mut path = (0..height)
+            .map(|level| self.levels[level][(index >> level) ^ 1].clone())
+            .collect::<Vec<Vec<u8>>>();
+
+        if peak + 1 < peaks.len() {
+            path.push(self.bag(&peaks[peak + 1..])?);
+        }
+
+        for left in peaks[..peak].iter().rev() {
+            path.push(left.to_vec());
+        }
+
+        Ok(Some(path))
+    }
+
+    /// Checks that `value` is the leaf at position `index` with the audit path `path`
+    /// given by `gen_proof`, against the current root.
+    pub fn verify(&self, value: &[u8], index: usize, path: &[Vec<u8>]) -> Result<bool, CommonError> {
+        if index >= self.len() {
+            return Ok(false);
+        }
+
+        let (peak, height) = peak_of(index, self.len());
+        let peaks_count = self.len().count_ones() as usize;
+        let has_right_peaks = peak + 1 < peaks_count;
+
+        if path.len() != height + has_right_peaks as usize + peak {
+            return Ok(false);
+        }
+
+        let mut hash = self.hasher.hash_leaf(value)?;
+
+        for (level, sibling) in path.iter().enumerate() {
+            // within the peak the bits of the index tell the side, then comes the bag
+            // of the peaks at the right, and last the peaks at the left
+            let sibling_is_left = if level < height {
+                (index >> level) & 1 == 1
+            } else {
+                !(level == height && has_right_peaks)
+            };
+
+            hash = if sibling_is_left {
+                self.hasher.hash_nodes(sibling, &hash)?
+            } else {
+                self.hasher.hash_nodes(&hash, sibling)?
+            };
+        }
+
+        Ok(eq_ct(&hash, &self.root()?))
+    }
+
+    /// Bags `peaks` from right to left, or returns the empty hash if there are none.
+    fn bag(&self, peaks: &[&[u8]]) -> Result<Vec<u8>, CommonError> {
+        let mut peaks = peaks.iter().rev();
+
+        let mut root = match peaks.next() {
+            Some(peak) => peak.to_vec(),
+            None => return self.hasher.hash_empty()
+        };
+
+        for peak in peaks {
+            root = self.hasher.hash_nodes(peak, &root)?;
+        }
+
+        Ok(root)
+    }
+
+}
+
+/// Returns the position among the peaks, from the left, of the peak holding
+/// the leaf at position `index` of a range of `size` leaves, and its height.
+fn peak_of(index: usize, size: usize) -> (usize, usize) {
+    let mut offset = 0;
+    let mut peak = 0;
+    let mut height = 0usize.count_zeros() as usize;
+
+    loop {
+        height -= 1;
+
+        if size & (1 << height) != 0 {
+            offset += 1 << height;
+            if index < offset {
+                return (peak, height);
+            }
+            peak += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::proof::{Positioned, verify_inclusion};
+    use services::ledger::merkletree::tree::{Tree, TreeLeafData};
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    fn mmr(count: usize) -> Mmr {
+        let mut mmr = Mmr::new();
+        for (i, value) in values(count).iter().enumerate() {
+            assert_eq!(mmr.append(value).unwrap(), i);
+        }
+        mmr
+    }
+
+    #[test]
+    fn root_works_same_as_from_leaves() {
+        for count in 0..40 {
+            let mmr = mmr(count);
+
+            assert_eq!(mmr.len(), count);
+            assert_eq!(mmr.peaks().len(), count.count_ones() as usize);
+            assert_eq!(&mmr.root().unwrap(), Tree::from_leaves(values(count)).unwrap().hash());
+        }
+    }
+
+    #[test]
+    fn gen_proof_works_same_as_audit_path() {
+        for count in 1..40 {
+            let mmr = mmr(count);
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let root = mmr.root().unwrap();
+
+            for (index, value) in values(count).iter().enumerate() {
+                let path = mmr.gen_proof(index).unwrap().unwrap();
+                let expected = tree.audit_path(index).unwrap().into_iter()
+                    .map(|sibling| match sibling {
+                        Positioned::Left(hash) | Positioned::Right(hash) => hash
+                    })
+                    .collect::<Vec<Vec<u8>>>();
+
+                assert_eq!(path, expected);
+                assert!(mmr.verify(value, index, &path).unwrap());
+                assert!(verify_inclusion(value, index, count, &path, &root).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn gen_proof_works_for_out_of_range_index() {
+        assert_eq!(mmr(5).gen_proof(5).unwrap(), None);
+        assert_eq!(Mmr::new().gen_proof(0).unwrap(), None);
+    }
+
+    #[test]
+    fn verify_works_for_wrong_proofs() {
+        let mmr = mmr(11);
+        let path = mmr.gen_proof(4).unwrap().unwrap();
+
+        assert!(!mmr.verify(b"value5", 4, &path).unwrap());
+        assert!(!mmr.verify(b"value4", 5, &path).unwrap());
+        assert!(!mmr.verify(b"value4", 4, &path[1..]).unwrap());
+        assert!(!mmr.verify(b"value4", 11, &path).unwrap());
+    }
+}
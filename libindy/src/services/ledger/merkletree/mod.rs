@@ -1,25 +1,53 @@
+#[cfg(feature = "std")]
 extern crate serde_json;
 
-pub mod tree;
+// Without `std` only the verification path of `proof` is built,
+// which needs neither `Tree` nor the rest of the module.
 pub mod proof;
+#[cfg(feature = "std")]
+pub mod tree;
+#[cfg(feature = "std")]
+pub mod multiproof;
+#[cfg(feature = "std")]
+pub mod consistency;
+#[cfg(feature = "std")]
+pub mod absence;
+#[cfg(feature = "std")]
+pub mod range;
+#[cfg(feature = "std")]
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "std")]
+pub mod visitor;
+#[cfg(feature = "std")]
+pub mod leaf;
+#[cfg(feature = "std")]
+pub mod mmr;
+#[cfg(feature = "std")]
+pub mod fixed;
+#[cfg(all(feature = "std", feature = "sparse"))]
+pub mod sparse;
+#[cfg(all(feature = "std", feature = "quickcheck"))]
+mod arbitrary;
+#[cfg(feature = "std")]
 pub mod merkletree;
 
+#[cfg(feature = "std")]
 use self::tree::*;
+#[cfg(feature = "std")]
 use self::merkletree::*;
+#[cfg(feature = "std")]
+use self::consistency::ConsistencyProof;
+#[cfg(feature = "std")]
 use errors::common::CommonError;
-use utils::crypto::hash::Hash;
+#[cfg(feature = "std")]
+use utils::crypto::hash::{Hash, MerkleHasher};
 
+#[cfg(feature = "std")]
 impl MerkleTree {
-    fn count_bits(v: usize) -> usize {
-        let mut ret = 0;
-        let mut val = v;
-        while val != 0 {
-            val &= val - 1;
-            ret += 1;
-        }
-        return ret;
-    }
-
     pub fn find_hash<'a>(from: &'a Tree, required_hash: &Vec<u8>) -> Option<&'a Tree> {
         match from {
             &Tree::Empty { .. } => {
@@ -62,148 +90,42 @@ impl MerkleTree {
     pub fn consistency_proof(&self,
                              new_root_hash: &Vec<u8>, new_size: usize,
                              proof: &Vec<Vec<u8>>) -> Result<bool, CommonError> {
-        if self.count == 0 {
-            // empty old tree
-            return Ok(true);
-        }
-        if self.count == new_size && self.root_hash() == new_root_hash {
-            // identical trees
-            return Ok(true);
-        }
-        if self.count > new_size {
-            // old tree is bigger!
-            assert!(false);
-            return Ok(false);
-        }
-
-        let mut old_node = self.count - 1;
-        let mut new_node = new_size - 1;
-
-        while old_node % 2 != 0 {
-            old_node = old_node / 2;
-            new_node = new_node / 2;
-        }
-
-        let mut proofs = proof.iter();
-        let mut old_hash: Vec<u8>;
-        let mut new_hash: Vec<u8>;
-
-        if old_node != 0 {
-            new_hash = unwrap_opt_or_return!(proofs.next(), Ok(false)).to_vec();
-            old_hash = new_hash.clone();
-        } else {
-            new_hash = self.root_hash().to_vec();
-            old_hash = new_hash.clone();
-        }
-
-        while old_node != 0 {
-            if old_node % 2 != 0 {
-                let next_proof = unwrap_opt_or_return!(proofs.next(), Ok(false));
-                old_hash = Hash::hash_nodes(next_proof, &old_hash)?.to_vec();
-                new_hash = Hash::hash_nodes(next_proof, &new_hash)?.to_vec();
-            } else if old_node < new_node {
-                new_hash = Hash::hash_nodes(&new_hash,
-                                            unwrap_opt_or_return!(proofs.next(), Ok(false)))?.to_vec();
-            }
-            old_node = old_node / 2;
-            new_node = new_node / 2;
-        }
-
-        while new_node != 0 {
-            let n = unwrap_opt_or_return!(proofs.next(), Ok(false));
-            new_hash = Hash::hash_nodes(&new_hash, n)?.to_vec();
-            new_node = new_node / 2;
-        }
-
-        if new_hash != *new_root_hash {
-            // new hash differs
-            return Ok(false);
-        }
-
-        if old_hash != *self.root_hash() {
-            // old hash differs
-            return Ok(false);
-        }
-
-        return Ok(true);
+        ConsistencyProof::new(proof.clone())
+            .verify(self.root_hash(), self.count, new_root_hash, new_size)
     }
 
     pub fn append(&mut self, node: TreeLeafData) -> Result<(), CommonError> {
-        if self.count == 0 {
-            // empty tree
-            self.root = Tree::new_leaf(node)?;
-            self.count += 1;
-        } else if Self::count_bits(self.count) != 1 {
-            // add to right subtree
-            match self.root.clone() {
-                Tree::Node { ref left, ref right, .. } => {
-                    let mut iter = right.iter().map(|x| (*x).clone()).collect::<Vec<TreeLeafData>>();
-                    iter.push(node);
-                    let new_right = MerkleTree::from_vec(iter)?;
-                    let combined_hash = Hash::hash_nodes(
-                        left.hash(),
-                        new_right.root_hash() as &Vec<u8>
-                    )?;
-                    self.root = Tree::Node {
-                        left: (*left).clone(),
-                        right: Box::new(new_right.root),
-                        hash: combined_hash.to_vec()
-                    };
-                    self.count += 1;
-                    self.nodes_count += 1;
-                }
-                _ => {
-                    assert!(false);
-                }
-            }
-        } else {
-            // add tree layer
-            let new_right = MerkleTree::from_vec(vec![node])?;
-            match self.root.clone() {
-                Tree::Node { ref hash, .. } => {
-                    let combined_hash = Hash::hash_nodes(
-                        hash,
-                        new_right.root_hash()
-                    )?;
-                    self.root = Tree::Node {
-                        left: Box::new(self.root.clone()),
-                        right: Box::new(new_right.root),
-                        hash: combined_hash.to_vec()
-                    };
-                    self.count += 1;
-                    self.nodes_count += 1;
-                }
-                Tree::Leaf { ref hash, ref value } => {
-                    let combined_hash = Hash::hash_nodes(
-                        hash,
-                        new_right.root_hash()
-                    )?;
-                    self.root = Tree::Node {
-                        left: Box::new(Tree::new_leaf((*value).clone())?),
-                        right: Box::new(new_right.root),
-                        hash: combined_hash.to_vec()
-                    };
-                    self.count += 1;
-                    self.nodes_count += 1;
-                }
-                _ => {
-                    assert!(false);
-                }
+        self.append_with_hasher(&Hash {}, node)
+    }
+
+    /// Same as `append` for a tree built with the given `hasher`.
+    /// Fails if the tree was built with another algorithm.
+    pub fn append_with_hasher<H: MerkleHasher>(&mut self, hasher: &H, node: TreeLeafData) -> Result<(), CommonError> {
+        self.check_algorithm(hasher)?;
+        self.root.push_with_hasher(hasher, node)?;
+
+        if self.count != 0 {
+            self.nodes_count += 1;
+            if self.count.is_power_of_two() {
+                // add tree layer
+                self.height += 1;
             }
-            self.height += 1;
         }
+        self.count += 1;
+
         Ok(())
     }
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     extern crate rust_base58;
 
     use super::*;
     use self::rust_base58::FromBase58;
     use self::serde_json;
+    use utils::crypto::hash::{Sha512Hash, Sha384Hash};
 
     #[test]
     fn append_works() {
@@ -220,6 +142,50 @@ mod tests {
         assert_eq!(mt.root_hash_hex(), "1285070cf01debc1155cef8dfd5ba54c05abb919a4c08c8632b079fb1e1e5e7c");
     }
 
+    #[test]
+    fn append_with_hasher_works_for_same_algorithm() {
+        let hasher = Sha512Hash {};
+        let mut mt = MerkleTree::from_vec_with_hasher(&hasher, vec![]).unwrap();
+        assert_eq!(mt.algorithm(), "sha512");
+
+        for i in 0..5 {
+            mt.append_with_hasher(&hasher, format!("value{}", i).into_bytes()).unwrap();
+        }
+
+        let values = (0..5).map(|i| format!("value{}", i).into_bytes()).collect();
+        assert_eq!(mt.root, Tree::from_leaves_with_hasher(&hasher, values).unwrap());
+        assert_eq!(mt.count(), 5);
+    }
+
+    #[test]
+    fn append_with_hasher_works_for_other_algorithm() {
+        let mut mt = MerkleTree::from_vec(vec![]).unwrap();
+        assert_eq!(mt.algorithm(), "sha256");
+
+        // even an empty tree knows its algorithm
+        assert!(mt.append_with_hasher(&Sha512Hash {}, b"value0".to_vec()).is_err());
+        assert!(mt.append_with_hasher(&Sha384Hash {}, b"value0".to_vec()).is_err());
+        assert!(mt.is_empty());
+
+        mt.append(b"value0".to_vec()).unwrap();
+        assert!(mt.append_with_hasher(&Sha512Hash {}, b"value1".to_vec()).is_err());
+        assert_eq!(mt.count(), 1);
+    }
+
+    #[test]
+    fn merge_works_for_algorithms() {
+        let left = MerkleTree::from_vec(vec![b"a".to_vec(), b"b".to_vec()]).unwrap();
+        let right = MerkleTree::from_vec(vec![b"c".to_vec()]).unwrap();
+        let merged = MerkleTree::merge(left.clone(), right).unwrap();
+        assert_eq!(merged.root_hash(), MerkleTree::from_vec(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]).unwrap().root_hash());
+        assert_eq!(merged.count(), 3);
+        assert_eq!(merged.nodes_count, 2);
+
+        let empty = MerkleTree::from_vec_with_hasher(&Sha512Hash {}, vec![]).unwrap();
+        assert!(MerkleTree::merge(left.clone(), empty.clone()).is_err());
+        assert!(MerkleTree::merge_with_hasher(&Sha512Hash {}, left, empty).is_err());
+    }
+
     #[test]
     fn find_hash_works() {
         let values = vec!["1", "2", "3", "4", "5", "6", "7", "8", "9"];
@@ -397,4 +363,39 @@ mod tests {
         mt.append(all_values[8 - 1].clone()).unwrap();
         assert!(mt.consistency_proof(&full_root_hash, 8, &proofs_for_8).unwrap());
     }
+
+    #[test]
+    fn proof_from_audit_path_works_for_node_hashes() {
+        let all_str_values = vec![
+            r#"{"data":{"alias":"Node1","client_ip":"10.0.0.2","client_port":9702,"node_ip":"10.0.0.2","node_port":9701,"services":["VALIDATOR"]},"dest":"Gw6pDLhcBcoQesN72qfotTgFa7cbuqZpkX3Xo6pLhPhv","identifier":"FYmoFw55GeQH7SRFa37dkx1d2dZ3zUF8ckg7wmL7ofN4","txnId":"fea82e10e894419fe2bea7d96296a6d46f50f93f9eeda954ec461b2ed2950b62","type":"0"}"#,
+            r#"{"data":{"alias":"Node2","client_ip":"10.0.0.2","client_port":9704,"node_ip":"10.0.0.2","node_port":9703,"services":["VALIDATOR"]},"dest":"8ECVSk179mjsjKRLWiQtssMLgp6EPhWXtaYyStWPSGAb","identifier":"8QhFxKxyaFsJy4CyxeYX34dFH8oWqyBv1P4HLQCsoeLy","txnId":"1ac8aece2a18ced660fef8694b61aac3af08ba875ce3026a160acbc3a3af35fc","type":"0"}"#,
+            r#"{"data":{"alias":"Node3","client_ip":"10.0.0.2","client_port":9706,"node_ip":"10.0.0.2","node_port":9705,"services":["VALIDATOR"]},"dest":"DKVxG2fXXTU8yT5N7hGEbXB3dfdAnYv1JczDUHpmDxya","identifier":"2yAeV5ftuasWNgQwVYzeHeTuM7LwwNtPR3Zg9N4JiDgF","txnId":"7e9f355dffa78ed24668f0e0e369fd8c224076571c51e2ea8be5f26479edebe4","type":"0"}"#,
+            r#"{"data":{"alias":"Node4","client_ip":"10.0.0.2","client_port":9708,"node_ip":"10.0.0.2","node_port":9707,"services":["VALIDATOR"]},"dest":"4PS3EDQ3dW1tci1Bp6543CfuuebjFrg36kLAUcskGfaA","identifier":"FTE95CVthRtrBnK2PYCBbC9LghTcGwi9Zfi1Gz2dnyNx","txnId":"aa5e817d7cc626170eca175822029339a444eb0ee8f0bd20d3b0b76e566fb008","type":"0"}"#,
+            r#"{"data":{"alias":"Node5","client_ip":"10.0.0.2","client_port":9710,"node_ip":"10.0.0.2","node_port":9709,"services":["VALIDATOR"]},"dest":"4SWokCJWJc69Tn74VvLS6t2G2ucvXqM9FDMsWJjmsUxe","identifier":"5NekXKJvGrxHvfxbXThySmaG8PmpNarXHCf1CkwTLfrg","txnId":"5abef8bc27d85d53753c5b6ed0cd2e197998c21513a379bfcf44d9c7a73c3a7e","type":"0"}"#,
+            r#"{"data":{"alias":"Node6","client_ip":"10.0.0.2","client_port":9712,"node_ip":"10.0.0.2","node_port":9711,"services":["VALIDATOR"]},"dest":"Cv1Ehj43DDM5ttNBmC6VPpEfwXWwfGktHwjDJsTV5Fz8","identifier":"A2yZJTPHZyqJDELb8E1mhxUqWPEW5vgH2ePLTiTDQayp","txnId":"a23059dc16aaf4513f97ca91f272235e809f8bda8c40f6688b88615a2c318ff8","type":"0"}"#,
+            r#"{"data":{"alias":"Node7","client_ip":"10.0.0.2","client_port":9714,"node_ip":"10.0.0.2","node_port":9713,"services":["VALIDATOR"]},"dest":"BM8dTooz5uykCbYSAAFwKNkYfT4koomBHsSWHTDtkjhW","identifier":"6pYGZXnqXLxLAhrEBhVjyvuhnV2LUgM9iw1gHds8JDqT","txnId":"e5f11aa7ec7091ca6c31a826eec885da7fcaa47611d03fdc3562b48247f179cf","type":"0"}"#,
+            r#"{"data":{"alias":"Node8","client_ip":"10.0.0.2","client_port":9716,"node_ip":"10.0.0.2","node_port":9715,"services":["VALIDATOR"]},"dest":"98VysG35LxrutKTNXvhaztPFHnx5u9kHtT7PnUGqDa8x","identifier":"B4xQBURedpCS3r6v8YxTyz5RYh3Nh5Jt2MxsmtAUr1rH","txnId":"2b01e69f89514be94ebf24bfa270abbe1c5abc72415801da3f0d58e71aaa33a2","type":"0"}"#,
+        ];
+        let all_values: Vec<Vec<u8>> = all_str_values.iter().map(|x| String::from(*x).as_bytes().to_vec()).collect::<Vec<_>>();
+        let mt = MerkleTree::from_vec(all_values.clone()).unwrap();
+        let root_hash = rust_base58::ToBase58::to_base58(mt.root_hash().as_slice());
+
+        // sibling hashes of the 7th txn, taken from the node consistency proof
+        // for the same ledger in consistency_proof_works_for_old4_new8
+        let audit_path = vec![
+            "5cVBJRrdFraAtDzUhezeifS6W4Gsgo3TdPXs8847p95L",
+            "HhkWitSAXG12Ugn4KFtrUyhbZHi9XrP4jnbLuSthynSu",
+            "BhXMcoxZ9eu3Cu85bzr4G4Msrw77BT3R6Mw6P6bM9wQe"
+        ];
+
+        let proof = Proof::from_audit_path(all_values[6].clone(), 6, 8, &audit_path, &root_hash).unwrap();
+        assert!(proof.validate(mt.root_hash()).unwrap());
+        assert_eq!(proof.lemma, mt.gen_proof(all_values[6].clone()).unwrap().unwrap().lemma);
+
+        let proof = Proof::from_audit_path(all_values[5].clone(), 6, 8, &audit_path, &root_hash).unwrap();
+        assert!(!proof.validate(mt.root_hash()).unwrap());
+
+        assert!(Proof::from_audit_path(all_values[6].clone(), 6, 7, &audit_path, &root_hash).is_err());
+        assert!(Proof::from_audit_path(all_values[6].clone(), 6, 8, &audit_path, "0OIl").is_err());
+    }
 }
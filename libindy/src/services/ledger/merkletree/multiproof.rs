@@ -0,0 +1,421 @@
+use services::ledger::merkletree::proof::{Lemma, Positioned, Proof};
+use services::ledger::merkletree::tree::{split_point, TreeLeafData};
+use utils::crypto::hash::{Hash, MerkleHasher, eq_ct};
+use errors::common::CommonError;
+
+use std::collections::HashMap;
+
+/// An inclusion proof for several leaves of a balanced `Tree` at once.
+///
+/// Holds the hashes of every subtree containing none of the proven leaves,
+/// in pre-order, so sibling hashes shared by several leaves appear only once.
+/// The shape of the tree is derived from its leaves count.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiProof {
+    /// The number of leaves in the tree
+    pub tree_size: usize,
+
+    /// The sorted positions of the proven leaves
+    pub indices: Vec<usize>,
+
+    /// The hashes of the subtrees without proven leaves
+    pub hashes: Vec<Vec<u8>>
+}
+
+impl MultiProof {
+
+    /// Constructs a new `MultiProof`
+    pub fn new(tree_size: usize, indices: Vec<usize>, hashes: Vec<Vec<u8>>) -> Self {
+        MultiProof {
+            tree_size: tree_size,
+            indices: indices,
+            hashes: hashes
+        }
+    }
+
+    /// Combines inclusion proofs of leaves of the same tree of `tree_size` leaves
+    /// into a single `MultiProof`, keeping each shared sibling hash once.
+    ///
+    /// Leaf positions are derived from the sibling positions of each proof.
+    /// Fails if `proofs` is empty, if the proofs don't share the same root hash,
+    /// or if they disagree on the hash of a subtree.
+    pub fn from_proofs(tree_size: usize, proofs: &[Proof]) -> Result<MultiProof, CommonError> {
+        let root_hash = match proofs.first() {
+            Some(proof) => &proof.root_hash,
+            None => return Err(CommonError::InvalidStructure("No proofs to combine".to_string()))
+        };
+
+        // hashes of the known subtrees, keyed by their first leaf and size
+        let mut known = HashMap::new();
+        let mut indices = Vec::with_capacity(proofs.len());
+
+        for proof in proofs {
+            if proof.root_hash != *root_hash || proof.lemma.node_hash != *root_hash {
+                return Err(CommonError::InvalidStructure("Proofs have inconsistent root hashes".to_string()));
+            }
+
+            indices.push(MultiProof::collect_known_hashes(tree_size, &proof.lemma, &mut known)?);
+        }
+
+        indices.sort();
+        indices.dedup();
+
+        let mut hashes = Vec::new();
+        MultiProof::collect_hashes(0, tree_size, &indices, &known, &mut hashes)?;
+
+        Ok(MultiProof::new(tree_size, indices, hashes))
+    }
+
+    /// Records the node and sibling hashes of `lemma` and returns the position of its leaf.
+    fn collect_known_hashes(tree_size: usize, lemma: &Lemma, known: &mut HashMap<(usize, usize), Vec<u8>>) -> Result<usize, CommonError> {
+        let mut lemma = lemma;
+        let mut start = 0;
+        let mut size = tree_size;
+
+        loop {
+            MultiProof::insert_known(known, (start, size), &lemma.node_hash)?;
+
+            let sub = match (&lemma.sub_lemma, &lemma.sibling_hash) {
+                (&None, &None) if size == 1 => break,
+                (&Some(ref sub), &Some(_)) if size > 1 => sub,
+                _ => return Err(CommonError::InvalidStructure(format!("Proof doesn't match a tree of size {}", tree_size)))
+            };
+
+            let split = split_point(size);
+
+            match lemma.sibling_hash {
+                Some(Positioned::Right(ref hash)) => {
+                    MultiProof::insert_known(known, (start + split, size - split), hash)?;
+                    size = split;
+                }
+                Some(Positioned::Left(ref hash)) => {
+                    MultiProof::insert_known(known, (start, split), hash)?;
+                    start += split;
+                    size -= split;
+                }
+                None => unreachable!()
+            }
+
+            lemma = sub;
+        }
+
+        Ok(start)
+    }
+
+    fn insert_known(known: &mut HashMap<(usize, usize), Vec<u8>>, key: (usize, usize), hash: &Vec<u8>) -> Result<(), CommonError> {
+        if known.entry(key).or_insert_with(|| hash.clone()) != hash {
+            return Err(CommonError::InvalidStructure("Proofs have inconsistent hashes".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Collects in pre-order the hashes of the subtrees holding none of `indices`,
+    /// for the subtree of `size` leaves starting at leaf `start`.
+    fn collect_hashes(start: usize, size: usize, indices: &[usize],
+                      known: &HashMap<(usize, usize), Vec<u8>>, hashes: &mut Vec<Vec<u8>>) -> Result<(), CommonError> {
+        if indices.is_empty() {
+            let hash = known.get(&(start, size))
+                .ok_or_else(|| CommonError::InvalidStructure("Proofs miss a subtree hash".to_string()))?;
+            hashes.push(hash.clone());
+            return Ok(());
+        }
+
+        if size == 1 {
+            return Ok(());
+        }
+
+        let split = split_point(size);
+        let position = indices.iter().position(|&i| i >= start + split).unwrap_or(indices.len());
+
+        MultiProof::collect_hashes(start, split, &indices[..position], known, hashes)?;
+        MultiProof::collect_hashes(start + split, size - split, &indices[position..], known, hashes)
+    }
+
+    /// Checks whether `leaves`, given in the order of `indices`,
+    /// lead together with this proof to the trusted `root_hash`.
+    pub fn validate(&self, root_hash: &[u8], leaves: &[TreeLeafData]) -> Result<bool, CommonError> {
+        self.validate_with_hasher(&Hash {}, root_hash, leaves)
+    }
+
+    /// Same as `validate` but for a tree hashed with the given `hasher`.
+    pub fn validate_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8], leaves: &[TreeLeafData]) -> Result<bool, CommonError> {
+        if self.tree_size == 0 || leaves.len() != self.indices.len() {
+            return Ok(false);
+        }
+
+        let mut leaf_hashes = Vec::with_capacity(leaves.len());
+        for leaf in leaves {
+            leaf_hashes.push(hasher.hash_leaf(leaf)?);
+        }
+
+        self.validate_leaf_hashes_with_hasher(hasher, root_hash, &leaf_hashes)
+    }
+
+    /// Same as `validate` but takes already hashed leaves.
+    ///
+    /// `indices` must be strictly increasing and within the tree, otherwise the proof is invalid.
+    pub fn validate_leaf_hashes(&self, root_hash: &[u8], leaf_hashes: &[Vec<u8>]) -> Result<bool, CommonError> {
+        self.validate_leaf_hashes_with_hasher(&Hash {}, root_hash, leaf_hashes)
+    }
+
+    /// Same as `validate_leaf_hashes` but for a tree hashed with the given `hasher`.
+    pub fn validate_leaf_hashes_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8], leaf_hashes: &[Vec<u8>]) -> Result<bool, CommonError> {
+        if self.tree_size == 0 || leaf_hashes.len() != self.indices.len() {
+            return Ok(false);
+        }
+
+        if self.indices.windows(2).any(|pair| pair[0] >= pair[1])
+            || self.indices.last().map_or(false, |&last| last >= self.tree_size) {
+            return Ok(false);
+        }
+
+        let items = self.indices.iter().cloned().zip(leaf_hashes.iter()).collect::<Vec<_>>();
+        let mut hashes = self.hashes.iter();
+
+        let computed = unwrap_opt_or_return!(MultiProof::compute_root(hasher, self.tree_size, &items, &mut hashes)?, Ok(false));
+
+        Ok(hashes.next().is_none() && eq_ct(&computed, root_hash))
+    }
+
+    /// Recomputes the hash of a subtree of `size` leaves
+    /// from the proven leaf hashes it holds and the proof hashes.
+    fn compute_root<'a, H, I>(hasher: &H, size: usize, items: &[(usize, &Vec<u8>)], hashes: &mut I) -> Result<Option<Vec<u8>>, CommonError>
+        where H: MerkleHasher, I: Iterator<Item=&'a Vec<u8>> {
+        if items.is_empty() {
+            return Ok(hashes.next().cloned());
+        }
+
+        if size == 1 {
+            return Ok(if items.len() == 1 && items[0].0 == 0 { Some(items[0].1.clone()) } else { None });
+        }
+
+        let split = split_point(size);
+        let position = items.iter().position(|&(i, _)| i >= split).unwrap_or(items.len());
+        let right_items = items[position..].iter().map(|&(i, hash)| (i - split, hash)).collect::<Vec<_>>();
+
+        let left = unwrap_opt_or_return!(MultiProof::compute_root(hasher, split, &items[..position], hashes)?, Ok(None));
+        let right = unwrap_opt_or_return!(MultiProof::compute_root(hasher, size - split, &right_items, hashes)?, Ok(None));
+
+        Ok(Some(hasher.hash_nodes(&left, &right)?))
+    }
+
+}
+
+/// Checks that each `(value, index)` of `items` is the leaf at position `index`
+/// of a tree of `tree_size` leaves with the trusted root hash `root_hash`,
+/// recomputing the ancestors they share only once with `proof`.
+///
+/// `items` can be in any order and hold the same index several times, as long as
+/// it is with the same value: conflicting values fail before anything is hashed
+/// up the tree. `proof` must prove exactly the distinct indices of `items`.
+pub fn verify_batch_inclusion(items: &[(TreeLeafData, usize)], tree_size: usize,
+                              proof: &MultiProof, root_hash: &[u8]) -> Result<bool, CommonError> {
+    verify_batch_inclusion_with_hasher(&Hash {}, items, tree_size, proof, root_hash)
+}
+
+/// Same as `verify_batch_inclusion` but for a tree hashed with the given `hasher`.
+pub fn verify_batch_inclusion_with_hasher<H: MerkleHasher>(hasher: &H, items: &[(TreeLeafData, usize)], tree_size: usize,
+                                                           proof: &MultiProof, root_hash: &[u8]) -> Result<bool, CommonError> {
+    if items.is_empty() || proof.tree_size != tree_size {
+        return Ok(false);
+    }
+
+    let mut leaves = Vec::with_capacity(items.len());
+    for &(ref value, index) in items {
+        leaves.push((index, hasher.hash_leaf(value)?));
+    }
+    leaves.sort();
+
+    let mut indices = Vec::with_capacity(leaves.len());
+    let mut leaf_hashes = Vec::with_capacity(leaves.len());
+
+    for (index, leaf_hash) in leaves {
+        if indices.last() == Some(&index) {
+            // sorted, so a conflicting value of the same index comes right after
+            if leaf_hashes.last() != Some(&leaf_hash) {
+                return Ok(false);
+            }
+            continue;
+        }
+
+        indices.push(index);
+        leaf_hashes.push(leaf_hash);
+    }
+
+    if indices != proof.indices {
+        return Ok(false);
+    }
+
+    proof.validate_leaf_hashes_with_hasher(hasher, root_hash, &leaf_hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::tree::Tree;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn gen_multiproof_and_validate_work() {
+        for count in 1..14 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for i in 0..count {
+                for j in i..count {
+                    let proof = tree.gen_multiproof(&[j, i]).unwrap();
+                    let mut leaves = vec![values(count)[i].clone()];
+                    if i != j {
+                        leaves.push(values(count)[j].clone());
+                    }
+
+                    assert!(proof.validate(tree.hash(), &leaves).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gen_multiproof_shares_sibling_hashes() {
+        let tree = Tree::from_leaves(values(16)).unwrap();
+        let proof = tree.gen_multiproof(&[0, 1, 2, 3]).unwrap();
+
+        assert_eq!(proof.indices, vec![0, 1, 2, 3]);
+        assert_eq!(proof.hashes.len(), 2);
+        assert!(proof.validate(tree.hash(), &values(4)).unwrap());
+    }
+
+    #[test]
+    fn gen_multiproof_works_for_out_of_range_index() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert!(tree.gen_multiproof(&[1, 5]).is_none());
+        assert!(Tree::from_leaves(vec![]).unwrap().gen_multiproof(&[0]).is_none());
+    }
+
+    #[test]
+    fn validate_works_for_wrong_leaves() {
+        let tree = Tree::from_leaves(values(7)).unwrap();
+        let proof = tree.gen_multiproof(&[2, 5]).unwrap();
+
+        assert!(!proof.validate(tree.hash(), &[values(7)[5].clone(), values(7)[2].clone()]).unwrap());
+        assert!(!proof.validate(tree.hash(), &[values(7)[2].clone()]).unwrap());
+        assert!(!proof.validate(Tree::from_leaves(values(6)).unwrap().hash(), &[values(7)[2].clone(), values(7)[5].clone()]).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_unsorted_or_out_of_range_indices() {
+        let tree = Tree::from_leaves(values(4)).unwrap();
+        let proof = tree.gen_multiproof(&[0, 3]).unwrap();
+        let leaves = [values(4)[0].clone(), values(4)[3].clone()];
+        assert!(proof.validate(tree.hash(), &leaves).unwrap());
+
+        let mut unsorted = proof.clone();
+        unsorted.indices = vec![3, 0];
+        assert!(!unsorted.validate(tree.hash(), &leaves).unwrap());
+
+        let mut repeated = proof.clone();
+        repeated.indices = vec![3, 3];
+        assert!(!repeated.validate(tree.hash(), &leaves).unwrap());
+
+        let mut out_of_range = proof.clone();
+        out_of_range.indices = vec![0, 4];
+        assert!(!out_of_range.validate(tree.hash(), &leaves).unwrap());
+    }
+
+    #[test]
+    fn verify_batch_inclusion_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for i in 0..count {
+                for j in i..count {
+                    let proof = tree.gen_multiproof(&[i, j]).unwrap();
+                    let items = vec![(values(count)[j].clone(), j), (values(count)[i].clone(), i)];
+
+                    assert!(verify_batch_inclusion(&items, count, &proof, tree.hash()).unwrap());
+                    assert!(!verify_batch_inclusion(&items, count + 1, &proof, tree.hash()).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn verify_batch_inclusion_works_for_same_index_twice() {
+        let tree = Tree::from_leaves(values(7)).unwrap();
+        let proof = tree.gen_multiproof(&[2, 5]).unwrap();
+
+        let items = vec![(values(7)[5].clone(), 5), (values(7)[2].clone(), 2), (values(7)[5].clone(), 5)];
+        assert!(verify_batch_inclusion(&items, 7, &proof, tree.hash()).unwrap());
+
+        let conflicting = vec![(values(7)[5].clone(), 5), (values(7)[2].clone(), 2), (values(7)[4].clone(), 5)];
+        assert!(!verify_batch_inclusion(&conflicting, 7, &proof, tree.hash()).unwrap());
+    }
+
+    #[test]
+    fn verify_batch_inclusion_works_for_wrong_items() {
+        let tree = Tree::from_leaves(values(7)).unwrap();
+        let proof = tree.gen_multiproof(&[2, 5]).unwrap();
+
+        let swapped = vec![(values(7)[2].clone(), 5), (values(7)[5].clone(), 2)];
+        assert!(!verify_batch_inclusion(&swapped, 7, &proof, tree.hash()).unwrap());
+
+        let missing = vec![(values(7)[2].clone(), 2)];
+        assert!(!verify_batch_inclusion(&missing, 7, &proof, tree.hash()).unwrap());
+        assert!(!verify_batch_inclusion(&[], 7, &proof, tree.hash()).unwrap());
+    }
+
+    #[test]
+    fn from_proofs_works_same_as_gen_multiproof() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for i in 0..count {
+                for j in i..count {
+                    let proofs = vec![
+                        tree.gen_proof(values(count)[j].clone()).unwrap().unwrap(),
+                        tree.gen_proof(values(count)[i].clone()).unwrap().unwrap(),
+                        tree.gen_proof(values(count)[j].clone()).unwrap().unwrap()
+                    ];
+
+                    let proof = MultiProof::from_proofs(count, &proofs).unwrap();
+                    assert_eq!(proof, tree.gen_multiproof(&[i, j]).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_proofs_fails_for_inconsistent_roots() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        let proofs = vec![
+            tree.gen_proof(b"value1".to_vec()).unwrap().unwrap(),
+            other.gen_proof(b"value3".to_vec()).unwrap().unwrap()
+        ];
+
+        assert!(MultiProof::from_proofs(5, &proofs).is_err());
+    }
+
+    #[test]
+    fn from_proofs_fails_for_inconsistent_hashes() {
+        let tree = Tree::from_leaves(values(4)).unwrap();
+
+        let first = tree.gen_proof(b"value0".to_vec()).unwrap().unwrap();
+        let mut second = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        second.lemma.sibling_hash = Some(Positioned::Left(vec![0; 32]));
+
+        assert!(MultiProof::from_proofs(4, &[first, second]).is_err());
+    }
+
+    #[test]
+    fn from_proofs_fails_for_wrong_tree_size() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let proofs = vec![tree.gen_proof(b"value1".to_vec()).unwrap().unwrap()];
+
+        assert!(MultiProof::from_proofs(9, &proofs).is_err());
+        assert!(MultiProof::from_proofs(5, &[]).is_err());
+    }
+}
@@ -1,10 +1,51 @@
-use services::ledger::merkletree::tree::{Tree, TreeLeafData};
-use utils::crypto::hash::Hash;
+#[cfg(feature = "std")]
+extern crate byteorder;
+#[cfg(feature = "std")]
+extern crate serde_json;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+#[cfg(feature = "std")]
+use std::error;
+use std::fmt;
+
+#[cfg(feature = "std")]
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "rayon")]
+use self::rayon::prelude::*;
+#[cfg(feature = "std")]
+use services::ledger::merkletree::tree::{Tree, left_count};
+#[cfg(feature = "std")]
+use utils::crypto::base58;
+use utils::crypto::hash::{Hash, MerkleHasher, eq_ct};
 use errors::common::CommonError;
 
+/// Raw bytes of a leaf value. Leaves are hashed as is,
+/// so binary ledger entries don't need any extra encoding.
+pub type TreeLeafData = Vec<u8>;
+
+/// Returns the number of leaves in the left subtree of a balanced
+/// node holding `count` leaves: the largest power of two below `count`.
+pub fn split_point(count: usize) -> usize {
+    debug_assert!(count > 1);
+    count.next_power_of_two() / 2
+}
+
+/// Version tag written first by `Proof::to_bytes`.
+#[cfg(feature = "std")]
+const PROOF_BYTES_VERSION: u8 = 1;
+
+/// Most sibling levels `Proof::from_bytes` accepts: a tree of at most 2^64 leaves
+/// is never deeper, and the lemma chain is dropped and walked recursively.
+#[cfg(feature = "std")]
+const PROOF_MAX_LEVELS: usize = 64;
+
 /// An inclusion proof represent the fact that a `value` is a member
 /// of a `MerkleTree` with root hash `root_hash`.
-#[derive(Clone, Debug)]
+///
+/// Proofs compare and hash structurally: two proofs are equal when they have
+/// the same root hash, value and lemma chain, from the root down to the leaf hash.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Proof {
     /// The hash of the root of the original `MerkleTree`
     pub root_hash: Vec<u8>,
@@ -27,17 +68,454 @@ impl Proof {
         }
     }
 
+    /// Returns the number of sibling hashes on the path from the leaf to the root.
+    pub fn lemma_len(&self) -> usize {
+        let mut len = 0;
+        let mut lemma = &self.lemma;
+
+        while let Some(ref sub) = lemma.sub_lemma {
+            if lemma.sibling_hash.is_some() {
+                len += 1;
+            }
+            lemma = sub;
+        }
+
+        len
+    }
+
+    /// Returns the hash of the leaf this proof is about.
+    pub fn leaf_hash(&self) -> &Vec<u8> {
+        let mut lemma = &self.lemma;
+
+        while let Some(ref sub) = lemma.sub_lemma {
+            lemma = sub;
+        }
+
+        &lemma.node_hash
+    }
+
+    /// Serializes this proof as the little-endian `u64` leaf index
+    /// followed by the sibling hashes, from the root down.
+    ///
+    /// Sibling positions are left out since they follow from the index:
+    /// `leaf_index` and `tree_size` must be the ones the proof was generated for.
+    #[cfg(feature = "std")]
+    pub fn to_compact(&self, leaf_index: usize, tree_size: usize) -> Result<Vec<u8>, CommonError> {
+        let mismatch = || CommonError::InvalidStructure(
+            format!("Proof doesn't match leaf {} of a tree of size {}", leaf_index, tree_size));
+
+        let mut bytes = Vec::new();
+        bytes.write_u64::<LittleEndian>(leaf_index as u64)?;
+
+        let mut lemma = &self.lemma;
+        let mut hash_len = None;
+
+        for position in Proof::sibling_positions(leaf_index, tree_size)? {
+            let sub = match lemma.sub_lemma {
+                Some(ref sub) => sub,
+                None => return Err(mismatch())
+            };
+
+            let hash = match (&lemma.sibling_hash, position) {
+                (&Some(Positioned::Left(ref hash)), Positioned::Left(())) |
+                (&Some(Positioned::Right(ref hash)), Positioned::Right(())) => hash,
+                _ => return Err(mismatch())
+            };
+
+            if *hash_len.get_or_insert(hash.len()) != hash.len() {
+                return Err(CommonError::InvalidStructure("Proof hashes differ in length".to_string()));
+            }
+
+            bytes.extend_from_slice(hash);
+            lemma = sub;
+        }
+
+        if lemma.sub_lemma.is_some() {
+            return Err(mismatch());
+        }
+
+        Ok(bytes)
+    }
+
+    /// Restores a proof of `value` serialized by `to_compact` for a tree of size `tree_size`.
+    ///
+    /// Inner node hashes and the root hash are recomputed from `value` and the sibling hashes,
+    /// so the result still has to be validated against a trusted root hash.
+    #[cfg(feature = "std")]
+    pub fn from_compact(bytes: &[u8], tree_size: usize, value: TreeLeafData) -> Result<Proof, CommonError> {
+        Proof::from_compact_with_hasher(&Hash {}, bytes, tree_size, value)
+    }
+
+    /// Same as `from_compact` for a proof of a tree built with the given `hasher`.
+    #[cfg(feature = "std")]
+    pub fn from_compact_with_hasher<H: MerkleHasher>(hasher: &H, bytes: &[u8], tree_size: usize, value: TreeLeafData) -> Result<Proof, CommonError> {
+        let mut hashes = bytes;
+        let leaf_index = hashes.read_u64::<LittleEndian>()
+            .map_err(|_| CommonError::InvalidStructure("Compact proof is too short".to_string()))? as usize;
+
+        let positions = Proof::sibling_positions(leaf_index, tree_size)?;
+
+        if (positions.is_empty() && !hashes.is_empty())
+            || (!positions.is_empty() && (hashes.is_empty() || hashes.len() % positions.len() != 0)) {
+            return Err(CommonError::InvalidStructure(
+                format!("Compact proof doesn't hold {} hashes", positions.len())));
+        }
+
+        let hash_len = if positions.is_empty() { 0 } else { hashes.len() / positions.len() };
+        let siblings = (0..positions.len())
+            .map(|i| hashes[i * hash_len..(i + 1) * hash_len].to_vec())
+            .collect();
+
+        let lemma = Lemma::from_siblings(hasher, hasher.hash_leaf(&value)?, positions, siblings)?;
+        Ok(Proof::new(lemma.node_hash.clone(), lemma, value))
+    }
+
+    /// Serializes this whole proof as a self-describing binary blob.
+    ///
+    /// The layout is a one-byte version tag (currently `1`) followed by the root hash,
+    /// the value and the lemma chain from the root down to the leaf hash. Every
+    /// byte string is prefixed with its little-endian `u32` length, and every lemma
+    /// is its node hash followed by a tag byte: `0` for the leaf, which ends the chain,
+    /// or `1` / `2` for a `Left` / `Right` sibling hash, which follows.
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CommonError> {
+        let mut bytes = vec![PROOF_BYTES_VERSION];
+        Proof::write_bytes(&mut bytes, &self.root_hash)?;
+        Proof::write_bytes(&mut bytes, &self.value)?;
+
+        let mut lemma = &self.lemma;
+
+        loop {
+            Proof::write_bytes(&mut bytes, &lemma.node_hash)?;
+
+            let sub = match (&lemma.sibling_hash, &lemma.sub_lemma) {
+                (&None, &None) => {
+                    bytes.push(0);
+                    return Ok(bytes);
+                }
+                (&Some(Positioned::Left(ref hash)), &Some(ref sub)) => {
+                    bytes.push(1);
+                    Proof::write_bytes(&mut bytes, hash)?;
+                    sub
+                }
+                (&Some(Positioned::Right(ref hash)), &Some(ref sub)) => {
+                    bytes.push(2);
+                    Proof::write_bytes(&mut bytes, hash)?;
+                    sub
+                }
+                _ => return Err(CommonError::InvalidStructure("Proof lemma chain is malformed".to_string()))
+            };
+
+            lemma = sub;
+        }
+    }
+
+    /// Restores a proof serialized by `to_bytes`.
+    ///
+    /// Unknown version tags, truncated input, trailing bytes and lemma chains of more
+    /// than 64 levels are all rejected with `CommonError::InvalidStructure`. Nothing
+    /// is validated: use `validate` or `validate_against` with a trusted root hash afterwards.
+    #[cfg(feature = "std")]
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Proof, CommonError> {
+        let version = Proof::read_u8(&mut bytes)?;
+        if version != PROOF_BYTES_VERSION {
+            return Err(CommonError::InvalidStructure(format!("Unknown proof format version {}", version)));
+        }
+
+        let root_hash = Proof::read_bytes(&mut bytes)?;
+        let value = Proof::read_bytes(&mut bytes)?;
+
+        let mut levels = Vec::new();
+
+        let leaf_hash = loop {
+            let node_hash = Proof::read_bytes(&mut bytes)?;
+
+            let sibling_hash = match Proof::read_u8(&mut bytes)? {
+                0 => break node_hash,
+                1 => Positioned::Left(Proof::read_bytes(&mut bytes)?),
+                2 => Positioned::Right(Proof::read_bytes(&mut bytes)?),
+                tag => return Err(CommonError::InvalidStructure(format!("Unknown proof lemma tag {}", tag)))
+            };
+
+            if levels.len() == PROOF_MAX_LEVELS {
+                return Err(CommonError::InvalidStructure(format!("Proof has more than {} levels", PROOF_MAX_LEVELS)));
+            }
+
+            levels.push((node_hash, sibling_hash));
+        };
+
+        if !bytes.is_empty() {
+            return Err(CommonError::InvalidStructure("Proof bytes have trailing data".to_string()));
+        }
+
+        let mut lemma = Lemma {
+            node_hash: leaf_hash,
+            sibling_hash: None,
+            sub_lemma: None
+        };
+
+        for (node_hash, sibling_hash) in levels.into_iter().rev() {
+            lemma = Lemma {
+                node_hash: node_hash,
+                sibling_hash: Some(sibling_hash),
+                sub_lemma: Some(Box::new(lemma))
+            };
+        }
+
+        Ok(Proof::new(root_hash, lemma, value))
+    }
+
+    #[cfg(feature = "std")]
+    fn write_bytes(bytes: &mut Vec<u8>, data: &[u8]) -> Result<(), CommonError> {
+        if data.len() > u32::max_value() as usize {
+            return Err(CommonError::InvalidStructure("Proof field is too long".to_string()));
+        }
+
+        bytes.write_u32::<LittleEndian>(data.len() as u32)?;
+        bytes.extend_from_slice(data);
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    fn read_u8(bytes: &mut &[u8]) -> Result<u8, CommonError> {
+        bytes.read_u8()
+            .map_err(|_| CommonError::InvalidStructure("Proof bytes are truncated".to_string()))
+    }
+
+    #[cfg(feature = "std")]
+    fn read_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>, CommonError> {
+        let len = bytes.read_u32::<LittleEndian>()
+            .map_err(|_| CommonError::InvalidStructure("Proof bytes are truncated".to_string()))? as usize;
+
+        if bytes.len() < len {
+            return Err(CommonError::InvalidStructure("Proof bytes are truncated".to_string()));
+        }
+
+        let (data, rest) = bytes.split_at(len);
+        *bytes = rest;
+        Ok(data.to_vec())
+    }
+
+    /// Restores a proof of `value` from the Merkle audit info of an indy-node reply.
+    ///
+    /// `root_hash` and `audit_path` are the base58 encoded `rootHash` and `auditPath`
+    /// fields of the reply, the audit path listing sibling hashes from the leaf up
+    /// as in RFC 6962. `leaf_index` is the transaction `seqNo` minus one and `tree_size`
+    /// the ledger size the path was computed for.
+    ///
+    /// Inner node hashes are recomputed, so `validate` with the trusted root hash
+    /// checks the path, while the stored root hash is the one from the reply.
+    #[cfg(feature = "std")]
+    pub fn from_audit_path<S: AsRef<str>>(value: TreeLeafData, leaf_index: usize, tree_size: usize,
+                                          audit_path: &[S], root_hash: &str) -> Result<Proof, CommonError> {
+        Proof::from_audit_path_with_hasher(&Hash {}, value, leaf_index, tree_size, audit_path, root_hash)
+    }
+
+    /// Same as `from_audit_path` for a ledger hashed with the given `hasher`.
+    #[cfg(feature = "std")]
+    pub fn from_audit_path_with_hasher<H, S>(hasher: &H, value: TreeLeafData, leaf_index: usize, tree_size: usize,
+                                             audit_path: &[S], root_hash: &str) -> Result<Proof, CommonError>
+        where H: MerkleHasher, S: AsRef<str> {
+        let leaf_hash = hasher.hash_leaf(&value)?;
+        let lemma = Lemma::from_audit_path_with_hasher(hasher, leaf_hash, leaf_index, tree_size, audit_path)?;
+        Ok(Proof::new(base58::decode(root_hash)?, lemma, value))
+    }
+
+    /// Restores a proof of `value` from the JSON reply of indy-node to the write of that
+    /// transaction, e.g. `{"op": "REPLY", "result": {"seqNo": 2471, "rootHash": "49dV...",
+    /// "auditPath": ["7ugD...", ...], ...}}`, as `from_audit_path` does with the fields
+    /// of `result`.
+    ///
+    /// The audit path is taken to be computed for a ledger of `seqNo` transactions, which
+    /// holds for a transaction committed in a batch of its own. Otherwise the ledger size
+    /// isn't in the reply and `from_audit_path` has to be given it. Other fields are ignored.
+    #[cfg(feature = "std")]
+    pub fn from_reply_json(value: TreeLeafData, reply: &str) -> Result<Proof, CommonError> {
+        Proof::from_reply_json_with_hasher(&Hash {}, value, reply)
+    }
+
+    /// Same as `from_reply_json` for a ledger hashed with the given `hasher`.
+    #[cfg(feature = "std")]
+    pub fn from_reply_json_with_hasher<H: MerkleHasher>(hasher: &H, value: TreeLeafData, reply: &str) -> Result<Proof, CommonError> {
+        let reply: AuditReply = serde_json::from_str(reply)
+            .map_err(|err| CommonError::InvalidStructure(format!("Cannot deserialize reply: {}", err)))?;
+
+        let result = reply.result;
+        if result.seq_no == 0 {
+            return Err(CommonError::InvalidStructure("Reply seqNo must be positive".to_string()));
+        }
+
+        Proof::from_audit_path_with_hasher(hasher, value, result.seq_no - 1, result.seq_no,
+                                           &result.audit_path, &result.root_hash)
+    }
+
+    /// Returns the index of the leaf this proof is about in a tree of `tree_size` leaves,
+    /// read from the sibling positions: this is `leaf_index`, as the bits of the path
+    /// alone miss the levels skipped by the shorter paths of an unbalanced tree.
+    /// Returns `None` if the proof doesn't fit a tree of that size.
+    pub fn path_bits(&self, tree_size: usize) -> Option<usize> {
+        self.leaf_index(tree_size)
+    }
+
+    /// Returns the position of the leaf this proof is about in a tree of `tree_size` leaves,
+    /// as told by the sibling positions.
+    /// Returns `None` if the proof doesn't fit a tree of that size.
+    pub fn leaf_index(&self, tree_size: usize) -> Option<usize> {
+        let mut lemma = &self.lemma;
+        let mut index = 0;
+        let mut size = tree_size;
+
+        while let Some(ref sub) = lemma.sub_lemma {
+            if size <= 1 {
+                return None;
+            }
+
+            let split = split_point(size);
+
+            match lemma.sibling_hash {
+                Some(Positioned::Right(_)) => size = split,
+                Some(Positioned::Left(_)) => {
+                    index += split;
+                    size -= split;
+                }
+                None => return None
+            }
+
+            lemma = sub;
+        }
+
+        if size == 1 && lemma.sibling_hash.is_none() { Some(index) } else { None }
+    }
+
+    /// Returns the position of the sibling at each level, from the root down,
+    /// on the path to leaf `leaf_index` of a tree of size `tree_size`.
+    fn sibling_positions(mut leaf_index: usize, mut tree_size: usize) -> Result<Vec<Positioned<()>>, CommonError> {
+        if leaf_index >= tree_size {
+            return Err(CommonError::InvalidStructure(
+                format!("Leaf {} is out of range for a tree of size {}", leaf_index, tree_size)));
+        }
+
+        let mut positions = Vec::new();
+
+        while tree_size > 1 {
+            let split = split_point(tree_size);
+
+            if leaf_index < split {
+                positions.push(Positioned::Right(()));
+                tree_size = split;
+            } else {
+                positions.push(Positioned::Left(()));
+                leaf_index -= split;
+                tree_size -= split;
+            }
+        }
+
+        Ok(positions)
+    }
+
     /// Checks whether this inclusion proof is well-formed,
     /// and whether its root hash matches the given `root_hash`.
     pub fn validate(&self, root_hash: &[u8]) -> Result<bool, CommonError> {
+        self.validate_with_hasher(&Hash {}, root_hash)
+    }
+
+    /// Same as `validate` for a proof of a tree built with the given `hasher`.
+    pub fn validate_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8]) -> Result<bool, CommonError> {
         if self.root_hash != root_hash || self.lemma.node_hash != root_hash {
             return Ok(false)
         }
 
-        Ok(self.validate_lemma(&self.lemma)?)
+        Ok(Proof::validate_lemma(hasher, &self.lemma)?)
+    }
+
+    /// Checks whether this inclusion proof leads to the given trusted `root_hash`.
+    ///
+    /// Unlike `validate`, neither the root hash nor the node hashes stored
+    /// in the proof are trusted: the root is recomputed from the leaf hash
+    /// and the sibling hashes only, so no `Tree` is needed.
+    pub fn validate_against(&self, root_hash: &[u8]) -> Result<bool, CommonError> {
+        self.validate_against_with_hasher(&Hash {}, root_hash)
     }
 
-    fn validate_lemma(&self, lemma: &Lemma) -> Result<bool, CommonError> {
+    /// Same as `validate_against` for a proof of a tree built with the given `hasher`.
+    pub fn validate_against_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8]) -> Result<bool, CommonError> {
+        Ok(match Proof::compute_root(hasher, &self.lemma)? {
+            Some(computed) => eq_ct(&computed, root_hash),
+            None => false
+        })
+    }
+
+    /// Same as `validate_against` for a root namespaced under `tag` by `Tree::root_with_prefix`.
+    pub fn validate_tagged(&self, tag: &[u8], tagged_root_hash: &[u8]) -> Result<bool, CommonError> {
+        self.validate_tagged_with_hasher(&Hash {}, tag, tagged_root_hash)
+    }
+
+    /// Same as `validate_tagged` for a tree hashed with the given `hasher`,
+    /// which also applies the tag as `Tree::root_with_prefix_with_hasher` does.
+    pub fn validate_tagged_with_hasher<H: MerkleHasher>(&self, hasher: &H, tag: &[u8], tagged_root_hash: &[u8]) -> Result<bool, CommonError> {
+        Ok(match Proof::compute_root(hasher, &self.lemma)? {
+            Some(computed) => eq_ct(&hasher.hash_tagged_root(tag, &computed)?, tagged_root_hash),
+            None => false
+        })
+    }
+
+    /// Checks that `value` hashes to the leaf hash of this proof,
+    /// and that this proof leads to the given trusted `root_hash`.
+    ///
+    /// Use this rather than `validate_against` when `value` is going to be trusted,
+    /// as the latter doesn't bind the `value` field to the proven leaf hash.
+    pub fn validate_value(&self, value: &TreeLeafData, root_hash: &[u8]) -> Result<bool, CommonError> {
+        self.validate_value_with_hasher(&Hash {}, value, root_hash)
+    }
+
+    /// Same as `validate_value` for a proof of a tree built with the given `hasher`.
+    pub fn validate_value_with_hasher<H: MerkleHasher>(&self, hasher: &H, value: &TreeLeafData, root_hash: &[u8]) -> Result<bool, CommonError> {
+        if hasher.hash_leaf(value)? != *self.leaf_hash() {
+            return Ok(false);
+        }
+
+        self.validate_against_with_hasher(hasher, root_hash)
+    }
+
+    /// Returns the root hash this proof implies, folding the leaf hash through the sibling
+    /// hashes in their positions, without trusting the stored root or node hashes.
+    ///
+    /// `validate_against` compares this very root with the trusted one in constant time,
+    /// so this is mostly meant for logging and debugging proofs.
+    /// Fails if the lemma chain is malformed.
+    pub fn root(&self) -> Result<Vec<u8>, CommonError> {
+        self.root_with_hasher(&Hash {})
+    }
+
+    /// Same as `root` for a proof of a tree built with the given `hasher`.
+    pub fn root_with_hasher<H: MerkleHasher>(&self, hasher: &H) -> Result<Vec<u8>, CommonError> {
+        Proof::compute_root(hasher, &self.lemma)?
+            .ok_or_else(|| CommonError::InvalidStructure("Proof lemma chain is malformed".to_string()))
+    }
+
+    /// Folds the leaf hash through the sibling hashes of `lemma`.
+    /// Returns `None` if the lemma chain is malformed.
+    fn compute_root<H: MerkleHasher>(hasher: &H, lemma: &Lemma) -> Result<Option<Vec<u8>>, CommonError> {
+        let sub = match lemma.sub_lemma {
+            None => {
+                return Ok(if lemma.sibling_hash.is_none() { Some(lemma.node_hash.clone()) } else { None });
+            }
+            Some(ref sub) => sub
+        };
+
+        let sub_hash = match Proof::compute_root(hasher, sub)? {
+            Some(hash) => hash,
+            None => return Ok(None)
+        };
+
+        Ok(match lemma.sibling_hash {
+            None => None,
+            Some(Positioned::Left(ref hash)) => Some(hasher.hash_nodes(hash, &sub_hash)?),
+            Some(Positioned::Right(ref hash)) => Some(hasher.hash_nodes(&sub_hash, hash)?)
+        })
+    }
+
+    fn validate_lemma<H: MerkleHasher>(hasher: &H, lemma: &Lemma) -> Result<bool, CommonError> {
         match lemma.sub_lemma {
 
             None =>
@@ -49,15 +527,15 @@ impl Proof {
                         Ok(false),
 
                     Some(Positioned::Left(ref hash)) => {
-                        let combined = Hash::hash_nodes(hash, &sub.node_hash)?;
-                        let hashes_match = combined.to_vec().as_slice() == lemma.node_hash.as_slice();
-                        Ok(hashes_match && self.validate_lemma(sub)?)
+                        let combined = hasher.hash_nodes(hash, &sub.node_hash)?;
+                        let hashes_match = combined == lemma.node_hash;
+                        Ok(hashes_match && Proof::validate_lemma(hasher, sub)?)
                     }
 
                     Some(Positioned::Right(ref hash)) => {
-                        let combined = Hash::hash_nodes(&sub.node_hash, hash)?;
-                        let hashes_match = combined.to_vec().as_slice() == lemma.node_hash.as_slice();
-                        Ok(hashes_match && self.validate_lemma(sub)?)
+                        let combined = hasher.hash_nodes(&sub.node_hash, hash)?;
+                        let hashes_match = combined == lemma.node_hash;
+                        Ok(hashes_match && Proof::validate_lemma(hasher, sub)?)
                     }
 
                 }
@@ -67,10 +545,168 @@ impl Proof {
 }
 
 
+/// Checks that `leaf_value` is the leaf at position `index` of a tree of `tree_size` leaves
+/// with the trusted root hash `root_hash`, without any `Tree` or `Proof`.
+///
+/// `siblings` is the audit path of the leaf: the sibling hashes from the leaf up, as in
+/// RFC 6962. Whether each one goes left or right is derived from `index` and `tree_size`.
+/// The computed root is compared to `root_hash` in constant time.
+pub fn verify_inclusion(leaf_value: &[u8], index: usize, tree_size: usize,
+                        siblings: &[Vec<u8>], root_hash: &[u8]) -> Result<bool, CommonError> {
+    verify_inclusion_with_hasher(&Hash {}, leaf_value, index, tree_size, siblings, root_hash)
+}
+
+/// Same as `verify_inclusion` but hashes the leaf and nodes with the given `hasher`.
+pub fn verify_inclusion_with_hasher<H: MerkleHasher>(hasher: &H, leaf_value: &[u8], index: usize, tree_size: usize,
+                                                     siblings: &[Vec<u8>], root_hash: &[u8]) -> Result<bool, CommonError> {
+    if index >= tree_size {
+        return Ok(false);
+    }
+
+    let leaf_hash = hasher.hash_leaf(&leaf_value)?;
+    verify_path(hasher, leaf_hash, index, tree_size, siblings, root_hash)
+}
+
+/// Checks that `root` is the subtree root at position `index` among the `roots_count` roots
+/// aggregated by `Tree::concat_roots` under the trusted `aggregate_root`.
+///
+/// `siblings` are given by `Tree::gen_root_proof`. This is `verify_inclusion` starting
+/// from a node hash instead of a leaf value.
+pub fn verify_root_inclusion(root: &[u8], index: usize, roots_count: usize,
+                             siblings: &[Vec<u8>], aggregate_root: &[u8]) -> Result<bool, CommonError> {
+    verify_root_inclusion_with_hasher(&Hash {}, root, index, roots_count, siblings, aggregate_root)
+}
+
+/// Same as `verify_root_inclusion` but hashes nodes with the given `hasher`.
+pub fn verify_root_inclusion_with_hasher<H: MerkleHasher>(hasher: &H, root: &[u8], index: usize, roots_count: usize,
+                                                          siblings: &[Vec<u8>], aggregate_root: &[u8]) -> Result<bool, CommonError> {
+    if index >= roots_count {
+        return Ok(false);
+    }
+
+    verify_path(hasher, root.to_vec(), index, roots_count, siblings, aggregate_root)
+}
+
+/// Folds `hash`, at position `index` of a tree of `tree_size` leaves, with the audit path
+/// `siblings` and compares the result to `root_hash` in constant time.
+fn verify_path<H: MerkleHasher>(hasher: &H, mut hash: Vec<u8>, index: usize, tree_size: usize,
+                                siblings: &[Vec<u8>], root_hash: &[u8]) -> Result<bool, CommonError> {
+    let positions = Proof::sibling_positions(index, tree_size)?;
+    if positions.len() != siblings.len() {
+        return Ok(false);
+    }
+
+    for (position, sibling) in positions.iter().rev().zip(siblings) {
+        hash = match *position {
+            Positioned::Left(()) => hasher.hash_nodes(sibling, &hash)?,
+            Positioned::Right(()) => hasher.hash_nodes(&hash, sibling)?
+        };
+    }
+
+    Ok(eq_ct(&hash, root_hash))
+}
+
+/// Checks in parallel whether each of `proofs` leads to the trusted `root_hash`,
+/// like `Proof::validate_against`, returning the results in the order of `proofs`.
+///
+/// Each check only hashes into its own context, so they share nothing but
+/// `root_hash`. A proof failing to be hashed is reported as invalid.
+#[cfg(feature = "rayon")]
+pub fn verify_proofs_par(proofs: &[Proof], root_hash: &[u8]) -> Vec<bool> {
+    proofs.par_iter()
+        .map(|proof| proof.validate_against(root_hash).unwrap_or(false))
+        .collect()
+}
+
+/// The fields of an indy-node write reply read by `Proof::from_reply_json`.
+#[cfg(feature = "std")]
+#[derive(Deserialize)]
+struct AuditReply {
+    result: AuditReplyResult
+}
+
+#[cfg(feature = "std")]
+#[derive(Deserialize)]
+struct AuditReplyResult {
+    #[serde(rename = "seqNo")]
+    seq_no: usize,
+
+    #[serde(rename = "rootHash")]
+    root_hash: String,
+
+    #[serde(rename = "auditPath")]
+    audit_path: Vec<String>
+}
+
+/// Reasons why `Tree::try_gen_proof` or `Tree::gen_proof_by_index` couldn't generate a proof.
+#[derive(Debug)]
+pub enum ProofError {
+    /// The tree has no leaves
+    EmptyTree,
+
+    /// No leaf of the tree holds the requested value
+    ValueNotFound,
+
+    /// The requested leaf index, and the number of leaves of the tree
+    IndexOutOfRange(usize, usize),
+
+    /// Hashing or loading a leaf failed, or the tree doesn't match its leaves, e.g. the store
+    /// of a `StoredTree` changed since the tree was built
+    CommonError(CommonError)
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProofError::EmptyTree => write!(f, "Tree is empty"),
+            ProofError::ValueNotFound => write!(f, "Value not found in tree"),
+            ProofError::IndexOutOfRange(index, count) =>
+                write!(f, "Leaf {} is out of range for a tree of size {}", index, count),
+            ProofError::CommonError(ref err) => err.fmt(f)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ProofError {
+    fn description(&self) -> &str {
+        match *self {
+            ProofError::EmptyTree => "Tree is empty",
+            ProofError::ValueNotFound => "Value not found in tree",
+            ProofError::IndexOutOfRange(..) => "Leaf index is out of range",
+            ProofError::CommonError(ref err) => err.description()
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            ProofError::EmptyTree => None,
+            ProofError::ValueNotFound => None,
+            ProofError::IndexOutOfRange(..) => None,
+            ProofError::CommonError(ref err) => Some(err)
+        }
+    }
+}
+
+impl From<CommonError> for ProofError {
+    fn from(err: CommonError) -> Self {
+        ProofError::CommonError(err)
+    }
+}
+
+impl From<ProofError> for CommonError {
+    fn from(err: ProofError) -> Self {
+        match err {
+            ProofError::CommonError(err) => err,
+            err => CommonError::InvalidStructure(err.to_string())
+        }
+    }
+}
+
 /// A `Lemma` holds the hash of a node, the hash of its sibling node,
 /// and a sub lemma, whose `node_hash`, when combined with this `sibling_hash`
 /// must be equal to this `node_hash`.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Lemma {
     pub node_hash: Vec<u8>,
     pub sibling_hash: Option<Positioned<Vec<u8>>>,
@@ -80,6 +716,7 @@ pub struct Lemma {
 impl Lemma {
 
     /// Attempts to generate a proof that the a value with hash `needle` is a member of the given `tree`.
+    #[cfg(feature = "std")]
     pub fn new(tree: &Tree, needle: &[u8]) -> Option<Lemma> {
         match *tree {
             Tree::Empty {.. } =>
@@ -88,11 +725,95 @@ impl Lemma {
             Tree::Leaf { ref hash, .. } =>
                 Lemma::new_leaf_proof(hash, needle),
 
-            Tree::Node { ref hash, ref left, ref right } =>
+            Tree::Node { ref hash, ref left, ref right, .. } =>
                 Lemma::new_tree_proof(hash, needle, left, right)
         }
     }
 
+    /// Restores the lemma of the leaf with hash `leaf_hash` at position `leaf_index`
+    /// of a ledger of `tree_size` leaves from its base58 encoded `audit_path`,
+    /// listing sibling hashes from the leaf up, see `Proof::from_audit_path`.
+    #[cfg(feature = "std")]
+    pub fn from_audit_path<S: AsRef<str>>(leaf_hash: Vec<u8>, leaf_index: usize, tree_size: usize,
+                                          audit_path: &[S]) -> Result<Lemma, CommonError> {
+        Lemma::from_audit_path_with_hasher(&Hash {}, leaf_hash, leaf_index, tree_size, audit_path)
+    }
+
+    /// Same as `from_audit_path` for a ledger hashed with the given `hasher`.
+    #[cfg(feature = "std")]
+    pub fn from_audit_path_with_hasher<H, S>(hasher: &H, leaf_hash: Vec<u8>, leaf_index: usize, tree_size: usize,
+                                             audit_path: &[S]) -> Result<Lemma, CommonError>
+        where H: MerkleHasher, S: AsRef<str> {
+        let positions = Proof::sibling_positions(leaf_index, tree_size)?;
+
+        if audit_path.len() != positions.len() {
+            return Err(CommonError::InvalidStructure(
+                format!("Audit path holds {} hashes instead of {}", audit_path.len(), positions.len())));
+        }
+
+        let mut siblings = Vec::with_capacity(audit_path.len());
+        for hash in audit_path.iter().rev() {
+            siblings.push(base58::decode(hash.as_ref())?);
+        }
+
+        Lemma::from_siblings(hasher, leaf_hash, positions, siblings)
+    }
+
+    /// Builds the lemma chain folding `leaf_hash` with the `siblings` hashes,
+    /// given from the root down along with their `positions`.
+    fn from_siblings<H: MerkleHasher>(hasher: &H, leaf_hash: Vec<u8>, positions: Vec<Positioned<()>>, siblings: Vec<Vec<u8>>) -> Result<Lemma, CommonError> {
+        let mut lemma = Lemma {
+            node_hash: leaf_hash,
+            sibling_hash: None,
+            sub_lemma: None
+        };
+
+        for (position, hash) in positions.into_iter().zip(siblings.into_iter()).rev() {
+            let (node_hash, sibling_hash) = match position {
+                Positioned::Left(()) => (hasher.hash_nodes(&hash, &lemma.node_hash)?, Positioned::Left(hash)),
+                Positioned::Right(()) => (hasher.hash_nodes(&lemma.node_hash, &hash)?, Positioned::Right(hash))
+            };
+
+            lemma = Lemma {
+                node_hash: node_hash,
+                sibling_hash: Some(sibling_hash),
+                sub_lemma: Some(Box::new(lemma))
+            };
+        }
+
+        Ok(lemma)
+    }
+
+    /// Generates the lemma of the leaf at position `index` of the given `tree`.
+    /// Returns `None` if `index` is out of range.
+    #[cfg(feature = "std")]
+    pub fn new_by_index(tree: &Tree, index: usize) -> Option<Lemma> {
+        match *tree {
+            Tree::Empty { .. } =>
+                None,
+
+            Tree::Leaf { ref hash, .. } =>
+                if index == 0 { Lemma::new_leaf_proof(hash, hash) } else { None },
+
+            Tree::Node { ref hash, ref left, ref right, count, .. } => {
+                let left_count = left_count(count, left, right);
+
+                let (sub_lemma, sibling_hash) = if index < left_count {
+                    (Lemma::new_by_index(left, index)?, Positioned::Right(right.hash().clone()))
+                } else {
+                    (Lemma::new_by_index(right, index - left_count)?, Positioned::Left(left.hash().clone()))
+                };
+
+                Some(Lemma {
+                    node_hash: hash.clone(),
+                    sibling_hash: Some(sibling_hash),
+                    sub_lemma: Some(Box::new(sub_lemma))
+                })
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
     fn new_leaf_proof(hash: &[u8], needle: &[u8]) -> Option<Lemma> {
         if *hash == *needle {
             Some(Lemma {
@@ -105,6 +826,7 @@ impl Lemma {
         }
     }
 
+    #[cfg(feature = "std")]
     fn new_tree_proof(hash: &[u8], needle: &[u8], left: &Tree, right: &Tree) -> Option<Lemma> {
         Lemma::new(left, needle)
             .map(|lemma| {
@@ -132,7 +854,13 @@ impl Lemma {
 }
 
 /// Tags a value so that we know from which branch of a `Tree` (if any) it was found.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// For a sibling hash `h` in a `Lemma`, the tag tells on which side of the running hash
+/// it goes when folding up to the root: `Left(h)` gives `hash_nodes(h, running)`
+/// and `Right(h)` gives `hash_nodes(running, h)`.
+///
+/// Ordering puts every `Left` before every `Right`, then compares the values.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Positioned<T> {
 
     /// The value was found in the left branch
@@ -141,3 +869,521 @@ pub enum Positioned<T> {
     /// The value was found in the right branch
     Right(T)
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    extern crate hex;
+
+    use super::*;
+    use self::hex::FromHex;
+    use utils::crypto::hash::Sha512Hash;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn lemma_len_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        for (index, value) in values(5).into_iter().enumerate() {
+            let proof = tree.gen_proof(value).unwrap().unwrap();
+            assert_eq!(proof.lemma_len(), tree.depth_of_leaf(index).unwrap());
+        }
+    }
+
+    #[test]
+    fn leaf_hash_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        for value in values(5) {
+            let proof = tree.gen_proof(value.clone()).unwrap().unwrap();
+            assert_eq!(proof.leaf_hash(), &Hash::hash_leaf(&value).unwrap().to_vec());
+        }
+    }
+
+    #[test]
+    fn accessors_work_for_single_leaf() {
+        let tree = Tree::from_leaves(values(1)).unwrap();
+        let proof = tree.gen_proof(b"value0".to_vec()).unwrap().unwrap();
+
+        assert_eq!(proof.lemma_len(), 0);
+        assert_eq!(proof.leaf_hash(), tree.hash());
+    }
+
+    #[test]
+    fn validate_value_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        for value in values(5) {
+            let proof = tree.gen_proof(value.clone()).unwrap().unwrap();
+            assert!(proof.validate_value(&value, tree.hash()).unwrap());
+        }
+    }
+
+    #[test]
+    fn validate_value_works_for_forged_value() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let mut proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        proof.value = b"forged".to_vec();
+
+        assert!(proof.validate_against(tree.hash()).unwrap());
+        assert!(!proof.validate_value(&proof.value, tree.hash()).unwrap());
+        assert!(!proof.validate_value(&b"value3".to_vec(), tree.hash()).unwrap());
+    }
+
+    #[test]
+    fn validate_value_works_for_other_root() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        let proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        assert!(!proof.validate_value(&b"value2".to_vec(), other.hash()).unwrap());
+    }
+
+    #[test]
+    fn compact_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let proof = tree.gen_proof(value.clone()).unwrap().unwrap();
+
+                let compact = proof.to_compact(index, count).unwrap();
+                assert_eq!(compact.len(), 8 + 32 * proof.lemma_len());
+
+                let restored = Proof::from_compact(&compact, count, value).unwrap();
+                assert_eq!(restored.root_hash, proof.root_hash);
+                assert_eq!(restored.lemma, proof.lemma);
+                assert_eq!(restored.value, proof.value);
+                assert!(restored.validate(tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn to_compact_works_for_wrong_index() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+
+        assert!(proof.to_compact(3, 5).is_err());
+        assert!(proof.to_compact(2, 9).is_err());
+        assert!(proof.to_compact(5, 5).is_err());
+    }
+
+    #[test]
+    fn from_compact_works_for_truncated_bytes() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        let compact = proof.to_compact(2, 5).unwrap();
+
+        assert!(Proof::from_compact(&compact[..4], 5, b"value2".to_vec()).is_err());
+        assert!(Proof::from_compact(&compact[..compact.len() - 1], 5, b"value2".to_vec()).is_err());
+        assert!(Proof::from_compact(&compact[..8], 5, b"value2".to_vec()).is_err());
+    }
+
+    #[test]
+    fn leaf_index_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let proof = tree.gen_proof(value).unwrap().unwrap();
+                assert_eq!(proof.leaf_index(count), Some(index));
+            }
+        }
+    }
+
+    #[test]
+    fn root_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for value in values(count) {
+                let mut proof = tree.gen_proof(value).unwrap().unwrap();
+                assert_eq!(&proof.root().unwrap(), tree.hash());
+
+                // the stored hashes aren't trusted
+                proof.root_hash = vec![0; 32];
+                if proof.lemma.sub_lemma.is_some() {
+                    proof.lemma.node_hash = vec![0; 32];
+                }
+                assert_eq!(&proof.root().unwrap(), tree.hash());
+            }
+        }
+
+        let tree = Tree::from_leaves_with_hasher(&Sha512Hash {}, values(5)).unwrap();
+        let proof = tree.gen_proof_with_hasher(&Sha512Hash {}, b"value3".to_vec()).unwrap().unwrap();
+        assert_eq!(&proof.root_with_hasher(&Sha512Hash {}).unwrap(), tree.hash());
+    }
+
+    #[test]
+    fn root_works_for_malformed_lemma() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let mut proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        proof.lemma.sibling_hash = None;
+
+        assert!(proof.root().is_err());
+        assert!(!proof.validate_against(tree.hash()).unwrap());
+    }
+
+    #[test]
+    fn path_bits_works() {
+        for count in 1..20 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for index in 0..count {
+                let proof = tree.gen_proof_by_index(index).unwrap();
+                assert_eq!(proof.path_bits(count), Some(index));
+            }
+        }
+    }
+
+    #[test]
+    fn path_bits_works_for_shorter_last_path() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let proof = tree.gen_proof(b"value4".to_vec()).unwrap().unwrap();
+        assert_eq!(proof.path_bits(5), Some(4));
+
+        let proof = tree.gen_proof(b"value3".to_vec()).unwrap().unwrap();
+        assert_eq!(proof.path_bits(5), Some(3));
+        assert_eq!(proof.path_bits(3), None);
+
+        let single = Tree::from_leaves(values(1)).unwrap();
+        assert_eq!(single.gen_proof(b"value0".to_vec()).unwrap().unwrap().path_bits(1), Some(0));
+    }
+
+    #[test]
+    fn leaf_index_works_for_other_tree_size() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let proof = tree.gen_proof(b"value4".to_vec()).unwrap().unwrap();
+
+        assert_eq!(proof.leaf_index(0), None);
+        assert_eq!(proof.leaf_index(1), None);
+        assert_eq!(proof.leaf_index(6), None);
+    }
+
+    #[test]
+    fn bytes_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for value in values(count) {
+                let proof = tree.gen_proof(value).unwrap().unwrap();
+
+                let restored = Proof::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+                assert_eq!(restored.root_hash, proof.root_hash);
+                assert_eq!(restored.lemma, proof.lemma);
+                assert_eq!(restored.value, proof.value);
+                assert!(restored.validate(tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn from_bytes_works_for_unknown_version() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let mut bytes = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap().to_bytes().unwrap();
+        bytes[0] = 2;
+
+        match Proof::from_bytes(&bytes) {
+            Err(CommonError::InvalidStructure(ref msg)) => assert!(msg.contains("version 2")),
+            _ => panic!("unknown version accepted")
+        }
+    }
+
+    #[test]
+    fn from_bytes_works_for_malformed_bytes() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let bytes = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap().to_bytes().unwrap();
+
+        assert!(Proof::from_bytes(&[]).is_err());
+        assert!(Proof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Proof::from_bytes(&trailing).is_err());
+    }
+
+    #[test]
+    fn from_bytes_works_for_too_many_levels() {
+        fn proof_bytes(levels: usize) -> Vec<u8> {
+            let mut bytes = vec![PROOF_BYTES_VERSION];
+            Proof::write_bytes(&mut bytes, &[0; 32]).unwrap();
+            Proof::write_bytes(&mut bytes, b"value").unwrap();
+
+            for _ in 0..levels {
+                Proof::write_bytes(&mut bytes, &[1; 32]).unwrap();
+                bytes.push(1);
+                Proof::write_bytes(&mut bytes, &[2; 32]).unwrap();
+            }
+
+            Proof::write_bytes(&mut bytes, &[3; 32]).unwrap();
+            bytes.push(0);
+            bytes
+        }
+
+        let bytes = proof_bytes(PROOF_MAX_LEVELS);
+        assert_eq!(Proof::from_bytes(&bytes).unwrap().to_bytes().unwrap(), bytes);
+        assert!(Proof::from_bytes(&proof_bytes(PROOF_MAX_LEVELS + 1)).is_err());
+        assert!(Proof::from_bytes(&proof_bytes(1000000)).is_err());
+    }
+
+    #[test]
+    fn proofs_dedup_in_hash_set() {
+        use std::collections::HashSet;
+
+        let tree = Tree::from_leaves(values(7)).unwrap();
+        let other = Tree::from_leaves(values(8)).unwrap();
+
+        let mut proofs = HashSet::new();
+        for _ in 0..3 {
+            for value in values(7) {
+                proofs.insert(tree.gen_proof(value.clone()).unwrap().unwrap());
+                proofs.insert(other.gen_proof(value).unwrap().unwrap());
+            }
+        }
+        assert_eq!(proofs.len(), 14);
+
+        let proof = tree.gen_proof_by_index(2).unwrap();
+        assert!(proofs.contains(&proof));
+        assert_ne!(proof, tree.gen_proof_by_index(3).unwrap());
+    }
+
+    #[test]
+    fn proof_error_converts_to_common_error() {
+        match CommonError::from(ProofError::IndexOutOfRange(5, 5)) {
+            CommonError::InvalidStructure(ref msg) => assert_eq!(msg, "Leaf 5 is out of range for a tree of size 5"),
+            err => panic!("unexpected error {:?}", err)
+        }
+
+        match CommonError::from(ProofError::from(CommonError::InvalidState("state".to_string()))) {
+            CommonError::InvalidState(ref msg) => assert_eq!(msg, "state"),
+            err => panic!("unexpected error {:?}", err)
+        }
+    }
+
+    #[test]
+    fn verify_inclusion_works_for_known_answers() {
+        let root = Vec::from_hex("fe6e9d4604f578602851a2c15ef3894ca07b9517f7d5f7dedc28179ca888580d").unwrap();
+        let leaf0 = Vec::from_hex("2215e8ac4e2b871c2a48189e79738c956c081e23ac2f2415bf77da199dfd920c").unwrap();
+        let leaf1 = Vec::from_hex("fa61e3dec3439589f4784c893bf321d0084f04c572c7af2b68e3f3360a35b486").unwrap();
+        let leaf2 = Vec::from_hex("906c5d2485cae722073a430f4d04fe1767507592cef226629aeadb85a2ec909d").unwrap();
+        let node01 = Vec::from_hex("e8bcd97e349693dcfec054fe219ab357b75d3c1cd9f8be1767f6090f9c86f9fd").unwrap();
+
+        assert!(verify_inclusion(b"1", 0, 3, &[leaf1.clone(), leaf2.clone()], &root).unwrap());
+        assert!(verify_inclusion(b"2", 1, 3, &[leaf0.clone(), leaf2.clone()], &root).unwrap());
+        assert!(verify_inclusion(b"3", 2, 3, &[node01.clone()], &root).unwrap());
+
+        assert!(!verify_inclusion(b"1", 1, 3, &[leaf1.clone(), leaf2.clone()], &root).unwrap());
+        assert!(!verify_inclusion(b"3", 2, 3, &[leaf0.clone()], &root).unwrap());
+        assert!(!verify_inclusion(b"3", 2, 4, &[node01.clone()], &root).unwrap());
+        assert!(!verify_inclusion(b"3", 3, 3, &[node01.clone()], &root).unwrap());
+    }
+
+    /// Reply of a test pool to the write of a schema, also used by the libvcx tests.
+    const SCHEMA_REPLY: &'static str = r#"{"op":"REPLY","result":{"reqId":1526055830283606591,"auditPath":["7ugDzcJ9KosJtxeG1USXrnthg6z8zcFfmh76s5P3Unmt","GKvW54XopASjPX8ihN9wjeVfYVqgmKW2K5nehUo2UCj6","32v8haTvzkNGxrPiHYRiBipqPLA2FDj7BY5mTt9anKD7","49eEV1Y1xKttCLfrsTu1p9CQcTwuBjGhXKAWTfyVLzvm","7ijtXV2TeGBMHwdrVKpFKBwnzu2dWxhfTH9sNa7xM4wM","7WNdgAkQzhnV2LcHPynDc6NPSrqipiZr2vP4zYDGzWKR"],"rootHash":"49dVmci81excka4Ff17uUPY6NyK5Fkyb7mxKQxHXGUXT","identifier":"2hoqvcwupRTUNkXn6ArYzs","signature":"2EpvwtTCBXPpwC8yG7bVWtWCqFhvU917ATma7auc4hpph3s9wpmGKVwUdp6WAd4MCCH2XsgfW9RS3aw6cNNJ6iVy","type":"101","data":{"name":"test-licence","attr_names":["age","height","name","sex"],"version":"4.4.4"},"seqNo":2471,"txnTime":1526055830,"signatures":null}}"#;
+
+    const SCHEMA_AUDIT_PATH: [&'static str; 6] = [
+        "7ugDzcJ9KosJtxeG1USXrnthg6z8zcFfmh76s5P3Unmt",
+        "GKvW54XopASjPX8ihN9wjeVfYVqgmKW2K5nehUo2UCj6",
+        "32v8haTvzkNGxrPiHYRiBipqPLA2FDj7BY5mTt9anKD7",
+        "49eEV1Y1xKttCLfrsTu1p9CQcTwuBjGhXKAWTfyVLzvm",
+        "7ijtXV2TeGBMHwdrVKpFKBwnzu2dWxhfTH9sNa7xM4wM",
+        "7WNdgAkQzhnV2LcHPynDc6NPSrqipiZr2vP4zYDGzWKR"
+    ];
+
+    const SCHEMA_ROOT_HASH: &'static str = "49dVmci81excka4Ff17uUPY6NyK5Fkyb7mxKQxHXGUXT";
+
+    /// Leaf hash of the schema transaction: the first hash of the audit path in the reply
+    /// to the cred def written right after it, at `seqNo` 2472.
+    const SCHEMA_LEAF_HASH: &'static str = "2MNcysNRXQA5hN1nRrEFaT6XBV4mTowaz5qLS2aoU5u7";
+
+    #[test]
+    fn lemma_from_audit_path_works_for_node_reply() {
+        let leaf_hash = base58::decode(SCHEMA_LEAF_HASH).unwrap();
+        let root_hash = base58::decode(SCHEMA_ROOT_HASH).unwrap();
+
+        let lemma = Lemma::from_audit_path(leaf_hash.clone(), 2470, 2471, &SCHEMA_AUDIT_PATH).unwrap();
+        assert_eq!(lemma.node_hash, root_hash);
+
+        let proof = Proof::new(root_hash.clone(), lemma, Vec::new());
+        assert_eq!(proof.leaf_index(2471), Some(2470));
+        assert!(proof.validate_against(&root_hash).unwrap());
+
+        let mut forged = leaf_hash.clone();
+        forged[0] ^= 1;
+        assert_ne!(Lemma::from_audit_path(forged, 2470, 2471, &SCHEMA_AUDIT_PATH).unwrap().node_hash, root_hash);
+
+        // the path of the last leaf is shorter than the ones of its neighbours
+        assert!(Lemma::from_audit_path(leaf_hash.clone(), 2469, 2471, &SCHEMA_AUDIT_PATH).is_err());
+        assert!(Lemma::from_audit_path(leaf_hash, 2470, 2472, &SCHEMA_AUDIT_PATH).is_err());
+    }
+
+    #[test]
+    fn from_reply_json_works_for_node_reply() {
+        // the schema transaction isn't known, only its reply
+        let value = b"schema".to_vec();
+        let proof = Proof::from_reply_json(value.clone(), SCHEMA_REPLY).unwrap();
+
+        let lemma = Lemma::from_audit_path(Hash::hash_leaf(&value).unwrap().to_vec(), 2470, 2471, &SCHEMA_AUDIT_PATH).unwrap();
+        assert_eq!(proof.lemma, lemma);
+        assert_eq!(proof.root_hash, base58::decode(SCHEMA_ROOT_HASH).unwrap());
+        assert_eq!(proof.leaf_index(2471), Some(2470));
+        assert!(!proof.validate(&proof.root_hash).unwrap());
+    }
+
+    #[test]
+    fn from_reply_json_works_for_malformed_reply() {
+        assert!(Proof::from_reply_json(Vec::new(), "{}").is_err());
+        assert!(Proof::from_reply_json(Vec::new(), r#"{"result":{"seqNo":1,"rootHash":"49dV"}}"#).is_err());
+        assert!(Proof::from_reply_json(Vec::new(), r#"{"result":{"seqNo":0,"rootHash":"","auditPath":[]}}"#).is_err());
+        assert!(Proof::from_reply_json(Vec::new(), &SCHEMA_REPLY.replace(r#""seqNo":2471"#, r#""seqNo":2472"#)).is_err());
+    }
+
+    #[test]
+    fn verify_inclusion_works_same_as_audit_path() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let siblings = tree.audit_path(index).unwrap().into_iter()
+                    .map(|sibling| match sibling { Positioned::Left(hash) | Positioned::Right(hash) => hash })
+                    .collect::<Vec<Vec<u8>>>();
+
+                assert!(verify_inclusion(&value, index, count, &siblings, tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn positioned_ordering_works() {
+        assert!(Positioned::Left(vec![0xff]) < Positioned::Right(vec![0x00]));
+        assert!(Positioned::Left(vec![0x00]) < Positioned::Left(vec![0x01]));
+        assert!(Positioned::Right(vec![0x00]) < Positioned::Right(vec![0x01]));
+
+        let mut positions = vec![Positioned::Right(2), Positioned::Left(3), Positioned::Right(1), Positioned::Left(1)];
+        positions.sort();
+        assert_eq!(positions, vec![Positioned::Left(1), Positioned::Left(3), Positioned::Right(1), Positioned::Right(2)]);
+    }
+
+    #[test]
+    fn positioned_tags_sibling_side() {
+        let tree = Tree::from_leaves(values(2)).unwrap();
+        let left_hash = Hash::hash_leaf(&b"value0".to_vec()).unwrap().to_vec();
+        let right_hash = Hash::hash_leaf(&b"value1".to_vec()).unwrap().to_vec();
+
+        let proof = tree.gen_proof(b"value1".to_vec()).unwrap().unwrap();
+        assert_eq!(proof.lemma.sibling_hash, Some(Positioned::Left(left_hash.clone())));
+        assert_eq!(tree.hash(), &Hash::hash_nodes(&left_hash, &right_hash).unwrap().to_vec());
+    }
+
+    #[test]
+    fn validate_tagged_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let tagged = tree.root_with_prefix(b"domain").unwrap();
+
+        for value in values(5) {
+            let proof = tree.gen_proof(value).unwrap().unwrap();
+            assert!(proof.validate_tagged(b"domain", &tagged).unwrap());
+            assert!(!proof.validate_tagged(b"pool", &tagged).unwrap());
+            assert!(!proof.validate_tagged(b"domain", tree.hash()).unwrap());
+            assert!(!proof.validate_against(&tagged).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn verify_proofs_par_works() {
+        let tree = Tree::from_leaves(values(40)).unwrap();
+        let other = Tree::from_leaves(values(41)).unwrap();
+
+        let mut proofs = Vec::new();
+        for (i, value) in values(40).into_iter().enumerate() {
+            let source = if i % 3 == 0 { &other } else { &tree };
+            proofs.push(source.gen_proof(value).unwrap().unwrap());
+        }
+
+        let expected = proofs.iter().map(|proof| proof.validate_against(tree.hash()).unwrap()).collect::<Vec<bool>>();
+        assert_eq!(verify_proofs_par(&proofs, tree.hash()), expected);
+        assert_eq!(expected.iter().filter(|&&valid| valid).count(), 26);
+        assert!(verify_proofs_par(&[], tree.hash()).is_empty());
+    }
+
+    /// Compares `verify_proofs_par` with validating the proofs one after the other,
+    /// run it with `cargo test measure_verify_proofs_par --features rayon -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    #[cfg(feature = "rayon")]
+    fn measure_verify_proofs_par() {
+        use std::time::Instant;
+
+        let count = 1 << 14;
+        let tree = Tree::from_leaves(values(count)).unwrap();
+        let proofs = (0..count).map(|i| tree.gen_proof_by_index(i).unwrap()).collect::<Vec<Proof>>();
+
+        let start = Instant::now();
+        let sequential = proofs.iter().map(|proof| proof.validate(tree.hash()).unwrap()).collect::<Vec<bool>>();
+        let sequential_time = start.elapsed();
+
+        let start = Instant::now();
+        let parallel = verify_proofs_par(&proofs, tree.hash());
+        let parallel_time = start.elapsed();
+
+        assert_eq!(parallel, sequential);
+        assert!(parallel.iter().all(|&valid| valid));
+
+        println!("validate:          {} proofs in {:?}", count, sequential_time);
+        println!("verify_proofs_par: {} proofs in {:?}", count, parallel_time);
+    }
+
+    #[test]
+    fn validate_against_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for value in values(count) {
+                let proof = tree.gen_proof(value).unwrap().unwrap();
+                assert!(proof.validate_against(tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn validate_against_works_for_other_root() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        let proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        assert!(!proof.validate_against(other.hash()).unwrap());
+    }
+
+    #[test]
+    fn validate_against_ignores_stored_hashes() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        let mut proof = tree.gen_proof(b"value2".to_vec()).unwrap().unwrap();
+        proof.root_hash = other.hash().clone();
+        proof.lemma.node_hash = other.hash().clone();
+
+        assert!(proof.validate_against(tree.hash()).unwrap());
+        assert!(!proof.validate_against(other.hash()).unwrap());
+    }
+
+    #[test]
+    fn validate_against_honors_sibling_position() {
+        let tree = Tree::from_leaves(values(4)).unwrap();
+
+        let mut proof = tree.gen_proof(b"value1".to_vec()).unwrap().unwrap();
+        proof.lemma.sibling_hash = match proof.lemma.sibling_hash {
+            Some(Positioned::Right(hash)) => Some(Positioned::Left(hash)),
+            Some(Positioned::Left(hash)) => Some(Positioned::Right(hash)),
+            None => None
+        };
+
+        assert!(!proof.validate_against(tree.hash()).unwrap());
+    }
+}
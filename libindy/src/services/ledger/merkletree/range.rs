@@ -0,0 +1,199 @@
+use services::ledger::merkletree::multiproof::MultiProof;
+use services::ledger::merkletree::tree::TreeLeafData;
+use utils::crypto::hash::{Hash, MerkleHasher, eq_ct};
+use errors::common::CommonError;
+
+/// A range proof represent the fact that `values` are all the leaves
+/// between `lo` and `hi`, inclusive, of a `Tree` whose leaves are sorted.
+///
+/// The covered leaves are contiguous and also take in the leaves right below `lo`
+/// and right above `hi` if any, which shows that no leaf of the range was left out.
+/// `proof` proves all of them at once and holds the roots of the subtrees around them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeProof {
+    /// The lower bound of the range
+    pub lo: TreeLeafData,
+
+    /// The upper bound of the range
+    pub hi: TreeLeafData,
+
+    /// The covered leaves, in tree order
+    pub values: Vec<TreeLeafData>,
+
+    /// The inclusion proof of the covered leaves
+    pub proof: MultiProof
+}
+
+impl RangeProof {
+
+    /// Constructs a new `RangeProof`
+    pub fn new(lo: TreeLeafData, hi: TreeLeafData, values: Vec<TreeLeafData>, proof: MultiProof) -> Self {
+        RangeProof {
+            lo: lo,
+            hi: hi,
+            values: values,
+            proof: proof
+        }
+    }
+
+    /// Returns the covered leaves within the range, i.e. without the boundary leaves.
+    pub fn range_values(&self) -> Vec<&TreeLeafData> {
+        self.values.iter()
+            .filter(|value| **value >= self.lo && **value <= self.hi)
+            .collect()
+    }
+
+    /// Checks whether this proof shows that the leaves within the range are exactly
+    /// `range_values()` in the sorted tree with the trusted root hash `root_hash`
+    /// and `tree_size` leaves.
+    ///
+    /// The covered leaves must be sorted and contiguous. Unless they start at the first
+    /// leaf of the tree the first one must be below the range, and unless they end at
+    /// the last leaf the last one must be above it: only these two can be out of the range.
+    pub fn validate(&self, root_hash: &[u8], tree_size: usize) -> Result<bool, CommonError> {
+        self.validate_with_hasher(&Hash {}, root_hash, tree_size)
+    }
+
+    /// Same as `validate` but for a tree hashed with the given `hasher`.
+    pub fn validate_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8], tree_size: usize) -> Result<bool, CommonError> {
+        if self.lo > self.hi || self.proof.tree_size != tree_size {
+            return Ok(false);
+        }
+
+        if tree_size == 0 {
+            return Ok(self.values.is_empty() && eq_ct(&hasher.hash_empty()?, root_hash));
+        }
+
+        let indices = &self.proof.indices;
+
+        if indices.is_empty() || indices.len() != self.values.len()
+            || indices.windows(2).any(|pair| pair[0] + 1 != pair[1])
+            || self.values.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Ok(false);
+        }
+
+        let start = indices[0];
+        let end = indices[indices.len() - 1] + 1;
+
+        let first = &self.values[0];
+        let last = &self.values[self.values.len() - 1];
+
+        // a leaf of the range could be hidden right outside of the covered leaves
+        if (start > 0 && *first >= self.lo) || (end < tree_size && *last <= self.hi) {
+            return Ok(false);
+        }
+
+        // only the covered leaves at both ends can be out of the range
+        let last_position = self.values.len() - 1;
+        for (position, value) in self.values.iter().enumerate() {
+            let allowed = (*value >= self.lo && *value <= self.hi)
+                || (position == 0 && *value < self.lo)
+                || (position == last_position && *value > self.hi);
+
+            if !allowed {
+                return Ok(false);
+            }
+        }
+
+        self.proof.validate_with_hasher(hasher, root_hash, &self.values)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::tree::Tree;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i * 2 + 10).into_bytes()).collect()
+    }
+
+    fn bound(i: usize) -> TreeLeafData {
+        format!("value{:02}", i).into_bytes()
+    }
+
+    #[test]
+    fn height_balanced_from_sorted_works() {
+        let tree = Tree::height_balanced_from_sorted(values(9)).unwrap();
+        assert_eq!(tree, Tree::from_leaves(values(9)).unwrap());
+        assert!(Tree::height_balanced_from_sorted(vec![b"a".to_vec(), b"a".to_vec()]).is_ok());
+        assert!(Tree::height_balanced_from_sorted(vec![]).is_ok());
+    }
+
+    #[test]
+    fn height_balanced_from_sorted_works_for_unsorted() {
+        let mut unsorted = values(9);
+        unsorted.swap(3, 4);
+        assert!(Tree::height_balanced_from_sorted(unsorted).is_err());
+    }
+
+    #[test]
+    fn gen_range_proof_works() {
+        for count in 1..12 {
+            let leaves = values(count);
+            let tree = Tree::height_balanced_from_sorted(leaves.clone()).unwrap();
+
+            for lo in 8..count * 2 + 12 {
+                for hi in lo..count * 2 + 12 {
+                    let proof = tree.gen_range_proof(&bound(lo), &bound(hi)).unwrap();
+
+                    let expected = leaves.iter()
+                        .filter(|value| **value >= bound(lo) && **value <= bound(hi))
+                        .collect::<Vec<_>>();
+
+                    assert_eq!(proof.range_values(), expected);
+                    assert!(proof.validate(tree.hash(), count).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gen_range_proof_works_for_empty_tree() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+        let proof = tree.gen_range_proof(&bound(10), &bound(20)).unwrap();
+
+        assert!(proof.range_values().is_empty());
+        assert!(proof.validate(tree.hash(), 0).unwrap());
+    }
+
+    #[test]
+    fn gen_range_proof_works_for_reversed_bounds() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert!(tree.gen_range_proof(&bound(14), &bound(12)).is_none());
+    }
+
+    #[test]
+    fn validate_works_for_dropped_leaves() {
+        let tree = Tree::from_leaves(values(8)).unwrap();
+        let proof = tree.gen_range_proof(&bound(13), &bound(19)).unwrap();
+        assert_eq!(proof.range_values().len(), 3);
+
+        // a leaf left out of the range
+        let mut first_dropped = proof.clone();
+        first_dropped.values.remove(1);
+        first_dropped.proof = tree.gen_multiproof(&[1, 3, 4, 5]).unwrap();
+        assert!(!first_dropped.validate(tree.hash(), 8).unwrap());
+
+        // the range cut short before the boundary leaf
+        let mut last_dropped = proof.clone();
+        last_dropped.values.pop();
+        last_dropped.proof = tree.gen_multiproof(&[1, 2, 3, 4]).unwrap();
+        assert!(!last_dropped.validate(tree.hash(), 8).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_other_root_or_bounds() {
+        let tree = Tree::from_leaves(values(8)).unwrap();
+        let other = Tree::from_leaves(values(9)).unwrap();
+        let proof = tree.gen_range_proof(&bound(13), &bound(19)).unwrap();
+
+        assert!(!proof.validate(other.hash(), 8).unwrap());
+        assert!(!proof.validate(tree.hash(), 9).unwrap());
+
+        let mut wider = proof.clone();
+        wider.hi = bound(21);
+        assert!(!wider.validate(tree.hash(), 8).unwrap());
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+
+use services::ledger::merkletree::proof::Positioned;
+use services::ledger::merkletree::tree::TreeLeafData;
+use utils::crypto::hash::{hash, Hash, MerkleHasher, HASHBYTES, eq_ct};
+use errors::common::CommonError;
+
+/// Number of levels of a `SparseTree`, one per bit of a key hash.
+pub const SPARSE_TREE_DEPTH: usize = HASHBYTES * 8;
+
+/// A sparse Merkle tree mapping arbitrary keys, e.g. DIDs or schema ids, to values.
+///
+/// Every key is placed at the leaf found by following the bits of its SHA-256 hash
+/// from the root, most significant bit first, so the tree always has 2^256 leaves.
+/// The leaves without a value are empty and hashed with `hash_empty`, and each level of
+/// empty subtrees shares a single default hash: only the paths to the stored keys are
+/// ever hashed. A `SparseProof` holds one sibling hash per level whatever the number
+/// of keys, and proves either the value of a key or that the key is absent.
+#[allow(missing_debug_implementations)]
+pub struct SparseTree<H: MerkleHasher = Hash> {
+    hasher: H,
+    leaves: BTreeMap<Vec<u8>, SparseLeaf>
+}
+
+struct SparseLeaf {
+    value: TreeLeafData,
+    hash: Vec<u8>
+}
+
+impl SparseTree<Hash> {
+
+    /// Constructs a new empty tree hashing with the default `Hash`
+    pub fn new() -> Self {
+        SparseTree::with_hasher(Hash {})
+    }
+
+}
+
+impl Default for SparseTree<Hash> {
+
+    fn default() -> Self {
+        SparseTree::new()
+    }
+
+}
+
+impl<H: MerkleHasher> SparseTree<H> {
+
+    /// Constructs a new empty tree hashing with the given `hasher`
+    pub fn with_hasher(hasher: H) -> Self {
+        SparseTree {
+            hasher: hasher,
+            leaves: BTreeMap::new()
+        }
+    }
+
+    /// Returns the number of keys in the tree.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Returns whether no key was inserted yet.
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Sets the value of `key`, returning its previous value if any.
+    pub fn insert(&mut self, key: &[u8], value: TreeLeafData) -> Result<Option<TreeLeafData>, CommonError> {
+        let leaf = SparseLeaf {
+            hash: self.hasher.hash_leaf(&value)?,
+            value: value
+        };
+
+        Ok(self.leaves.insert(hash(key)?, leaf).map(|leaf| leaf.value))
+    }
+
+    /// Returns the value of `key`, or `None` if the key is absent.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&TreeLeafData>, CommonError> {
+        Ok(self.leaves.get(&hash(key)?).map(|leaf| &leaf.value))
+    }
+
+    /// Returns the root hash of the tree.
+    ///
+    /// This rehashes the whole path to every key, that is `SPARSE_TREE_DEPTH` nodes per key at most.
+    pub fn root_hash(&self) -> Result<Vec<u8>, CommonError> {
+        let defaults = default_hashes(&self.hasher)?;
+        let leaves: Vec<(&Vec<u8>, &SparseLeaf)> = self.leaves.iter().collect();
+        self.subtree_hash(&leaves, 0, &defaults)
+    }
+
+    /// Generates a proof of the value of `key` if present, and of its absence otherwise.
+    pub fn gen_proof(&self, key: &[u8]) -> Result<SparseProof, CommonError> {
+        let defaults = default_hashes(&self.hasher)?;
+        let path = hash(key)?;
+
+        let all: Vec<(&Vec<u8>, &SparseLeaf)> = self.leaves.iter().collect();
+        let mut leaves = all.as_slice();
+        let mut siblings = Vec::with_capacity(SPARSE_TREE_DEPTH);
+
+        for depth in 0..SPARSE_TREE_DEPTH {
+            let (left, right) = leaves.split_at(split_leaves(leaves, depth));
+
+            leaves = if bit_at(&path, depth) {
+                siblings.push(Positioned::Left(self.subtree_hash(left, depth + 1, &defaults)?));
+                right
+            } else {
+                siblings.push(Positioned::Right(self.subtree_hash(right, depth + 1, &defaults)?));
+                left
+            };
+        }
+
+        Ok(SparseProof {
+            key: key.to_vec(),
+            value: self.leaves.get(&path).map(|leaf| leaf.value.clone()),
+            siblings: siblings
+        })
+    }
+
+    /// Returns the hash of the subtree at `depth` holding `leaves`, sorted by path.
+    fn subtree_hash(&self, leaves: &[(&Vec<u8>, &SparseLeaf)], depth: usize, defaults: &[Vec<u8>]) -> Result<Vec<u8>, CommonError> {
+        match leaves.len() {
+            0 => Ok(defaults[depth].clone()),
+            1 if depth == SPARSE_TREE_DEPTH => Ok(leaves[0].1.hash.clone()),
+            _ => {
+                let (left, right) = leaves.split_at(split_leaves(leaves, depth));
+                self.hasher.hash_nodes(&self.subtree_hash(left, depth + 1, defaults)?,
+                                       &self.subtree_hash(right, depth + 1, defaults)?)
+            }
+        }
+    }
+
+}
+
+/// A proof of the value of a key in a `SparseTree`, or of its absence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseProof {
+    /// The key concerned by this proof
+    pub key: Vec<u8>,
+
+    /// The value of the key, `None` if this proves the key is absent
+    pub value: Option<TreeLeafData>,
+
+    /// The sibling hashes on the path to the key, from the root down
+    pub siblings: Vec<Positioned<Vec<u8>>>
+}
+
+impl SparseProof {
+
+    /// Checks whether this proof leads to the given trusted `root_hash`.
+    pub fn validate(&self, root_hash: &[u8]) -> Result<bool, CommonError> {
+        self.validate_with_hasher(&Hash {}, root_hash)
+    }
+
+    /// Same as `validate` but hashes with the given `hasher`.
+    pub fn validate_with_hasher<H: MerkleHasher>(&self, hasher: &H, root_hash: &[u8]) -> Result<bool, CommonError> {
+        let path = hash(&self.key)?;
+
+        if self.siblings.len() != SPARSE_TREE_DEPTH {
+            return Ok(false);
+        }
+
+        let mut running = match self.value {
+            Some(ref value) => hasher.hash_leaf(value)?,
+            None => hasher.hash_empty()?
+        };
+
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            running = match (sibling, bit_at(&path, depth)) {
+                (&Positioned::Left(ref hash), true) => hasher.hash_nodes(hash, &running)?,
+                (&Positioned::Right(ref hash), false) => hasher.hash_nodes(&running, hash)?,
+                _ => return Ok(false)
+            };
+        }
+
+        Ok(eq_ct(&running, root_hash))
+    }
+
+}
+
+/// Returns the hash of an empty subtree at each depth, from the root down to the leaves.
+fn default_hashes<H: MerkleHasher>(hasher: &H) -> Result<Vec<Vec<u8>>, CommonError> {
+    let mut defaults = vec![hasher.hash_empty()?];
+
+    for _ in 0..SPARSE_TREE_DEPTH {
+        let last = defaults[defaults.len() - 1].clone();
+        defaults.push(hasher.hash_nodes(&last, &last)?);
+    }
+
+    defaults.reverse();
+    Ok(defaults)
+}
+
+/// Returns whether the path goes right at `depth`.
+fn bit_at(path: &[u8], depth: usize) -> bool {
+    path[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+/// Returns the number of `leaves`, sorted by path, going left at `depth`.
+fn split_leaves(leaves: &[(&Vec<u8>, &SparseLeaf)], depth: usize) -> usize {
+    leaves.iter()
+        .position(|&(path, _)| bit_at(path, depth))
+        .unwrap_or(leaves.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| format!("did:sov:key{}", i).into_bytes()).collect()
+    }
+
+    fn tree(count: usize) -> SparseTree {
+        let mut tree = SparseTree::new();
+        for (i, key) in keys(count).iter().enumerate() {
+            tree.insert(key, format!("value{}", i).into_bytes()).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn insert_and_get_work() {
+        let mut tree = tree(10);
+        assert_eq!(tree.len(), 10);
+
+        for (i, key) in keys(10).iter().enumerate() {
+            assert_eq!(tree.get(key).unwrap(), Some(&format!("value{}", i).into_bytes()));
+        }
+        assert_eq!(tree.get(b"unknown").unwrap(), None);
+
+        assert_eq!(tree.insert(b"did:sov:key3", b"edited".to_vec()).unwrap(), Some(b"value3".to_vec()));
+        assert_eq!(tree.get(b"did:sov:key3").unwrap(), Some(&b"edited".to_vec()));
+        assert_eq!(tree.len(), 10);
+    }
+
+    #[test]
+    fn root_hash_works_for_empty() {
+        let defaults = default_hashes(&Hash {}).unwrap();
+        assert_eq!(defaults.len(), SPARSE_TREE_DEPTH + 1);
+        assert_eq!(SparseTree::new().root_hash().unwrap(), defaults[0]);
+    }
+
+    #[test]
+    fn root_hash_ignores_insertion_order() {
+        let mut reversed = SparseTree::new();
+        for (i, key) in keys(10).iter().enumerate().rev() {
+            reversed.insert(key, format!("value{}", i).into_bytes()).unwrap();
+        }
+
+        assert_eq!(reversed.root_hash().unwrap(), tree(10).root_hash().unwrap());
+    }
+
+    #[test]
+    fn root_hash_changes_with_values() {
+        let mut tree = tree(10);
+        let root = tree.root_hash().unwrap();
+
+        tree.insert(b"did:sov:key3", b"edited".to_vec()).unwrap();
+        assert_ne!(tree.root_hash().unwrap(), root);
+
+        tree.insert(b"did:sov:key3", b"value3".to_vec()).unwrap();
+        assert_eq!(tree.root_hash().unwrap(), root);
+    }
+
+    #[test]
+    fn gen_proof_works_for_present_keys() {
+        let tree = tree(10);
+        let root = tree.root_hash().unwrap();
+
+        for (i, key) in keys(10).iter().enumerate() {
+            let proof = tree.gen_proof(key).unwrap();
+            assert_eq!(proof.value, Some(format!("value{}", i).into_bytes()));
+            assert_eq!(proof.siblings.len(), SPARSE_TREE_DEPTH);
+            assert!(proof.validate(&root).unwrap());
+        }
+    }
+
+    #[test]
+    fn gen_proof_works_for_absent_keys() {
+        let tree = tree(10);
+        let root = tree.root_hash().unwrap();
+
+        for key in vec![b"unknown".to_vec(), b"did:sov:key10".to_vec()] {
+            let proof = tree.gen_proof(&key).unwrap();
+            assert_eq!(proof.value, None);
+            assert!(proof.validate(&root).unwrap());
+        }
+
+        let proof = SparseTree::new().gen_proof(b"unknown").unwrap();
+        assert!(proof.validate(&SparseTree::new().root_hash().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn validate_works_for_forged_proofs() {
+        let tree = tree(10);
+        let root = tree.root_hash().unwrap();
+
+        let mut proof = tree.gen_proof(b"did:sov:key3").unwrap();
+        proof.value = None;
+        assert!(!proof.validate(&root).unwrap());
+
+        let mut proof = tree.gen_proof(b"unknown").unwrap();
+        proof.value = Some(b"value3".to_vec());
+        assert!(!proof.validate(&root).unwrap());
+
+        let mut proof = tree.gen_proof(b"did:sov:key3").unwrap();
+        proof.key = b"did:sov:key4".to_vec();
+        assert!(!proof.validate(&root).unwrap());
+
+        let mut proof = tree.gen_proof(b"did:sov:key3").unwrap();
+        proof.siblings.pop();
+        assert!(!proof.validate(&root).unwrap());
+    }
+}
@@ -0,0 +1,214 @@
+use services::ledger::merkletree::proof::{Proof, ProofError, Lemma};
+use services::ledger::merkletree::tree::{Tree, TreeLeafData};
+use utils::crypto::hash::{Hash, MerkleHasher};
+use errors::common::CommonError;
+
+/// A source of leaf values kept out of memory, e.g. ledger transactions on disk.
+pub trait LeafStore {
+
+    /// Returns the number of leaves in the store.
+    fn len(&self) -> usize;
+
+    /// Returns whether the store has no leaves.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value of the leaf at position `index`.
+    fn load(&self, index: usize) -> Result<TreeLeafData, CommonError>;
+
+}
+
+/// A `Tree` over the leaves of a `LeafStore`, where only the hashes stay in memory.
+///
+/// The tree is a hash-only skeleton, as built by `Tree::from_leaves_with_hashes`:
+/// every leaf is loaded once to be hashed when the tree is built, and afterwards
+/// only the leaves that are asked for, or proved, are loaded again.
+#[allow(missing_debug_implementations)]
+pub struct StoredTree<S: LeafStore, H: MerkleHasher = Hash> {
+    hasher: H,
+    store: S,
+    tree: Tree
+}
+
+impl<S: LeafStore> StoredTree<S, Hash> {
+
+    /// Constructs the tree over all the leaves of `store`.
+    pub fn new(store: S) -> Result<Self, CommonError> {
+        StoredTree::with_hasher(store, Hash {})
+    }
+
+}
+
+impl<S: LeafStore, H: MerkleHasher> StoredTree<S, H> {
+
+    /// Same as `new` but hashing with the given `hasher`
+    pub fn with_hasher(store: S, hasher: H) -> Result<Self, CommonError> {
+        let mut hashes = Vec::with_capacity(store.len());
+        for index in 0..store.len() {
+            hashes.push(hasher.hash_leaf(&store.load(index)?)?);
+        }
+
+        Ok(StoredTree {
+            tree: Tree::from_leaves_with_hashes_with_hasher(&hasher, hashes)?,
+            hasher: hasher,
+            store: store
+        })
+    }
+
+    /// Returns the hash-only tree.
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Returns the root hash of the tree.
+    pub fn hash(&self) -> &Vec<u8> {
+        self.tree.hash()
+    }
+
+    /// Returns the number of leaves in the tree.
+    pub fn len(&self) -> usize {
+        self.tree.get_count()
+    }
+
+    /// Returns whether the tree has no leaves.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Loads the value of the leaf at position `index`.
+    /// Returns `None` if `index` is out of range.
+    pub fn get(&self, index: usize) -> Result<Option<TreeLeafData>, CommonError> {
+        if index >= self.len() {
+            return Ok(None);
+        }
+
+        self.store.load(index).map(Some)
+    }
+
+    /// Generates an inclusion proof for the leaf at position `index`,
+    /// loading the value of this leaf only.
+    ///
+    /// Fails if the loaded value doesn't hash to the leaf hash,
+    /// i.e. the store changed since the tree was built.
+    pub fn gen_proof(&self, index: usize) -> Result<Proof, ProofError> {
+        if self.is_empty() {
+            return Err(ProofError::EmptyTree);
+        }
+
+        let lemma = match Lemma::new_by_index(&self.tree, index) {
+            Some(lemma) => lemma,
+            None => return Err(ProofError::IndexOutOfRange(index, self.len()))
+        };
+
+        let value = self.store.load(index)?;
+        let proof = Proof::new(self.tree.hash().clone(), lemma, value);
+
+        if self.hasher.hash_leaf(&proof.value)? != *proof.leaf_hash() {
+            return Err(ProofError::CommonError(CommonError::InvalidState(
+                format!("Leaf {} of the store doesn't match the tree", index))));
+        }
+
+        Ok(proof)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    struct TestStore {
+        values: Vec<TreeLeafData>,
+        loaded: RefCell<Vec<usize>>
+    }
+
+    impl TestStore {
+        fn new(count: usize) -> TestStore {
+            TestStore {
+                values: (0..count).map(|i| format!("value{}", i).into_bytes()).collect(),
+                loaded: RefCell::new(Vec::new())
+            }
+        }
+    }
+
+    impl<'a> LeafStore for &'a TestStore {
+        fn len(&self) -> usize {
+            self.values.len()
+        }
+
+        fn load(&self, index: usize) -> Result<TreeLeafData, CommonError> {
+            self.loaded.borrow_mut().push(index);
+            Ok(self.values[index].clone())
+        }
+    }
+
+    #[test]
+    fn new_works_same_as_from_leaves() {
+        for count in 0..12 {
+            let store = TestStore::new(count);
+            let tree = StoredTree::new(&store).unwrap();
+
+            assert_eq!(tree.len(), count);
+            assert_eq!(tree.hash(), Tree::from_leaves(store.values.clone()).unwrap().hash());
+            assert!(tree.tree().iter().all(|value| value.is_empty()));
+        }
+    }
+
+    #[test]
+    fn get_works() {
+        let store = TestStore::new(5);
+        let tree = StoredTree::new(&store).unwrap();
+        store.loaded.borrow_mut().clear();
+
+        assert_eq!(tree.get(3).unwrap(), Some(b"value3".to_vec()));
+        assert_eq!(tree.get(5).unwrap(), None);
+        assert_eq!(*store.loaded.borrow(), vec![3]);
+    }
+
+    #[test]
+    fn gen_proof_loads_proved_leaf_only() {
+        let store = TestStore::new(11);
+        let tree = StoredTree::new(&store).unwrap();
+
+        for index in 0..11 {
+            store.loaded.borrow_mut().clear();
+
+            let proof = tree.gen_proof(index).unwrap();
+            assert_eq!(*store.loaded.borrow(), vec![index]);
+            assert_eq!(proof.value, store.values[index]);
+            assert!(proof.validate(tree.hash()).unwrap());
+        }
+    }
+
+    #[test]
+    fn gen_proof_works_for_errors() {
+        let store = TestStore::new(5);
+        let tree = StoredTree::new(&store).unwrap();
+
+        match tree.gen_proof(5) {
+            Err(ProofError::IndexOutOfRange(5, 5)) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+
+        let empty = TestStore::new(0);
+        match StoredTree::new(&empty).unwrap().gen_proof(0) {
+            Err(ProofError::EmptyTree) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn gen_proof_works_for_changed_store() {
+        let mut store = TestStore::new(5);
+        let hash = StoredTree::new(&store).unwrap().hash().clone();
+
+        store.values[2] = b"changed".to_vec();
+        let tree = StoredTree { hasher: Hash {}, store: &store, tree: Tree::from_leaves(TestStore::new(5).values).unwrap().to_hash_only() };
+
+        assert_eq!(tree.hash(), &hash);
+        assert!(tree.gen_proof(1).is_ok());
+        assert!(tree.gen_proof(2).is_err());
+    }
+}
@@ -1,31 +1,238 @@
+extern crate hex;
+extern crate rmp_serde;
+extern crate serde;
+extern crate serde_json;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
 use std::cmp;
-use utils::crypto::hash::{Digest, Hash};
+use std::fmt;
+use std::hash;
+use std::io::Read;
+use std::iter::{Enumerate, FromIterator};
+use std::mem;
+use std::ops::Range;
+use std::sync::Arc;
+use utils::crypto::base58;
+use utils::crypto::base64;
+use utils::crypto::hash::{Digest, Hash, MerkleHasher, eq_ct};
 use errors::common::CommonError;
 
+use self::hex::FromHex;
+
+#[cfg(feature = "rayon")]
+use self::rayon::prelude::*;
+
 pub use services::ledger::merkletree::proof::{
     Proof,
+    ProofError,
     Lemma,
-    Positioned
+    Positioned,
+    TreeLeafData,
+    split_point
 };
+pub use services::ledger::merkletree::multiproof::MultiProof;
+pub use services::ledger::merkletree::consistency::ConsistencyProof;
+pub use services::ledger::merkletree::absence::AbsenceProof;
+pub use services::ledger::merkletree::range::RangeProof;
+pub use services::ledger::merkletree::builder::MerkleTreeBuilder;
+pub use services::ledger::merkletree::store::{LeafStore, StoredTree};
+pub use services::ledger::merkletree::index::ProofIndex;
+pub use services::ledger::merkletree::visitor::Visitor;
+pub use services::ledger::merkletree::leaf::{LeafPolicy, LeafError};
+pub use services::ledger::merkletree::fixed::{FixedTree, FixedHash};
+
+/// Hash of the root of a `Tree`.
+///
+/// Wraps the raw bytes so that a root can't be mixed up with
+/// a leaf or an inner node hash by accident.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RootHash(Vec<u8>);
+
+impl RootHash {
+    /// Returns the raw bytes of the hash
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the lowercase hex representation of the hash
+    pub fn to_hex(&self) -> String {
+        let mut ret = String::with_capacity(self.0.len() * 2);
+        for i in self.0.iter() {
+            ret.push_str(&format!("{:02x}", i));
+        }
+        ret
+    }
+}
+
+impl AsRef<[u8]> for RootHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for RootHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
 
-pub type TreeLeafData = Vec<u8>;
+/// Shape of a `Tree`, as computed by `Tree::stats` in a single walk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeStats {
+    /// The number of `Leaf` nodes
+    pub leaves: usize,
+
+    /// The number of interior `Node`s
+    pub nodes: usize,
+
+    /// The number of `Empty` nodes
+    pub empties: usize,
+
+    /// The number of edges from the root to the deepest leaf
+    pub height: usize,
+
+    /// Whether the tree has the shape built by `from_leaves`, see `assert_balanced`
+    pub balanced: bool
+}
+
+impl TreeStats {
+
+    /// Returns the number of nodes of every kind.
+    pub fn total(&self) -> usize {
+        self.leaves + self.nodes + self.empties
+    }
+
+}
 
 /// Binary Tree where leaves hold a stand-alone value.
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+///
+/// Byte fields are serialized as byte strings, so binary formats
+/// like MessagePack keep them compact while JSON still gets arrays of numbers.
+///
+/// `Node`s cache the leaves count and the height of their subtree, so `get_count`
+/// and `get_height` are O(1) instead of walking the whole tree. These caches are not
+/// serialized: deserialization recomputes them from the children, so they can't be
+/// forged by the input and the serialized shape stays the same as without them.
+///
+/// Children are shared through `Arc`, so `clone()` is O(1) and successive
+/// versions of an append-only tree share all the subtrees they have in common.
+/// The price is paid on mutation: `push` copies every node along the rightmost
+/// path that is still shared with another version, and consuming iteration
+/// clones the subtrees it can't take ownership of.
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Tree {
     Empty {
+        #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+        hash: Vec<u8>
+    },
+
+    Leaf {
+        #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+        hash: Vec<u8>,
+        #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+        value: TreeLeafData
+    },
+
+    Node {
+        #[serde(serialize_with = "serialize_bytes", deserialize_with = "deserialize_bytes")]
+        hash: Vec<u8>,
+        left: Arc<Tree>,
+        right: Arc<Tree>,
+        #[serde(skip)]
+        count: usize,
+        #[serde(skip)]
+        height: usize
+    }
+}
+
+/// Serialized shape of `Tree`, without the cached sizes of the nodes.
+#[derive(Deserialize)]
+enum TreeRepr {
+    Empty {
+        #[serde(deserialize_with = "deserialize_bytes")]
         hash: Vec<u8>
     },
 
     Leaf {
+        #[serde(deserialize_with = "deserialize_bytes")]
         hash: Vec<u8>,
+        #[serde(deserialize_with = "deserialize_bytes")]
         value: TreeLeafData
     },
 
     Node {
+        #[serde(deserialize_with = "deserialize_bytes")]
         hash: Vec<u8>,
-        left: Box<Tree>,
-        right: Box<Tree>
+        left: Box<TreeRepr>,
+        right: Box<TreeRepr>
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Tree {
+    fn deserialize<D>(deserializer: D) -> Result<Tree, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        <TreeRepr as serde::Deserialize>::deserialize(deserializer).map(Tree::from_repr)
+    }
+}
+
+fn serialize_bytes<S>(bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer
+{
+    serializer.serialize_bytes(bytes)
+}
+
+fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    deserializer.deserialize_byte_buf(BytesVisitor)
+}
+
+/// Accepts byte strings as well as sequences of numbers,
+/// so trees serialized before as plain arrays still parse.
+struct BytesVisitor;
+
+impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte array")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Vec<u8>, E> where E: serde::de::Error {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Vec<u8>, E> where E: serde::de::Error {
+        Ok(v)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Vec<u8>, A::Error> where A: serde::de::SeqAccess<'de> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Mirror of `Tree` with every byte field as a base64 string, for `to_compact_json`.
+#[derive(Serialize, Deserialize)]
+enum CompactTree {
+    Empty {
+        hash: String
+    },
+
+    Leaf {
+        hash: String,
+        value: String
+    },
+
+    Node {
+        hash: String,
+        left: Box<CompactTree>,
+        right: Box<CompactTree>
     }
 }
 
@@ -37,6 +244,13 @@ impl Tree {
         }
     }
 
+    /// Create an empty tree holding the empty hash of the given `hasher`
+    pub fn empty_with_hasher<H: MerkleHasher>(hasher: &H) -> Result<Tree, CommonError> {
+        Ok(Tree::Empty {
+            hash: hasher.hash_empty()?
+        })
+    }
+
     /// Create a new tree
     pub fn new(hash: Digest, value: TreeLeafData) -> Self {
         Tree::Leaf {
@@ -52,168 +266,4104 @@ impl Tree {
         Ok(Tree::new(hash, value))
     }
 
-    /// Returns a hash from the tree.
-    pub fn hash(&self) -> &Vec<u8> {
-        match *self {
-            Tree::Empty { ref hash }    => hash,
-            Tree::Leaf { ref hash, .. } => hash,
-            Tree::Node { ref hash, .. } => hash
+    /// Create a new leaf over a large value streamed from `reader`, e.g. a document on disk.
+    ///
+    /// The value is hashed chunk by chunk with `Hash::hash_leaf_reader` and is not kept:
+    /// the leaf holds `handle` instead, e.g. the path or id of the document, or an empty
+    /// value as the leaves of `from_leaves_with_hashes` do. The leaf hash is the one of the
+    /// streamed value, so proofs of such leaves come from `gen_proof_by_index` and hold
+    /// `handle` as their value, and `verify_root` fails on a tree holding them.
+    pub fn new_leaf_streaming<R: Read>(mut reader: R, handle: TreeLeafData) -> Result<Tree, CommonError> {
+        let hash = Hash::hash_leaf_reader(&mut reader)?;
+        Ok(Tree::new(hash, handle))
+    }
+
+    /// Create a new leaf hashed with the given `hasher`
+    pub fn new_leaf_with_hasher<H: MerkleHasher>(hasher: &H, value: TreeLeafData) -> Result<Tree, CommonError> {
+        Ok(Tree::Leaf {
+            hash: hasher.hash_leaf(&value)?,
+            value: value
+        })
+    }
+
+    /// Same as `new_leaf`, but rejects the values that don't follow `policy`.
+    pub fn try_new_leaf(policy: &LeafPolicy, value: TreeLeafData) -> Result<Tree, LeafError> {
+        Tree::try_new_leaf_with_hasher(&Hash {}, policy, value)
+    }
+
+    /// Same as `try_new_leaf` but hashes with the given `hasher`.
+    pub fn try_new_leaf_with_hasher<H: MerkleHasher>(hasher: &H, policy: &LeafPolicy, value: TreeLeafData) -> Result<Tree, LeafError> {
+        policy.check(&value)?;
+        Ok(Tree::new_leaf_with_hasher(hasher, value)?)
+    }
+
+    /// Create a new node over the given children
+    pub fn new_node(left: Tree, right: Tree) -> Result<Tree, CommonError> {
+        let hash = Hash::hash_nodes(left.hash(), right.hash())?;
+        Ok(Tree::with_children(hash.to_vec(), left, right))
+    }
+
+    /// Create a new node over the given children hashed with the given `hasher`
+    pub fn new_node_with_hasher<H: MerkleHasher>(hasher: &H, left: Tree, right: Tree) -> Result<Tree, CommonError> {
+        let hash = hasher.hash_nodes(left.hash(), right.hash())?;
+        Ok(Tree::with_children(hash, left, right))
+    }
+
+    /// Create a node with the given `hash` over the given children, computing
+    /// its cached sizes from theirs.
+    fn with_children(hash: Vec<u8>, left: Tree, right: Tree) -> Tree {
+        Tree::Node {
+            hash: hash,
+            count: left.get_count() + right.get_count(),
+            height: 1 + cmp::max(left.get_height(), right.get_height()),
+            left: Arc::new(left),
+            right: Arc::new(right)
         }
     }
 
-    /// Returns a borrowing iterator over the leaves of the tree.
-    pub fn iter(&self) -> LeavesIterator {
-        LeavesIterator::new(self)
+    /// Rebuilds a deserialized tree bottom-up, recomputing the cached sizes.
+    fn from_repr(repr: TreeRepr) -> Tree {
+        match repr {
+            TreeRepr::Empty { hash } => Tree::Empty { hash: hash },
+            TreeRepr::Leaf { hash, value } => Tree::Leaf { hash: hash, value: value },
+            TreeRepr::Node { hash, left, right } =>
+                Tree::with_children(hash, Tree::from_repr(*left), Tree::from_repr(*right))
+        }
     }
 
-    pub fn get_height(&self) -> usize {
-        match *self {
-            Tree::Empty { .. } => { 0 },
-            Tree::Node { ref left, ref right, .. } => {
-                1 + cmp::max(left.get_height(),right.get_height())
-            },
-            Tree::Leaf { .. } => { 0 }
+    /// Combines two existing trees under a new root without rehashing their inner nodes.
+    ///
+    /// If either side is `Empty` the other one is returned unchanged.
+    /// Fails if the root hashes differ in length, i.e. come from different hashers;
+    /// `MerkleTree::merge` also tells apart hashers of the same length.
+    /// The result has the same shape as `from_leaves` over all the values only if
+    /// `left` is a perfect tree holding at least as many leaves as `right`;
+    /// `push` and `assert_balanced` expect that shape.
+    pub fn merge(left: Tree, right: Tree) -> Result<Tree, CommonError> {
+        Tree::merge_with_hasher(&Hash {}, left, right)
+    }
+
+    /// Same as `merge` but hashes the new root with the given `hasher`.
+    pub fn merge_with_hasher<H: MerkleHasher>(hasher: &H, left: Tree, right: Tree) -> Result<Tree, CommonError> {
+        match (left, right) {
+            (Tree::Empty { .. }, tree) | (tree, Tree::Empty { .. }) => Ok(tree),
+            (left, right) => {
+                if left.hash().len() != right.hash().len() {
+                    return Err(CommonError::InvalidStructure("Trees were built with different hashers".to_string()));
+                }
+                Tree::new_node_with_hasher(hasher, left, right)
+            }
         }
     }
 
-    pub fn get_count(&self) -> usize {
-        match *self {
-            Tree::Empty { .. } => { 0 },
-            Tree::Node { ref left, ref right, .. } => {
-                left.get_count() + right.get_count()
-            },
-            Tree::Leaf { .. } => { 1 }
+    /// Builds a balanced tree from the given leaf values.
+    ///
+    /// Adjacent nodes are combined pairwise level by level; an odd node
+    /// at the end of a level is promoted to the next level unchanged.
+    /// It is never paired with a copy of itself, so unlike trees padding odd levels
+    /// by duplication (CVE-2012-2459), the last leaf can't be duplicated to get the same root.
+    /// Returns an `Empty` tree holding the empty hash if `values` is empty.
+    pub fn from_leaves(values: Vec<TreeLeafData>) -> Result<Tree, CommonError> {
+        Tree::from_leaves_with_hasher(&Hash {}, values)
+    }
+
+    /// Same as `from_leaves` but hashes with the given `hasher`.
+    pub fn from_leaves_with_hasher<H: MerkleHasher>(hasher: &H, values: Vec<TreeLeafData>) -> Result<Tree, CommonError> {
+        if values.is_empty() {
+            return Ok(Tree::Empty { hash: hasher.hash_empty()? });
         }
+
+        let mut leaves = Vec::with_capacity(values.len());
+
+        for v in values {
+            leaves.push(Tree::new_leaf_with_hasher(hasher, v)?);
+        }
+
+        Tree::from_level_with_hasher(hasher, leaves)
     }
-}
 
+    /// Same as `from_leaves` for values coming from an untrusted source,
+    /// failing before any hashing if there are more than `max_leaves` of them.
+    ///
+    /// At most `max_leaves + 1` values are taken from `values`, so an oversized
+    /// or endless source, e.g. a decoding iterator, is never collected in full.
+    pub fn from_leaves_bounded<I>(values: I, max_leaves: usize) -> Result<Tree, CommonError>
+        where I: IntoIterator<Item = TreeLeafData> {
+        Tree::from_leaves_bounded_with_hasher(&Hash {}, values, max_leaves)
+    }
 
-/// An borrowing iterator over the leaves of a `Tree`.
-/// Adapted from http://codereview.stackexchange.com/q/110283.
-#[allow(missing_debug_implementations)]
-pub struct LeavesIterator<'a> {
-    current_value: Option<&'a TreeLeafData>,
-    right_nodes: Vec<&'a Tree>
-}
+    /// Same as `from_leaves_bounded` but hashes with the given `hasher`.
+    pub fn from_leaves_bounded_with_hasher<H, I>(hasher: &H, values: I, max_leaves: usize) -> Result<Tree, CommonError>
+        where H: MerkleHasher, I: IntoIterator<Item = TreeLeafData> {
+        let values = values.into_iter()
+            .take(max_leaves.saturating_add(1))
+            .collect::<Vec<TreeLeafData>>();
 
-impl <'a> LeavesIterator<'a> {
+        if values.len() > max_leaves {
+            return Err(CommonError::InvalidStructure(
+                format!("Tree can't hold more than {} leaves", max_leaves)));
+        }
 
-    fn new(root: &'a Tree) -> Self {
-        let mut iter = LeavesIterator {
-            current_value: None,
-            right_nodes: Vec::new()
-        };
+        Tree::from_leaves_with_hasher(hasher, values)
+    }
 
-        iter.add_left(root);
+    /// Same as `from_leaves` for values sorted in ascending order, as `gen_absence_proof`
+    /// and `gen_range_proof` need. Fails if the values aren't sorted.
+    pub fn height_balanced_from_sorted(values: Vec<TreeLeafData>) -> Result<Tree, CommonError> {
+        if let Some(position) = values.windows(2).position(|pair| pair[0] > pair[1]) {
+            return Err(CommonError::InvalidStructure(format!("Leaf {} is lower than the one before it", position + 1)));
+        }
 
-        iter
+        Tree::from_leaves(values)
     }
 
-    fn add_left(&mut self, mut tree: &'a Tree) {
-        loop {
-            match *tree {
-                Tree::Empty { .. } => {
-                    self.current_value = None;
-                    break;
-                },
+    /// Builds a balanced tree from already hashed leaves, only hashing the nodes above them.
+    ///
+    /// `hashes` are taken as the leaf hashes as is, whereas `from_leaves` hashes the values
+    /// it is given: don't pass leaf values here, nor leaf hashes to `from_leaves`.
+    /// The root matches the one of a tree built by `from_leaves` over the values with these
+    /// leaf hashes, but leaves hold empty values, like the skeleton of `to_hash_only`.
+    pub fn from_leaves_with_hashes(hashes: Vec<Vec<u8>>) -> Result<Tree, CommonError> {
+        Tree::from_leaves_with_hashes_with_hasher(&Hash {}, hashes)
+    }
 
-                Tree::Node { ref left, ref right, .. } => {
-                    self.right_nodes.push(right);
-                    tree = left;
-                },
+    /// Same as `from_leaves_with_hashes` but hashes nodes with the given `hasher`.
+    pub fn from_leaves_with_hashes_with_hasher<H: MerkleHasher>(hasher: &H, hashes: Vec<Vec<u8>>) -> Result<Tree, CommonError> {
+        if hashes.is_empty() {
+            return Ok(Tree::Empty { hash: hasher.hash_empty()? });
+        }
 
-                Tree::Leaf { ref value, .. } => {
-                    self.current_value = Some(value);
-                    break;
-                }
-            }
+        let leaves = hashes.into_iter()
+            .map(|hash| Tree::Leaf { hash: hash, value: Vec::new() })
+            .collect();
+
+        Tree::from_level_with_hasher(hasher, leaves)
+    }
+
+    /// Builds a hash-only tree, as `from_leaves_with_hashes` does, from the leaf hashes
+    /// concatenated without any framing in `bytes`, e.g. as some peers send them.
+    /// Fails if the length of `bytes` is not a multiple of the digest length.
+    pub fn try_from_leaf_hashes(bytes: &[u8]) -> Result<Tree, CommonError> {
+        Tree::try_from_leaf_hashes_with_hasher(&Hash {}, bytes)
+    }
+
+    /// Same as `try_from_leaf_hashes` for the digest length and node hashes of the given `hasher`.
+    pub fn try_from_leaf_hashes_with_hasher<H: MerkleHasher>(hasher: &H, bytes: &[u8]) -> Result<Tree, CommonError> {
+        let digest_len = hasher.digest_len();
+
+        if digest_len == 0 || bytes.len() % digest_len != 0 {
+            return Err(CommonError::InvalidStructure(
+                format!("Leaf hashes length {} is not a multiple of {}", bytes.len(), digest_len)));
+        }
+
+        let hashes = bytes.chunks(digest_len)
+            .map(|hash| hash.to_vec())
+            .collect();
+
+        Tree::from_leaves_with_hashes_with_hasher(hasher, hashes)
+    }
+
+    /// Builds the tree aggregating already final subtree `roots`, e.g. the roots of
+    /// the shards of a ledger, whose root commits to all of them at once.
+    ///
+    /// The roots are combined as node hashes, never hashed again as leaf values, so this
+    /// gives the same tree as `from_leaves_with_hashes`; it only fails on a root that is not
+    /// a digest. `gen_root_proof` proves that a root is under the aggregate, and
+    /// `proof::verify_root_inclusion` checks it.
+    pub fn concat_roots(roots: &[Vec<u8>]) -> Result<Tree, CommonError> {
+        Tree::concat_roots_with_hasher(&Hash {}, roots)
+    }
+
+    /// Same as `concat_roots` for the digest length and node hashes of the given `hasher`.
+    pub fn concat_roots_with_hasher<H: MerkleHasher>(hasher: &H, roots: &[Vec<u8>]) -> Result<Tree, CommonError> {
+        if let Some(position) = roots.iter().position(|root| root.len() != hasher.digest_len()) {
+            return Err(CommonError::InvalidStructure(
+                format!("Root {} is {} bytes long instead of {}", position, roots[position].len(), hasher.digest_len())));
         }
+
+        Tree::from_leaves_with_hashes_with_hasher(hasher, roots.to_vec())
     }
 
-}
+    /// Combines the nodes of a level pairwise up to a single root.
+    ///
+    /// Each level is written over the first half of the previous one,
+    /// so no other level than the leaves one is ever allocated.
+    fn from_level_with_hasher<H: MerkleHasher>(hasher: &H, mut cur: Vec<Tree>) -> Result<Tree, CommonError> {
+        let take = |tree: &mut Tree| mem::replace(tree, Tree::Empty { hash: Vec::new() });
 
-impl <'a> Iterator for LeavesIterator<'a> {
+        while cur.len() > 1 {
+            let len = cur.len();
 
-    type Item = &'a TreeLeafData;
+            // the nodes at 2i and 2i + 1 are always read before the slot i is written
+            for i in 0..len / 2 {
+                let left = take(&mut cur[2 * i]);
+                let right = take(&mut cur[2 * i + 1]);
+                cur[i] = Tree::new_node_with_hasher(hasher, left, right)?;
+            }
 
-    fn next(&mut self) -> Option<&'a TreeLeafData> {
-        let result = self.current_value.take();
+            if len % 2 == 1 {
+                cur[len / 2] = take(&mut cur[len - 1]);
+            }
 
-        if let Some(rest) = self.right_nodes.pop() {
-            self.add_left(rest);
+            cur.truncate((len + 1) / 2);
         }
 
-        result
+        Ok(cur.remove(0))
     }
 
-}
+    /// Same as `from_leaves` for leaves given as hex strings,
+    /// that are decoded to bytes before being hashed.
+    pub fn from_hex_leaves<S: AsRef<str>>(leaves: &[S]) -> Result<Tree, CommonError> {
+        let mut values = Vec::with_capacity(leaves.len());
 
-/// An iterator over the leaves of a `Tree`.
-#[allow(missing_debug_implementations)]
-pub struct LeavesIntoIterator {
-    current_value: Option<TreeLeafData>,
-    right_nodes: Vec<Tree>
-}
+        for leaf in leaves {
+            let value = Vec::from_hex(leaf.as_ref())
+                .map_err(|err| CommonError::InvalidStructure(format!("Can't decode hex leaf: {:?}", err)))?;
+            values.push(value);
+        }
 
-impl LeavesIntoIterator {
+        Tree::from_leaves(values)
+    }
 
-    fn new(root: Tree) -> Self {
-        let mut iter = LeavesIntoIterator {
-            current_value: None,
-            right_nodes: Vec::new()
-        };
+    /// Same as `from_leaves` for leaves given as base58 strings,
+    /// the way indy-node encodes binary values.
+    pub fn from_base58_leaves<S: AsRef<str>>(leaves: &[S]) -> Result<Tree, CommonError> {
+        let mut values = Vec::with_capacity(leaves.len());
 
-        iter.add_left(root);
+        for leaf in leaves {
+            values.push(base58::decode(leaf.as_ref())?);
+        }
 
-        iter
+        Tree::from_leaves(values)
     }
 
-    fn add_left(&mut self, mut tree: Tree) {
-        loop {
-            match tree {
-                Tree::Empty { .. } => {
-                    self.current_value = None;
-                    break;
-                },
+    /// Same as `from_leaves` but hashes leaves and the nodes of each level in parallel.
+    ///
+    /// Builds exactly the same tree as `from_leaves`, it only pays off for big batches.
+    #[cfg(feature = "rayon")]
+    pub fn par_from_leaves(values: Vec<TreeLeafData>) -> Result<Tree, CommonError> {
+        if values.is_empty() {
+            return Ok(Tree::Empty { hash: Hash::empty_hash() });
+        }
 
-                Tree::Node { left, right, .. } => {
-                    self.right_nodes.push(*right);
-                    tree = *left;
-                },
+        let mut cur = values.into_par_iter()
+            .map(Tree::new_leaf)
+            .collect::<Result<Vec<Tree>, CommonError>>()?;
 
-                Tree::Leaf { value, .. } => {
-                    self.current_value = Some(value);
-                    break;
-                }
+        while cur.len() > 1 {
+            let mut pairs = Vec::with_capacity((cur.len() + 1) / 2);
+            let mut nodes = cur.into_iter();
+
+            while let Some(left) = nodes.next() {
+                pairs.push((left, nodes.next()));
             }
+
+            cur = pairs.into_par_iter()
+                .map(|(left, right)| match right {
+                    Some(right) => Tree::new_node(left, right),
+                    None => Ok(left)
+                })
+                .collect::<Result<Vec<Tree>, CommonError>>()?;
         }
+
+        Ok(cur.remove(0))
     }
 
-}
+    /// Appends a new leaf to a tree built by `from_leaves`.
+    ///
+    /// Only the nodes along the rightmost path are rehashed, so an append costs
+    /// O(log n) hashes and the resulting tree is the same as the one `from_leaves`
+    /// builds over all the values.
+    pub fn push(&mut self, value: TreeLeafData) -> Result<(), CommonError> {
+        self.push_with_hasher(&Hash {}, value)
+    }
 
-impl Iterator for LeavesIntoIterator {
+    /// Same as `push` for a tree built with the given `hasher`.
+    ///
+    /// The tree doesn't record which hasher built it, so it must be the same one.
+    /// Fails if the new leaf hash isn't as long as the root hash, which catches
+    /// mixing digests of different sizes. `MerkleTree` records the algorithm and
+    /// checks it in `append_with_hasher`.
+    pub fn push_with_hasher<H: MerkleHasher>(&mut self, hasher: &H, value: TreeLeafData) -> Result<(), CommonError> {
+        let leaf = Tree::new_leaf_with_hasher(hasher, value)?;
+        let count = self.get_count();
 
-    type Item = TreeLeafData;
+        if count != 0 && leaf.hash().len() != self.hash().len() {
+            return Err(CommonError::InvalidStructure("Leaf hash doesn't match the tree hasher".to_string()));
+        }
 
-    fn next(&mut self) -> Option<TreeLeafData> {
-        let result = self.current_value.take();
+        self.push_counted(hasher, count, leaf)
+    }
 
-        if let Some(rest) = self.right_nodes.pop() {
-            self.add_left(rest);
+    /// Appends the values to the tree, same as calling `push` for each of them.
+    ///
+    /// New leaves are grouped into perfect subtrees that are built at once and
+    /// hooked below the rightmost path, so it is rehashed once per group instead
+    /// of once per value.
+    pub fn extend<I: IntoIterator<Item = TreeLeafData>>(&mut self, values: I) -> Result<(), CommonError> {
+        let values = values.into_iter().collect::<Vec<TreeLeafData>>();
+        let mut remaining = values.len();
+        let mut values = values.into_iter();
+
+        while remaining > 0 {
+            let count = self.get_count();
+
+            if count == 0 {
+                *self = Tree::from_leaves(values.collect())?;
+                return Ok(());
+            }
+
+            // the rightmost perfect subtree holds the lowest set bit of count leaves,
+            // the same number of leaves can be appended as a sibling of it
+            let mut size = count & count.wrapping_neg();
+            while size > remaining {
+                size /= 2;
+            }
+
+            let subtree = Tree::from_leaves(values.by_ref().take(size).collect())?;
+            self.push_counted(&Hash {}, count, subtree)?;
+            remaining -= size;
         }
 
-        result
+        Ok(())
     }
 
-}
+    /// Appends all the leaves of `other`, a tree built by `from_leaves`, so that the result
+    /// is the tree `from_leaves` builds over the values of this tree followed by the ones
+    /// of `other`, e.g. to add a range of leaves built by another worker.
+    ///
+    /// Unlike `merge` this keeps the root history of an append-only tree. The perfect
+    /// subtrees of `other` that fit below the rightmost path are appended as they are,
+    /// the others are split into their children first, so only nodes above or along
+    /// the rightmost path are hashed. Fails if the root hashes differ in length.
+    pub fn append_subtree(&mut self, other: Tree) -> Result<(), CommonError> {
+        self.append_subtree_with_hasher(&Hash {}, other)
+    }
 
-impl IntoIterator for Tree {
+    /// Same as `append_subtree` for trees built with the given `hasher`.
+    pub fn append_subtree_with_hasher<H: MerkleHasher>(&mut self, hasher: &H, other: Tree) -> Result<(), CommonError> {
+        if !self.is_empty() && !other.is_empty() && self.hash().len() != other.hash().len() {
+            return Err(CommonError::InvalidStructure("Subtree hash doesn't match the tree hasher".to_string()));
+        }
 
-    type Item     = TreeLeafData;
-    type IntoIter = LeavesIntoIterator;
+        self.append_balanced(hasher, other)
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        LeavesIntoIterator::new(self)
+    fn append_balanced<H: MerkleHasher>(&mut self, hasher: &H, subtree: Tree) -> Result<(), CommonError> {
+        let count = self.get_count();
+        let size = subtree.get_count();
+
+        if size == 0 {
+            return Ok(());
+        }
+
+        if count == 0 {
+            *self = subtree;
+            return Ok(());
+        }
+
+        // as in `extend`, a perfect subtree fits if it is no bigger than the rightmost one
+        if size.is_power_of_two() && size <= count & count.wrapping_neg() {
+            return self.push_counted(hasher, count, subtree);
+        }
+
+        match subtree {
+            Tree::Node { left, right, .. } => {
+                self.append_balanced(hasher, Arc::try_unwrap(left).unwrap_or_else(|shared| (*shared).clone()))?;
+                self.append_balanced(hasher, Arc::try_unwrap(right).unwrap_or_else(|shared| (*shared).clone()))
+            }
+            _ => Err(CommonError::InvalidStructure("Tree is not balanced".to_string()))
+        }
     }
 
+    /// Returns the tree as it was when it held its first `new_len` leaves,
+    /// e.g. to get the root the ledger had at an earlier size.
+    ///
+    /// For a tree built by `from_leaves` or `push`, this is the same tree as the one
+    /// `from_leaves` builds over the first `new_len` values, so its root is the historical one.
+    /// Only the nodes along the path to the new last leaf are rehashed, the subtrees
+    /// at their left are shared with this tree. Fails if `new_len` is bigger than the leaves count.
+    pub fn truncate(&self, new_len: usize) -> Result<Tree, CommonError> {
+        self.truncate_with_hasher(&Hash {}, new_len)
+    }
+
+    /// Same as `truncate` for a tree built with the given `hasher`.
+    pub fn truncate_with_hasher<H: MerkleHasher>(&self, hasher: &H, new_len: usize) -> Result<Tree, CommonError> {
+        if new_len > self.get_count() {
+            return Err(CommonError::InvalidStructure(
+                format!("Can't truncate a tree of size {} to size {}", self.get_count(), new_len)));
+        }
+
+        if new_len == 0 {
+            return Tree::empty_with_hasher(hasher);
+        }
+
+        if new_len == self.get_count() {
+            return Ok(self.clone());
+        }
+
+        match *self {
+            Tree::Node { ref left, ref right, count, .. } => {
+                let left_count = left_count(count, left, right);
+
+                if new_len <= left_count {
+                    left.truncate_with_hasher(hasher, new_len)
+                } else {
+                    let right = right.truncate_with_hasher(hasher, new_len - left_count)?;
+                    Tree::new_node_with_hasher(hasher, (**left).clone(), right)
+                }
+            }
+            _ => Err(CommonError::InvalidStructure("Tree is not balanced".to_string()))
+        }
+    }
+
+    /// Splits the tree into one over the leaves `[0, index)` and one over the leaves
+    /// `[index, n)`, each being the tree `from_leaves` builds over its values.
+    ///
+    /// The left tree has the root this tree had at size `index`, as with `truncate`.
+    /// The right tree has a shape of its own, so merging both trees back together
+    /// with `merge` doesn't give the original root unless `index` is the size of the
+    /// left subtree. Leaf hashes are reused, not recomputed. Fails if `index` is bigger
+    /// than the leaves count.
+    pub fn split_at(self, index: usize) -> Result<(Tree, Tree), CommonError> {
+        self.split_at_with_hasher(&Hash {}, index)
+    }
+
+    /// Same as `split_at` for a tree built with the given `hasher`.
+    pub fn split_at_with_hasher<H: MerkleHasher>(self, hasher: &H, index: usize) -> Result<(Tree, Tree), CommonError> {
+        let left = self.truncate_with_hasher(hasher, index)?;
+
+        let leaves = self.nodes()
+            .filter(|node| match **node {
+                Tree::Leaf { .. } => true,
+                _ => false
+            })
+            .skip(index)
+            .cloned()
+            .collect::<Vec<Tree>>();
+
+        let right = if leaves.is_empty() {
+            Tree::empty_with_hasher(hasher)?
+        } else {
+            Tree::from_level_with_hasher(hasher, leaves)?
+        };
+
+        Ok((left, right))
+    }
+
+    /// Appends a perfect `subtree` to a balanced tree of `count` leaves.
+    /// The subtree can't hold more leaves than the rightmost perfect subtree of the tree.
+    fn push_counted<H: MerkleHasher>(&mut self, hasher: &H, count: usize, subtree: Tree) -> Result<(), CommonError> {
+        if count == 0 {
+            *self = subtree;
+            return Ok(());
+        }
+
+        if count.is_power_of_two() {
+            // perfect tree, the new leaves start a new right subtree
+            let left = mem::replace(self, Tree::Empty { hash: Vec::new() });
+            *self = Tree::new_node_with_hasher(hasher, left, subtree)?;
+            return Ok(());
+        }
+
+        match *self {
+            Tree::Node { ref mut hash, ref left, ref mut right, count: ref mut node_count, ref mut height } => {
+                *node_count += subtree.get_count();
+                Arc::make_mut(right).push_counted(hasher, count - split_point(count), subtree)?;
+                *hash = hasher.hash_nodes(left.hash(), right.hash())?;
+                *height = 1 + cmp::max(left.get_height(), right.get_height());
+                Ok(())
+            }
+            _ => Err(CommonError::InvalidStructure("Tree is not balanced".to_string()))
+        }
+    }
+
+    /// Checks that every stored hash matches the one recomputed from the leaf values,
+    /// and that the cached node sizes match the actual subtrees.
+    ///
+    /// Meant for trees coming from an untrusted source, e.g. deserialized ones,
+    /// where the root hash could have been set without matching the leaves.
+    /// An `Empty` below the root is a stub left by `prune_to_proof`: its hash is trusted
+    /// to be the one of the subtree it replaces, and the sizes of its parent aren't checked.
+    pub fn verify_root(&self) -> Result<bool, CommonError> {
+        self.verify_root_with_hasher(&Hash {})
+    }
+
+    /// Same as `verify_root` but hashes with the given `hasher`.
+    pub fn verify_root_with_hasher<H: MerkleHasher>(&self, hasher: &H) -> Result<bool, CommonError> {
+        match *self {
+            Tree::Empty { ref hash } => Ok(*hash == hasher.hash_empty()?),
+            _ => self.verify_subtree(hasher)
+        }
+    }
+
+    fn verify_subtree<H: MerkleHasher>(&self, hasher: &H) -> Result<bool, CommonError> {
+        match *self {
+            Tree::Empty { .. } => Ok(true),
+            Tree::Leaf { ref hash, ref value } => Ok(*hash == hasher.hash_leaf(value)?),
+            Tree::Node { ref hash, ref left, ref right, count, height } => {
+                let sized = left.is_empty() || right.is_empty()
+                    || (count == left.get_count() + right.get_count()
+                        && height == 1 + cmp::max(left.get_height(), right.get_height()));
+
+                Ok(sized
+                    && left.verify_subtree(hasher)?
+                    && right.verify_subtree(hasher)?
+                    && *hash == hasher.hash_nodes(left.hash(), right.hash())?)
+            }
+        }
+    }
+
+    /// Returns the tree reduced to the leaf at position `index` and the nodes on its path,
+    /// every sibling subtree being replaced by an `Empty` stub holding its hash, or `None`
+    /// if `index` is out of range.
+    ///
+    /// The pruned tree has the same root hash and yields the same proof with `gen_proof`,
+    /// so it can be shipped instead of a `Proof`, and is checked with `verify_root`. Its
+    /// nodes keep the counts and heights of the full tree, so `get_count` and indexing
+    /// work as before, while the leaf iterators only yield the kept leaf.
+    pub fn prune_to_proof(&self, index: usize) -> Option<Tree> {
+        match *self {
+            Tree::Empty { .. } => None,
+
+            Tree::Leaf { .. } => if index == 0 { Some(self.clone()) } else { None },
+
+            Tree::Node { ref hash, ref left, ref right, count, height } => {
+                let stub = |tree: &Tree| Tree::Empty { hash: tree.hash().clone() };
+                let left_count = left_count(count, left, right);
+
+                let (left, right) = if index < left_count {
+                    (left.prune_to_proof(index)?, stub(right))
+                } else {
+                    (stub(left), right.prune_to_proof(index - left_count)?)
+                };
+
+                Some(Tree::Node {
+                    hash: hash.clone(),
+                    count: count,
+                    height: height,
+                    left: Arc::new(left),
+                    right: Arc::new(right)
+                })
+            }
+        }
+    }
+
+    /// Recomputes every leaf and node hash bottom-up, e.g. after leaf values were edited,
+    /// and returns the new root hash.
+    ///
+    /// Unlike `verify_root`, stale hashes are replaced instead of reported. This is O(n)
+    /// and subtrees shared with clones of this tree are copied before being rehashed.
+    pub fn rebuild_hashes(&mut self) -> Result<Vec<u8>, CommonError> {
+        self.rebuild_hashes_with_hasher(&Hash {})
+    }
+
+    /// Same as `rebuild_hashes` but hashes with the given `hasher`.
+    pub fn rebuild_hashes_with_hasher<H: MerkleHasher>(&mut self, hasher: &H) -> Result<Vec<u8>, CommonError> {
+        match *self {
+            Tree::Empty { ref mut hash } => {
+                *hash = hasher.hash_empty()?;
+                Ok(hash.clone())
+            }
+            Tree::Leaf { ref mut hash, ref value } => {
+                *hash = hasher.hash_leaf(value)?;
+                Ok(hash.clone())
+            }
+            Tree::Node { ref mut hash, ref mut left, ref mut right, .. } => {
+                let left_hash = Arc::make_mut(left).rebuild_hashes_with_hasher(hasher)?;
+                let right_hash = Arc::make_mut(right).rebuild_hashes_with_hasher(hasher)?;
+                *hash = hasher.hash_nodes(&left_hash, &right_hash)?;
+                Ok(hash.clone())
+            }
+        }
+    }
+
+    /// Replaces the value of the leaf at position `index`, e.g. in a mutable view of a ledger,
+    /// and returns the new root hash, or `None` if `index` is out of range.
+    ///
+    /// Only the new leaf and its ancestors are rehashed, that is O(log n) hashes for a balanced
+    /// tree, which gives the same tree as rebuilding it with the value changed.
+    pub fn replace_leaf(&mut self, index: usize, value: TreeLeafData) -> Result<Option<Vec<u8>>, CommonError> {
+        self.replace_leaf_with_hasher(&Hash {}, index, value)
+    }
+
+    /// Same as `replace_leaf` for a tree built with the given `hasher`.
+    pub fn replace_leaf_with_hasher<H: MerkleHasher>(&mut self, hasher: &H, index: usize, value: TreeLeafData) -> Result<Option<Vec<u8>>, CommonError> {
+        if index >= self.get_count() {
+            return Ok(None);
+        }
+
+        match *self {
+            // a stub left by `prune_to_proof`
+            Tree::Empty { .. } => return Ok(None),
+
+            Tree::Leaf { .. } => {
+                *self = Tree::new_leaf_with_hasher(hasher, value)?;
+            }
+
+            Tree::Node { ref mut hash, ref mut left, ref mut right, count, .. } => {
+                let left_count = left_count(count, left, right);
+
+                let replaced = if index < left_count {
+                    Arc::make_mut(left).replace_leaf_with_hasher(hasher, index, value)?
+                } else {
+                    Arc::make_mut(right).replace_leaf_with_hasher(hasher, index - left_count, value)?
+                };
+
+                if replaced.is_none() {
+                    return Ok(None);
+                }
+
+                *hash = hasher.hash_nodes(left.hash(), right.hash())?;
+            }
+        }
+
+        Ok(Some(self.hash().clone()))
+    }
+
+    /// Consumes the tree and applies `f` to every leaf value, in order, e.g. to migrate
+    /// leaf encodings.
+    ///
+    /// The shape of the tree is preserved, every leaf staying at its position, but all
+    /// the leaf and node hashes are recomputed so the root hash changes with the values.
+    pub fn map_values<F>(self, f: F) -> Result<Tree, CommonError>
+        where F: Fn(TreeLeafData) -> TreeLeafData {
+        self.map_values_with_hasher(&Hash {}, f)
+    }
+
+    /// Same as `map_values` but hashes with the given `hasher`.
+    pub fn map_values_with_hasher<H, F>(self, hasher: &H, f: F) -> Result<Tree, CommonError>
+        where H: MerkleHasher, F: Fn(TreeLeafData) -> TreeLeafData {
+        self.map_leaves(hasher, &f)
+    }
+
+    fn map_leaves<H, F>(self, hasher: &H, f: &F) -> Result<Tree, CommonError>
+        where H: MerkleHasher, F: Fn(TreeLeafData) -> TreeLeafData {
+        match self {
+            Tree::Empty { .. } => Tree::empty_with_hasher(hasher),
+            Tree::Leaf { value, .. } => Tree::new_leaf_with_hasher(hasher, f(value)),
+            Tree::Node { left, right, .. } => {
+                let left = LeavesIntoIterator::take(left).map_leaves(hasher, f)?;
+                let right = LeavesIntoIterator::take(right).map_leaves(hasher, f)?;
+                Tree::new_node_with_hasher(hasher, left, right)
+            }
+        }
+    }
+
+    /// Returns a copy of the tree sharing all its subtrees with this one, in O(1).
+    ///
+    /// This is what `clone()` already does since children are behind `Arc`; the name
+    /// only makes it explicit for callers keeping many versions of a tree around.
+    pub fn clone_shallow(&self) -> Tree {
+        self.clone()
+    }
+
+    /// Returns a copy of the tree with every leaf value replaced by an empty one,
+    /// keeping all the hashes, e.g. to ship a light skeleton of a tree with large values.
+    ///
+    /// The skeleton keeps the root hash and still generates proofs with `gen_proof`,
+    /// but leaf values can't be retrieved from it and `verify_root` fails on it.
+    pub fn to_hash_only(&self) -> Tree {
+        match *self {
+            Tree::Empty { ref hash } => Tree::Empty { hash: hash.clone() },
+            Tree::Leaf { ref hash, .. } => Tree::Leaf { hash: hash.clone(), value: Vec::new() },
+            Tree::Node { ref hash, ref left, ref right, count, height } => Tree::Node {
+                hash: hash.clone(),
+                left: Arc::new(left.to_hash_only()),
+                right: Arc::new(right.to_hash_only()),
+                count: count,
+                height: height
+            }
+        }
+    }
+
+    /// Serializes the tree to MessagePack.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, CommonError> {
+        rmp_serde::to_vec(self)
+            .map_err(|err| CommonError::InvalidState(format!("Can't serialize tree: {:?}", err)))
+    }
+
+    /// Deserializes a tree from MessagePack.
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Tree, CommonError> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|err| CommonError::InvalidStructure(format!("Cannot deserialize tree: {}", err)))
+    }
+
+    /// Serializes the tree to JSON, as an externally tagged `Empty`, `Leaf` or `Node` object.
+    pub fn to_json(&self) -> Result<String, CommonError> {
+        serde_json::to_string(self)
+            .map_err(|err| CommonError::InvalidState(format!("Can't serialize tree: {:?}", err)))
+    }
+
+    /// Deserializes a tree from the JSON produced by `to_json`.
+    ///
+    /// Use `decode_json_with_limit` instead for JSON coming from an untrusted source.
+    pub fn try_decode_json(json: &str) -> Result<Tree, CommonError> {
+        serde_json::from_str(json)
+            .map_err(|err| CommonError::InvalidStructure(format!("Cannot deserialize tree: {}", err)))
+    }
+
+    /// Serializes the tree to JSON as `to_json` does, but with every hash and value
+    /// as a base64 string instead of an array of numbers, which is about 3 times smaller.
+    pub fn to_compact_json(&self) -> Result<String, CommonError> {
+        serde_json::to_string(&self.to_compact_tree())
+            .map_err(|err| CommonError::InvalidState(format!("Can't serialize tree: {:?}", err)))
+    }
+
+    /// Deserializes a tree from the JSON produced by `to_compact_json`.
+    pub fn decode_compact_json(json: &str) -> Result<Tree, CommonError> {
+        let compact: CompactTree = serde_json::from_str(json)
+            .map_err(|err| CommonError::InvalidStructure(format!("Cannot deserialize tree: {}", err)))?;
+
+        Tree::from_compact_tree(compact)
+    }
+
+    fn to_compact_tree(&self) -> CompactTree {
+        match *self {
+            Tree::Empty { ref hash } =>
+                CompactTree::Empty { hash: base64::encode(hash) },
+
+            Tree::Leaf { ref hash, ref value } =>
+                CompactTree::Leaf { hash: base64::encode(hash), value: base64::encode(value) },
+
+            Tree::Node { ref hash, ref left, ref right, .. } =>
+                CompactTree::Node {
+                    hash: base64::encode(hash),
+                    left: Box::new(left.to_compact_tree()),
+                    right: Box::new(right.to_compact_tree())
+                }
+        }
+    }
+
+    fn from_compact_tree(compact: CompactTree) -> Result<Tree, CommonError> {
+        Ok(match compact {
+            CompactTree::Empty { hash } =>
+                Tree::Empty { hash: base64::decode(&hash)? },
+
+            CompactTree::Leaf { hash, value } =>
+                Tree::Leaf { hash: base64::decode(&hash)?, value: base64::decode(&value)? },
+
+            CompactTree::Node { hash, left, right } =>
+                Tree::with_children(base64::decode(&hash)?,
+                                    Tree::from_compact_tree(*left)?,
+                                    Tree::from_compact_tree(*right)?)
+        })
+    }
+
+    /// Deserializes a tree from JSON, rejecting trees nested deeper than `max_depth`.
+    ///
+    /// The nesting is checked on the raw text before parsing, so hostile input
+    /// can't exhaust the stack. Note that serde_json also stops at 128 nesting
+    /// levels by itself, which is about 60 tree levels.
+    pub fn decode_json_with_limit(json: &str, max_depth: usize) -> Result<Tree, CommonError> {
+        // Every tree level opens two objects, leaves also open byte arrays.
+        if Tree::json_nesting_exceeds(json, 2 * (max_depth + 1) + 1) {
+            return Err(CommonError::InvalidStructure(format!("Tree is nested deeper than {} levels", max_depth)));
+        }
+
+        serde_json::from_str(json)
+            .map_err(|err| CommonError::InvalidStructure(format!("Cannot deserialize tree: {}", err)))
+    }
+
+    /// Same as `decode_json_with_limit`, but also rejects a tree holding a hash
+    /// that is not `HASHBYTES` long, e.g. a truncated one.
+    ///
+    /// Such a tree would otherwise be accepted and only fail to verify much later.
+    pub fn decode_json_checked(json: &str, max_depth: usize) -> Result<Tree, CommonError> {
+        Tree::decode_json_checked_with_hasher(&Hash {}, json, max_depth)
+    }
+
+    /// Same as `decode_json_checked` for the digest length of the given `hasher`.
+    pub fn decode_json_checked_with_hasher<H: MerkleHasher>(hasher: &H, json: &str, max_depth: usize) -> Result<Tree, CommonError> {
+        let tree = Tree::decode_json_with_limit(json, max_depth)?;
+        tree.check_hashes_len(hasher.digest_len())?;
+        Ok(tree)
+    }
+
+    /// Fails on the first hash that is not `digest_len` long, without trusting
+    /// the counts of the nodes as `nodes()` does.
+    fn check_hashes_len(&self, digest_len: usize) -> Result<(), CommonError> {
+        if self.hash().len() != digest_len {
+            return Err(CommonError::InvalidStructure(
+                format!("Hash is {} bytes long instead of {}", self.hash().len(), digest_len)));
+        }
+
+        if let Tree::Node { ref left, ref right, .. } = *self {
+            left.check_hashes_len(digest_len)?;
+            right.check_hashes_len(digest_len)?;
+        }
+
+        Ok(())
+    }
+
+    fn json_nesting_exceeds(json: &str, max_nesting: usize) -> bool {
+        let mut nesting = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for b in json.bytes() {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_string = false;
+                }
+                continue;
+            }
+
+            match b {
+                b'"' => in_string = true,
+                b'{' | b'[' => {
+                    nesting += 1;
+                    if nesting > max_nesting {
+                        return true;
+                    }
+                }
+                b'}' | b']' => nesting -= cmp::min(nesting, 1),
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    /// Returns a hash from the tree.
+    pub fn hash(&self) -> &Vec<u8> {
+        match *self {
+            Tree::Empty { ref hash }    => hash,
+            Tree::Leaf { ref hash, .. } => hash,
+            Tree::Node { ref hash, .. } => hash
+        }
+    }
+
+    /// Returns the hash of the root of the tree.
+    pub fn root_hash(&self) -> RootHash {
+        RootHash(self.hash().clone())
+    }
+
+    /// Checks whether the root hash equals `expected` in constant time,
+    /// to be used instead of `==` when comparing against a trusted root.
+    pub fn root_eq_ct(&self, expected: &[u8]) -> bool {
+        eq_ct(self.hash(), expected)
+    }
+
+    /// Returns the root hash namespaced under a domain `tag`, e.g. the name of a subledger,
+    /// so that subledgers holding the same transactions still publish different roots.
+    ///
+    /// The tagged root is `SHA-256(0x02 || tag || root)`: it differs from the plain root
+    /// and from the root tagged with any other tag. Only the published root is tagged,
+    /// the tree and its proofs are unchanged, use `Proof::validate_tagged` to check them.
+    pub fn root_with_prefix(&self, tag: &[u8]) -> Result<Vec<u8>, CommonError> {
+        self.root_with_prefix_with_hasher(&Hash {}, tag)
+    }
+
+    /// Same as `root_with_prefix` for a tree built with the given `hasher`,
+    /// which also hashes the tag with `MerkleHasher::hash_tagged_root`.
+    pub fn root_with_prefix_with_hasher<H: MerkleHasher>(&self, hasher: &H, tag: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hasher.hash_tagged_root(tag, self.hash())
+    }
+
+    /// Builds the tree over `values` with the given `hasher`, as `from_leaves_with_hasher` does,
+    /// and returns it along with its root namespaced under `tag` by `root_with_prefix_with_hasher`.
+    ///
+    /// The tree itself isn't tagged: its root hash is still the untagged one,
+    /// which differs from the tagged root published for the subledger.
+    pub fn from_leaves_tagged<H: MerkleHasher>(hasher: &H, tag: &[u8], values: Vec<TreeLeafData>) -> Result<(Tree, Vec<u8>), CommonError> {
+        let tree = Tree::from_leaves_with_hasher(hasher, values)?;
+        let tagged_root = tree.root_with_prefix_with_hasher(hasher, tag)?;
+        Ok((tree, tagged_root))
+    }
+
+    /// Returns the root hash as a lowercase hex string.
+    /// For an `Empty` tree this is the hex of the empty hash.
+    pub fn hex_root(&self) -> String {
+        self.root_hash().to_hex()
+    }
+
+    /// Returns the root hash as a base58 string, as reported by the ledger.
+    pub fn base58_root(&self) -> String {
+        base58::encode(self.hash())
+    }
+
+    /// Returns a borrowing iterator over the leaves of the tree.
+    pub fn iter(&self) -> LeavesIterator {
+        LeavesIterator::new(self)
+    }
+
+    /// Returns a borrowing iterator over the leaves along with their positions,
+    /// e.g. to map values to the indices their proofs are generated for.
+    pub fn enumerate_leaves(&self) -> Enumerate<LeavesIterator> {
+        self.iter().enumerate()
+    }
+
+    /// Returns the leaf values, in the same order as `iter()` yields them.
+    pub fn values(&self) -> Vec<&TreeLeafData> {
+        let mut values = Vec::with_capacity(self.get_count());
+        values.extend(self.iter());
+        values
+    }
+
+    /// Consumes the tree and returns its leaf values, in the same order as `iter()` yields them.
+    pub fn into_values(self) -> Vec<TreeLeafData> {
+        let mut values = Vec::with_capacity(self.get_count());
+        values.extend(self.into_iter());
+        values
+    }
+
+    /// Returns an iterator over the leaf hashes, in the same order as `iter()` yields the values.
+    pub fn leaf_hashes<'a>(&'a self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.nodes().filter_map(|node| match *node {
+            Tree::Leaf { ref hash, .. } => Some(hash.as_slice()),
+            _ => None
+        })
+    }
+
+    /// Returns the leaf hashes sorted bytewise, duplicates included, e.g. to reconcile
+    /// the set of leaves with a peer.
+    ///
+    /// Unlike `leaf_hashes()` this order has nothing to do with the positions of the leaves
+    /// as yielded by `iter()`.
+    pub fn leaf_hashes_sorted(&self) -> Vec<Vec<u8>> {
+        let mut hashes = self.leaf_hashes()
+            .map(|hash| hash.to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        hashes.sort();
+        hashes
+    }
+
+    /// Returns an iterator over the leaf values along with their hashes, in the same order
+    /// as `iter()`, which spares rehashing the values.
+    pub fn iter_with_hashes(&self) -> LeavesWithHashesIterator {
+        LeavesWithHashesIterator::new(self)
+    }
+
+    /// Returns a borrowing iterator over the leaves at positions `range.start..range.end`.
+    ///
+    /// Subtrees lying outside the range are skipped as a whole, so only the
+    /// overlapping branches are visited. Yields nothing if the range is empty
+    /// or out of bounds.
+    pub fn leaves_in_range(&self, range: Range<usize>) -> LeavesRangeIterator {
+        LeavesRangeIterator::new(self, range)
+    }
+
+    /// Returns an iterator over every node of the tree, in pre-order:
+    /// a node is yielded before its left subtree, which comes before its right subtree.
+    pub fn nodes(&self) -> NodesIterator {
+        NodesIterator::new(self)
+    }
+
+    /// Walks the tree depth-first, calling back `visitor` on every node,
+    /// in the order described by `Visitor`.
+    pub fn walk<V: Visitor>(&self, visitor: &mut V) {
+        match *self {
+            Tree::Empty { ref hash } => visitor.visit_empty(hash),
+
+            Tree::Leaf { ref hash, ref value } => visitor.visit_leaf(hash, value),
+
+            Tree::Node { ref hash, ref left, ref right, count, height } => {
+                visitor.visit_node_pre(hash, count, height);
+                left.walk(visitor);
+                right.walk(visitor);
+                visitor.visit_node_post(hash, count, height);
+            }
+        }
+    }
+
+    /// Returns the value of the leaf at position `index`.
+    ///
+    /// Leaves are numbered from zero in the same order as they are
+    /// yielded by `iter()`. Returns `None` if `index` is out of range.
+    pub fn get_leaf(&self, index: usize) -> Option<&TreeLeafData> {
+        match *self {
+            Tree::Empty { .. } => None,
+            Tree::Leaf { ref value, .. } => {
+                if index == 0 { Some(value) } else { None }
+            },
+            Tree::Node { ref left, ref right, count, .. } => {
+                let left_count = left_count(count, left, right);
+                if index < left_count {
+                    left.get_leaf(index)
+                } else {
+                    right.get_leaf(index - left_count)
+                }
+            }
+        }
+    }
+
+    /// Returns the subtree reached by descending from the root along `path`,
+    /// `Left` and `Right` standing for the branch taken at each step.
+    ///
+    /// Note that a `Lemma` tags the sibling rather than the branch taken,
+    /// so its positions need to be flipped to be used as a path.
+    /// Returns `None` if the path runs past a leaf or ends on an `Empty` tree.
+    pub fn subtree_at_path(&self, path: &[Positioned<()>]) -> Option<&Tree> {
+        let mut tree = self;
+
+        for step in path {
+            tree = match (tree, step) {
+                (&Tree::Node { ref left, .. }, &Positioned::Left(_)) => left,
+                (&Tree::Node { ref right, .. }, &Positioned::Right(_)) => right,
+                _ => return None
+            };
+        }
+
+        match *tree {
+            Tree::Empty { .. } => None,
+            _ => Some(tree)
+        }
+    }
+
+    /// Returns the hash of the lowest subtree holding both leaves at positions `i` and `j`,
+    /// i.e. the node where the paths to them diverge, or the leaf itself if `i == j`.
+    /// Returns `None` if either index is out of range.
+    pub fn common_ancestor_hash(&self, i: usize, j: usize) -> Option<&[u8]> {
+        if i >= self.get_count() || j >= self.get_count() {
+            return None;
+        }
+
+        let mut tree = self;
+        let (mut i, mut j) = (i, j);
+
+        loop {
+            match *tree {
+                Tree::Node { ref left, ref right, ref hash, count, .. } => {
+                    let left_count = left_count(count, left, right);
+
+                    if i < left_count && j < left_count {
+                        tree = left;
+                    } else if i >= left_count && j >= left_count {
+                        tree = right;
+                        i -= left_count;
+                        j -= left_count;
+                    } else {
+                        return Some(hash);
+                    }
+                }
+                _ => return Some(tree.hash())
+            }
+        }
+    }
+
+    /// Returns the number of edges between the root and the leaf at position `index`.
+    /// Returns `None` if `index` is out of range.
+    pub fn depth_of_leaf(&self, index: usize) -> Option<usize> {
+        match *self {
+            Tree::Empty { .. } => None,
+            Tree::Leaf { .. } => {
+                if index == 0 { Some(0) } else { None }
+            },
+            Tree::Node { ref left, ref right, count, .. } => {
+                let left_count = left_count(count, left, right);
+                let depth = if index < left_count {
+                    left.depth_of_leaf(index)
+                } else {
+                    right.depth_of_leaf(index - left_count)
+                };
+                depth.map(|depth| depth + 1)
+            }
+        }
+    }
+
+    /// Returns from left to right the hashes of the nodes at `level` edges below the root,
+    /// e.g. to compare the subtrees with a peer before drilling down into the differing ones.
+    ///
+    /// A branch shorter than `level`, like the last leaf of an unbalanced tree, gives
+    /// the hash of its leaf instead, so the hashes always cover all the leaves.
+    /// Level 0 is the root hash alone, and past the height of the tree come the leaf hashes.
+    pub fn to_level_hashes(&self, level: usize) -> Vec<Vec<u8>> {
+        let mut hashes = Vec::new();
+        self.collect_level_hashes(level, &mut hashes);
+        hashes
+    }
+
+    fn collect_level_hashes(&self, level: usize, hashes: &mut Vec<Vec<u8>>) {
+        match *self {
+            Tree::Node { ref left, ref right, .. } if level > 0 => {
+                left.collect_level_hashes(level - 1, hashes);
+                right.collect_level_hashes(level - 1, hashes);
+            }
+            _ => hashes.push(self.hash().clone())
+        }
+    }
+
+    /// Asserts in debug builds that the tree has the shape built by `from_leaves`.
+    ///
+    /// Every node must hold in its left subtree the largest power of two
+    /// leaves that is smaller than its own leaves count, and the right subtree
+    /// must be of the same shape. Note that it doesn't mean leaves depths
+    /// differ by one at most: the last leaf of a 5 leaves tree is at depth 1
+    /// while others are at depth 3.
+    pub fn assert_balanced(&self) {
+        debug_assert!(self.balanced_count().is_some(), "Tree is not balanced");
+    }
+
+    /// Returns the leaves count if the tree is balanced.
+    fn balanced_count(&self) -> Option<usize> {
+        match *self {
+            Tree::Empty { .. } => Some(0),
+            Tree::Leaf { .. } => Some(1),
+            Tree::Node { ref left, ref right, .. } => {
+                let left_count = left.balanced_count()?;
+                let right_count = right.balanced_count()?;
+                let count = left_count + right_count;
+
+                if left_count > 0 && right_count > 0 && left_count == split_point(count) {
+                    Some(count)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Returns whether the tree is a perfect binary tree: all its leaves are at the
+    /// same depth, so their count is a power of two. An empty tree is not perfect.
+    pub fn is_perfect(&self) -> bool {
+        self.shape().1
+    }
+
+    /// Returns whether the tree is a complete binary tree: every level but the deepest
+    /// one is full, and the leaves of the deepest level are the leftmost ones.
+    /// A perfect tree is complete, and so is an empty tree.
+    pub fn is_complete(&self) -> bool {
+        self.is_empty() || self.shape().2
+    }
+
+    /// Walks the tree once and returns its height, whether it is perfect,
+    /// and whether it is complete.
+    fn shape(&self) -> (usize, bool, bool) {
+        match *self {
+            Tree::Empty { .. } => (0, false, false),
+            Tree::Leaf { .. } => (0, true, true),
+            Tree::Node { ref left, ref right, .. } => {
+                let (left_height, left_perfect, left_complete) = left.shape();
+                let (right_height, right_perfect, right_complete) = right.shape();
+
+                let perfect = left_perfect && right_perfect && left_height == right_height;
+
+                // the deepest level ends either in the right subtree, or in the left one
+                // with a right subtree then perfect and one level shorter
+                let complete = (left_perfect && right_complete && left_height == right_height)
+                    || (left_complete && right_perfect && left_height == right_height + 1);
+
+                (1 + cmp::max(left_height, right_height), perfect, complete)
+            }
+        }
+    }
+
+    /// Returns the number of interior `Node`s, in O(n).
+    pub fn node_count(&self) -> usize {
+        self.stats().nodes
+    }
+
+    /// Returns the number of nodes of every kind: interior ones, leaves and empty ones, in O(n).
+    pub fn total_node_count(&self) -> usize {
+        self.stats().total()
+    }
+
+    /// Walks the tree once to count its nodes of each kind and check its shape.
+    pub fn stats(&self) -> TreeStats {
+        match *self {
+            Tree::Empty { .. } =>
+                TreeStats { leaves: 0, nodes: 0, empties: 1, height: 0, balanced: true },
+
+            Tree::Leaf { .. } =>
+                TreeStats { leaves: 1, nodes: 0, empties: 0, height: 0, balanced: true },
+
+            Tree::Node { ref left, ref right, .. } => {
+                let left = left.stats();
+                let right = right.stats();
+                let leaves = left.leaves + right.leaves;
+
+                TreeStats {
+                    leaves: leaves,
+                    nodes: left.nodes + right.nodes + 1,
+                    empties: left.empties + right.empties,
+                    height: 1 + cmp::max(left.height, right.height),
+                    balanced: left.balanced && right.balanced
+                        && left.leaves > 0 && right.leaves > 0 && left.leaves == split_point(leaves)
+                }
+            }
+        }
+    }
+
+    /// Generate an inclusion proof for the given value.
+    /// Returns `None` if the given value is not found in the tree.
+    pub fn gen_proof(&self, value: TreeLeafData) -> Result<Option<Proof>, CommonError> {
+        self.gen_proof_with_hasher(&Hash {}, value)
+    }
+
+    /// Same as `gen_proof` for a tree built with the given `hasher`.
+    pub fn gen_proof_with_hasher<H: MerkleHasher>(&self, hasher: &H, value: TreeLeafData) -> Result<Option<Proof>, CommonError> {
+        let root_hash = self.hash().clone();
+        let leaf_hash = hasher.hash_leaf(&value)?;
+
+        Ok(Lemma::new(self, leaf_hash.as_slice()).map(|lemma|
+            Proof::new(root_hash, lemma, value)
+        ))
+    }
+
+    /// Checks that `proof` leads to the root hash of this tree, and that its leaf hash
+    /// is the one of the leaf at the position given by its sibling positions.
+    ///
+    /// On top of what `Proof::validate_against` checks with the root hash only, the whole
+    /// lemma chain must match the nodes of this tree on the path to that position, so a
+    /// proof can't claim a position that doesn't hold its leaf. The value of the proof
+    /// isn't checked, use `Proof::validate_value` for that.
+    pub fn verify_proof(&self, proof: &Proof) -> Result<bool, CommonError> {
+        if !proof.validate_against(self.hash())? {
+            return Ok(false);
+        }
+
+        let index = unwrap_opt_or_return!(proof.leaf_index(self.get_count()), Ok(false));
+
+        Ok(Lemma::new_by_index(self, index).map_or(false, |lemma| lemma == proof.lemma))
+    }
+
+    /// Builds the index of the leaves of the tree, for `gen_proof_cached`.
+    pub fn build_proof_index(&self) -> ProofIndex {
+        ProofIndex::new(self)
+    }
+
+    /// Same as `gen_proof`, but finds the leaf in `index` instead of searching the tree,
+    /// so only the path from the root to the leaf is walked.
+    ///
+    /// Fails if `index` was not built from this tree, e.g. the tree was pushed to since then.
+    pub fn gen_proof_cached(&self, index: &ProofIndex, value: TreeLeafData) -> Result<Option<Proof>, CommonError> {
+        if !index.is_valid_for(self) {
+            return Err(CommonError::InvalidState("Proof index is stale, it must be rebuilt".to_string()));
+        }
+
+        let leaf_hash = Hash::hash_leaf(&value)?;
+
+        Ok(index.position(&leaf_hash.to_vec())
+            .and_then(|position| Lemma::new_by_index(self, position))
+            .map(|lemma| Proof::new(self.hash().clone(), lemma, value)))
+    }
+
+    /// Same as `gen_proof`, but tells an empty tree from a missing value
+    /// instead of returning `None` for both.
+    pub fn try_gen_proof(&self, value: TreeLeafData) -> Result<Proof, ProofError> {
+        if self.is_empty() {
+            return Err(ProofError::EmptyTree);
+        }
+
+        match self.gen_proof(value)? {
+            Some(proof) => Ok(proof),
+            None => Err(ProofError::ValueNotFound)
+        }
+    }
+
+    /// Generates an inclusion proof for the leaf at position `index`,
+    /// which doesn't require the leaf value to be unique in the tree.
+    pub fn gen_proof_by_index(&self, index: usize) -> Result<Proof, ProofError> {
+        if self.is_empty() {
+            return Err(ProofError::EmptyTree);
+        }
+
+        match (Lemma::new_by_index(self, index), self.get_leaf(index)) {
+            (Some(lemma), Some(value)) => Ok(Proof::new(self.hash().clone(), lemma, value.clone())),
+            _ => Err(ProofError::IndexOutOfRange(index, self.get_count()))
+        }
+    }
+
+    /// Returns the position of the first leaf, in `iter()` order, whose value matches `predicate`,
+    /// along with its inclusion proof, or `None` if no leaf matches.
+    ///
+    /// Like `gen_proof_by_index` this only reads the stored hashes, so it works the same
+    /// whatever the hasher the tree was built with.
+    pub fn find_by_predicate<F>(&self, predicate: F) -> Option<(usize, Proof)>
+        where F: Fn(&TreeLeafData) -> bool
+    {
+        let index = self.iter().position(|value| predicate(value))?;
+        self.gen_proof_by_index(index).ok().map(|proof| (index, proof))
+    }
+
+    /// Returns the audit path of the leaf at position `index`: the hashes of the siblings
+    /// met on the way from the leaf up to the root, tagged with their position.
+    /// Returns `None` if `index` is out of range.
+    pub fn audit_path(&self, index: usize) -> Option<Vec<Positioned<Vec<u8>>>> {
+        let mut path = Vec::new();
+        let mut tree = self;
+        let mut index = index;
+
+        if index >= self.get_count() {
+            return None;
+        }
+
+        while let Tree::Node { ref left, ref right, count, .. } = *tree {
+            let left_count = left_count(count, left, right);
+            if index < left_count {
+                path.push(Positioned::Right(right.hash().clone()));
+                tree = left;
+            } else {
+                path.push(Positioned::Left(left.hash().clone()));
+                index -= left_count;
+                tree = right;
+            }
+        }
+
+        if tree.is_empty() {
+            // the leaf was pruned by `prune_to_proof`
+            return None;
+        }
+
+        path.reverse();
+        Some(path)
+    }
+
+    /// Returns the sibling hashes proving that the root at position `index` of a tree
+    /// built by `concat_roots` is under its aggregate root, from the root up, as expected
+    /// by `proof::verify_root_inclusion`.
+    /// Returns `None` if `index` is out of range.
+    pub fn gen_root_proof(&self, index: usize) -> Option<Vec<Vec<u8>>> {
+        self.audit_path(index).map(|path| path.into_iter()
+            .map(|sibling| match sibling {
+                Positioned::Left(hash) | Positioned::Right(hash) => hash
+            })
+            .collect())
+    }
+
+    /// Generate a single inclusion proof for the leaves at the given positions.
+    ///
+    /// Sibling hashes shared by several leaves are included only once.
+    /// Positions are sorted and de-duplicated. Returns `None` if the tree
+    /// is empty or a position is out of range.
+    pub fn gen_multiproof(&self, indices: &[usize]) -> Option<MultiProof> {
+        let count = self.get_count();
+
+        let mut indices = indices.to_vec();
+        indices.sort();
+        indices.dedup();
+
+        if count == 0 || indices.last().map(|&last| last >= count).unwrap_or(false) {
+            return None;
+        }
+
+        let mut hashes = Vec::new();
+        self.collect_multiproof_hashes(&indices, &mut hashes);
+
+        Some(MultiProof::new(count, indices, hashes))
+    }
+
+    /// Collects in pre-order the hashes of the subtrees holding none of `indices`.
+    fn collect_multiproof_hashes(&self, indices: &[usize], hashes: &mut Vec<Vec<u8>>) {
+        if indices.is_empty() {
+            hashes.push(self.hash().clone());
+            return;
+        }
+
+        if let Tree::Node { ref left, ref right, count, .. } = *self {
+            let left_count = left_count(count, left, right);
+            let split = indices.iter().position(|&i| i >= left_count).unwrap_or(indices.len());
+            let right_indices = indices[split..].iter().map(|i| i - left_count).collect::<Vec<_>>();
+
+            left.collect_multiproof_hashes(&indices[..split], hashes);
+            right.collect_multiproof_hashes(&right_indices, hashes);
+        }
+    }
+
+    /// Generate a proof that this tree is an append-only extension
+    /// of the tree built over its first `old_size` leaves.
+    /// Returns `None` if `old_size` is bigger than the leaves count.
+    pub fn gen_consistency_proof(&self, old_size: usize) -> Option<ConsistencyProof> {
+        let count = self.get_count();
+
+        if old_size > count {
+            return None;
+        }
+
+        let mut hashes = Vec::new();
+        if old_size > 0 {
+            self.collect_consistency_hashes(old_size, true, &mut hashes);
+        }
+
+        Some(ConsistencyProof::new(hashes))
+    }
+
+    /// SUBPROOF(m, D[n], b) from RFC 6962, section 2.1.2.
+    fn collect_consistency_hashes(&self, old_size: usize, is_old_root: bool, hashes: &mut Vec<Vec<u8>>) {
+        if old_size == self.get_count() {
+            if !is_old_root {
+                hashes.push(self.hash().clone());
+            }
+            return;
+        }
+
+        if let Tree::Node { ref left, ref right, count, .. } = *self {
+            let left_count = left_count(count, left, right);
+
+            if old_size <= left_count {
+                left.collect_consistency_hashes(old_size, is_old_root, hashes);
+                hashes.push(right.hash().clone());
+            } else {
+                right.collect_consistency_hashes(old_size - left_count, false, hashes);
+                hashes.push(left.hash().clone());
+            }
+        }
+    }
+
+    /// Generates a proof that `value` is not a leaf of this tree, whose leaves
+    /// must be sorted in ascending order, from the proofs of the leaves right below
+    /// and right above it, which `AbsenceProof::validate` checks are adjacent.
+    ///
+    /// Returns `None` if `value` is in the tree. On a tree that isn't sorted
+    /// the proof is meaningless and doesn't validate.
+    pub fn gen_absence_proof(&self, value: &TreeLeafData) -> Option<AbsenceProof> {
+        let count = self.get_count();
+        let low = self.partition_point(|leaf| leaf < value);
+
+        if self.get_leaf(low) == Some(value) {
+            return None;
+        }
+
+        let lower = if low > 0 { Some(self.gen_proof_by_index(low - 1).ok()?) } else { None };
+        let upper = if low < count { Some(self.gen_proof_by_index(low).ok()?) } else { None };
+
+        Some(AbsenceProof::new(value.clone(), lower, upper))
+    }
+
+    /// Generates a proof that the leaves between `lo` and `hi`, inclusive, of this tree,
+    /// whose leaves must be sorted in ascending order, are exactly `RangeProof::range_values`.
+    ///
+    /// Returns `None` if `lo` is greater than `hi`. On a tree that isn't sorted
+    /// the proof is meaningless and may not validate.
+    pub fn gen_range_proof(&self, lo: &TreeLeafData, hi: &TreeLeafData) -> Option<RangeProof> {
+        if lo > hi {
+            return None;
+        }
+
+        let count = self.get_count();
+        if count == 0 {
+            return Some(RangeProof::new(lo.clone(), hi.clone(), Vec::new(), MultiProof::new(0, Vec::new(), Vec::new())));
+        }
+
+        // cover the range along with the leaves right outside of it
+        let start = self.partition_point(|leaf| leaf < lo).saturating_sub(1);
+        let end = cmp::min(self.partition_point(|leaf| leaf <= hi) + 1, count);
+
+        let indices = (start..end).collect::<Vec<usize>>();
+        let values = self.leaves_in_range(start..end).cloned().collect();
+
+        Some(RangeProof::new(lo.clone(), hi.clone(), values, self.gen_multiproof(&indices)?))
+    }
+
+    /// Returns the position of the first leaf for which `pred` is false,
+    /// `pred` having to be true for all the leaves before it and false for all the leaves after it.
+    fn partition_point<P>(&self, pred: P) -> usize where P: Fn(&TreeLeafData) -> bool {
+        let mut low = 0;
+        let mut high = self.get_count();
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.get_leaf(mid) {
+                Some(leaf) if pred(leaf) => low = mid + 1,
+                _ => high = mid
+            }
+        }
+
+        low
+    }
+
+    /// Returns the position of the first leaf holding `value`.
+    ///
+    /// If the value is present several times the smallest index is returned.
+    /// Returns `None` if the value is not found in the tree.
+    pub fn leaf_index(&self, value: &TreeLeafData) -> Option<usize> {
+        self.iter().position(|v| v == value)
+    }
+
+    /// Returns the position of the first leaf whose hash is `leaf_hash`.
+    ///
+    /// Same as `leaf_index` but compares precomputed leaf hashes
+    /// instead of values, so the candidate is hashed once by the caller.
+    pub fn leaf_index_by_hash(&self, leaf_hash: &[u8]) -> Option<usize> {
+        self.position_by_hash(leaf_hash).ok()
+    }
+
+    /// Checks whether `value` is stored in one of the leaves.
+    ///
+    /// The candidate is hashed once and compared against the stored
+    /// leaf hashes. Inner node hashes tell nothing about which leaves
+    /// are below them, so all leaves may be visited in the worst case.
+    pub fn contains(&self, value: &TreeLeafData) -> Result<bool, CommonError> {
+        let leaf_hash = Hash::hash_leaf(value)?;
+        Ok(self.leaf_index_by_hash(&leaf_hash.to_vec()).is_some())
+    }
+
+    /// Returns the sorted positions of the leaves that differ between `self` and `other`.
+    ///
+    /// Both trees are walked in lockstep and subtrees with matching hashes are skipped,
+    /// so trees that mostly agree are compared without visiting most of their leaves.
+    /// If the trees hold a different number of leaves, the extra positions are reported too.
+    pub fn diff(&self, other: &Tree) -> Vec<usize> {
+        let mut positions = Vec::new();
+        Tree::collect_diff(self, other, 0, &mut positions);
+        positions
+    }
+
+    fn collect_diff(a: &Tree, b: &Tree, offset: usize, positions: &mut Vec<usize>) {
+        if a.hash() == b.hash() {
+            return;
+        }
+
+        if let (&Tree::Node { left: ref a_left, right: ref a_right, .. },
+                &Tree::Node { left: ref b_left, right: ref b_right, .. }) = (a, b) {
+            let left_count = a_left.get_count();
+            if left_count == b_left.get_count() {
+                Tree::collect_diff(a_left, b_left, offset, positions);
+                Tree::collect_diff(a_right, b_right, offset + left_count, positions);
+                return;
+            }
+        }
+
+        // shapes differ, fall back to comparing the leaves one by one
+        let mut a_leaves = a.iter();
+        let mut b_leaves = b.iter();
+        let mut position = offset;
+
+        loop {
+            match (a_leaves.next(), b_leaves.next()) {
+                (None, None) => break,
+                (Some(a_value), Some(b_value)) if a_value == b_value => {},
+                _ => positions.push(position)
+            }
+            position += 1;
+        }
+    }
+
+    /// Returns `Ok(index)` of the matching leaf or
+    /// `Err(count)` with the number of leaves walked through.
+    fn position_by_hash(&self, leaf_hash: &[u8]) -> Result<usize, usize> {
+        match *self {
+            Tree::Empty { .. } => Err(0),
+            Tree::Leaf { ref hash, .. } => {
+                if hash.as_slice() == leaf_hash { Ok(0) } else { Err(1) }
+            },
+            Tree::Node { ref left, ref right, count, .. } => {
+                match left.position_by_hash(leaf_hash) {
+                    Ok(index) => Ok(index),
+                    Err(_) => {
+                        let left_count = left_count(count, left, right);
+                        right.position_by_hash(leaf_hash)
+                            .map(|index| left_count + index)
+                            .map_err(|right_count| left_count + right_count)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the height of the tree, in O(1).
+    ///
+    /// This is the number of edges from the root to the deepest leaf, i.e. the length
+    /// of the longest proof: a balanced tree of `n > 0` leaves has height `ceil(log2(n))`,
+    /// so a single leaf has height 0. An empty tree has height 0 as well,
+    /// use `is_empty` to tell it apart from a single leaf.
+    pub fn get_height(&self) -> usize {
+        match *self {
+            Tree::Empty { .. } => { 0 },
+            Tree::Node { height, .. } => { height },
+            Tree::Leaf { .. } => { 0 }
+        }
+    }
+
+    /// Returns whether the tree has no leaves.
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            Tree::Empty { .. } => true,
+            _ => false
+        }
+    }
+
+    /// Returns the number of leaves in the tree, in O(1).
+    pub fn get_count(&self) -> usize {
+        match *self {
+            Tree::Empty { .. } => { 0 },
+            Tree::Node { count, .. } => { count },
+            Tree::Leaf { .. } => { 1 }
+        }
+    }
+
+    /// Returns the number of leaves actually held, which is `get_count` except for a tree
+    /// pruned by `prune_to_proof`, whose `Empty` stubs hold none of the leaves they count for.
+    fn held_count(&self) -> usize {
+        match *self {
+            Tree::Empty { .. } => 0,
+            Tree::Leaf { .. } => 1,
+            Tree::Node { ref left, ref right, count, .. } => {
+                if count == left.get_count() + right.get_count() {
+                    count
+                } else {
+                    left.held_count() + right.held_count()
+                }
+            }
+        }
+    }
+}
+
+/// Equality stays structural, two trees are equal only if all their nodes are.
+impl Eq for Tree {}
+
+/// Only the root hash is hashed: it commits to the whole tree, so it is
+/// consistent with the structural equality and cheap to compute.
+impl hash::Hash for Tree {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        hash::Hash::hash(self.hash(), state)
+    }
+}
+
+/// Nodes deeper than this are elided by `Display`.
+const DISPLAY_MAX_DEPTH: usize = 16;
+
+/// Number of bytes of hashes and values shown by `Display`.
+const DISPLAY_BYTES: usize = 4;
+
+impl fmt::Display for Tree {
+
+    /// Renders the tree as an indented ASCII diagram with truncated hex hashes and values.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, "", "", 0)
+    }
+
+}
+
+impl Tree {
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, prefix: &str, child_prefix: &str, depth: usize) -> fmt::Result {
+        if depth > DISPLAY_MAX_DEPTH {
+            return writeln!(f, "{}...", prefix);
+        }
+
+        match *self {
+            Tree::Empty { ref hash } =>
+                writeln!(f, "{}Empty {}", prefix, short_hex(hash)),
+
+            Tree::Leaf { ref hash, ref value } =>
+                writeln!(f, "{}Leaf {} {}", prefix, short_hex(hash), short_hex(value)),
+
+            Tree::Node { ref hash, ref left, ref right, .. } => {
+                writeln!(f, "{}Node {}", prefix, short_hex(hash))?;
+                left.fmt_indented(f, &format!("{}+-- ", child_prefix), &format!("{}|   ", child_prefix), depth + 1)?;
+                right.fmt_indented(f, &format!("{}`-- ", child_prefix), &format!("{}    ", child_prefix), depth + 1)
+            }
+        }
+    }
+
+}
+
+fn short_hex(bytes: &[u8]) -> String {
+    let mut hex = bytes.iter()
+        .take(DISPLAY_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    if bytes.len() > DISPLAY_BYTES {
+        hex.push_str("..");
+    }
+
+    hex
+}
+
+/// Returns the number of leaves under the `left` child of a node holding `count` leaves.
+/// An `Empty` stub left by `prune_to_proof` stands for the leaves the `right` child doesn't hold.
+pub fn left_count(count: usize, left: &Tree, right: &Tree) -> usize {
+    if left.is_empty() { count.saturating_sub(right.get_count()) } else { left.get_count() }
+}
+
+/// An borrowing iterator over the leaves of a `Tree`.
+/// Adapted from http://codereview.stackexchange.com/q/110283.
+#[allow(missing_debug_implementations)]
+pub struct LeavesIterator<'a> {
+    current_value: Option<&'a TreeLeafData>,
+    right_nodes: Vec<&'a Tree>,
+    current_back_value: Option<&'a TreeLeafData>,
+    left_nodes: Vec<&'a Tree>,
+    remaining: usize
+}
+
+impl <'a> LeavesIterator<'a> {
+
+    fn new(root: &'a Tree) -> Self {
+        let mut iter = LeavesIterator {
+            current_value: None,
+            right_nodes: Vec::new(),
+            current_back_value: None,
+            left_nodes: Vec::new(),
+            remaining: root.held_count()
+        };
+
+        iter.add_left(root);
+        iter.add_right(root);
+
+        iter
+    }
+
+    fn add_left(&mut self, mut tree: &'a Tree) {
+        loop {
+            match *tree {
+                Tree::Empty { .. } => {
+                    self.current_value = None;
+                    break;
+                },
+
+                Tree::Node { ref left, ref right, .. } => {
+                    self.right_nodes.push(right);
+                    tree = left;
+                },
+
+                Tree::Leaf { ref value, .. } => {
+                    self.current_value = Some(value);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn add_right(&mut self, mut tree: &'a Tree) {
+        loop {
+            match *tree {
+                Tree::Empty { .. } => {
+                    self.current_back_value = None;
+                    break;
+                },
+
+                Tree::Node { ref left, ref right, .. } => {
+                    self.left_nodes.push(left);
+                    tree = right;
+                },
+
+                Tree::Leaf { ref value, .. } => {
+                    self.current_back_value = Some(value);
+                    break;
+                }
+            }
+        }
+    }
+
+}
+
+impl <'a> Iterator for LeavesIterator<'a> {
+
+    type Item = &'a TreeLeafData;
+
+    fn next(&mut self) -> Option<&'a TreeLeafData> {
+        // Front and back cursors walk the tree independently,
+        // the remaining count keeps them from passing each other.
+        if self.remaining == 0 {
+            return None;
+        }
+
+        // the `Empty` stubs of a pruned tree hold no value
+        while self.current_value.is_none() {
+            let rest = self.right_nodes.pop()?;
+            self.add_left(rest);
+        }
+
+        let result = self.current_value.take();
+
+        if let Some(rest) = self.right_nodes.pop() {
+            self.add_left(rest);
+        }
+
+        self.remaining -= 1;
+
+        result
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+}
+
+impl <'a> DoubleEndedIterator for LeavesIterator<'a> {
+
+    fn next_back(&mut self) -> Option<&'a TreeLeafData> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        while self.current_back_value.is_none() {
+            let rest = self.left_nodes.pop()?;
+            self.add_right(rest);
+        }
+
+        let result = self.current_back_value.take();
+
+        if let Some(rest) = self.left_nodes.pop() {
+            self.add_right(rest);
+        }
+
+        self.remaining -= 1;
+
+        result
+    }
+
+}
+
+impl <'a> ExactSizeIterator for LeavesIterator<'a> {}
+
+/// A borrowing iterator over the leaves of a `Tree` within a range of positions.
+#[allow(missing_debug_implementations)]
+pub struct LeavesRangeIterator<'a> {
+    stack: Vec<(&'a Tree, usize)>,
+    range: Range<usize>,
+    remaining: usize
+}
+
+impl <'a> LeavesRangeIterator<'a> {
+
+    fn new(root: &'a Tree, range: Range<usize>) -> Self {
+        let remaining = if root.held_count() == root.get_count() {
+            cmp::min(range.end, root.get_count()).saturating_sub(range.start)
+        } else {
+            // a pruned tree, only walking it tells how many of its leaves are in range
+            LeavesRangeIterator { stack: vec![(root, 0)], range: range.clone(), remaining: 0 }.count()
+        };
+
+        LeavesRangeIterator {
+            stack: vec![(root, 0)],
+            range: range,
+            remaining: remaining
+        }
+    }
+
+}
+
+impl <'a> Iterator for LeavesRangeIterator<'a> {
+
+    type Item = &'a TreeLeafData;
+
+    fn next(&mut self) -> Option<&'a TreeLeafData> {
+        while let Some((tree, offset)) = self.stack.pop() {
+            if offset >= self.range.end || offset + tree.get_count() <= self.range.start {
+                continue;
+            }
+
+            match *tree {
+                Tree::Empty { .. } => {},
+
+                Tree::Leaf { ref value, .. } => {
+                    self.remaining = self.remaining.saturating_sub(1);
+                    return Some(value);
+                },
+
+                Tree::Node { ref left, ref right, count, .. } => {
+                    self.stack.push((right, offset + left_count(count, left, right)));
+                    self.stack.push((left, offset));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+}
+
+impl <'a> ExactSizeIterator for LeavesRangeIterator<'a> {}
+
+/// A borrowing iterator over the leaves of a `Tree` yielding each value with its hash.
+#[allow(missing_debug_implementations)]
+pub struct LeavesWithHashesIterator<'a> {
+    nodes: NodesIterator<'a>,
+    remaining: usize
+}
+
+impl <'a> LeavesWithHashesIterator<'a> {
+
+    fn new(root: &'a Tree) -> Self {
+        LeavesWithHashesIterator {
+            nodes: NodesIterator::new(root),
+            remaining: root.held_count()
+        }
+    }
+
+}
+
+impl <'a> Iterator for LeavesWithHashesIterator<'a> {
+
+    type Item = (&'a TreeLeafData, &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a TreeLeafData, &'a [u8])> {
+        for node in self.nodes.by_ref() {
+            if let Tree::Leaf { ref hash, ref value } = *node {
+                self.remaining = self.remaining.saturating_sub(1);
+                return Some((value, hash.as_slice()));
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+}
+
+impl <'a> ExactSizeIterator for LeavesWithHashesIterator<'a> {}
+
+/// A borrowing pre-order iterator over all the nodes of a `Tree`,
+/// including `Empty`, `Leaf` and `Node` ones.
+///
+/// The number of nodes isn't known upfront: `Empty` stubs inside a tree, e.g. one
+/// pruned by `prune_to_proof`, are nodes that the leaves count doesn't account for.
+#[allow(missing_debug_implementations)]
+pub struct NodesIterator<'a> {
+    stack: Vec<&'a Tree>
+}
+
+impl <'a> NodesIterator<'a> {
+
+    fn new(root: &'a Tree) -> Self {
+        NodesIterator {
+            stack: vec![root]
+        }
+    }
+
+}
+
+impl <'a> Iterator for NodesIterator<'a> {
+
+    type Item = &'a Tree;
+
+    fn next(&mut self) -> Option<&'a Tree> {
+        let tree = self.stack.pop()?;
+
+        if let Tree::Node { ref left, ref right, .. } = *tree {
+            self.stack.push(right);
+            self.stack.push(left);
+        }
+
+        Some(tree)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // every pending subtree yields at least its own root
+        (self.stack.len(), None)
+    }
+
+}
+
+/// An iterator over the leaves of a `Tree`.
+#[allow(missing_debug_implementations)]
+pub struct LeavesIntoIterator {
+    current_value: Option<TreeLeafData>,
+    right_nodes: Vec<Tree>,
+    remaining: usize
+}
+
+impl LeavesIntoIterator {
+
+    fn new(root: Tree) -> Self {
+        let mut iter = LeavesIntoIterator {
+            current_value: None,
+            right_nodes: Vec::new(),
+            remaining: root.held_count()
+        };
+
+        iter.add_left(root);
+
+        iter
+    }
+
+    /// Moves the subtree out of `tree`, cloning it if another version still shares it.
+    fn take(tree: Arc<Tree>) -> Tree {
+        Arc::try_unwrap(tree).unwrap_or_else(|shared| (*shared).clone())
+    }
+
+    fn add_left(&mut self, mut tree: Tree) {
+        loop {
+            match tree {
+                Tree::Empty { .. } => {
+                    self.current_value = None;
+                    break;
+                },
+
+                Tree::Node { left, right, .. } => {
+                    self.right_nodes.push(LeavesIntoIterator::take(right));
+                    tree = LeavesIntoIterator::take(left);
+                },
+
+                Tree::Leaf { value, .. } => {
+                    self.current_value = Some(value);
+                    break;
+                }
+            }
+        }
+    }
+
+}
+
+impl Iterator for LeavesIntoIterator {
+
+    type Item = TreeLeafData;
+
+    fn next(&mut self) -> Option<TreeLeafData> {
+        // the `Empty` stubs of a pruned tree hold no value
+        while self.current_value.is_none() {
+            let rest = self.right_nodes.pop()?;
+            self.add_left(rest);
+        }
+
+        let result = self.current_value.take();
+
+        if let Some(rest) = self.right_nodes.pop() {
+            self.add_left(rest);
+        }
+
+        if result.is_some() {
+            self.remaining -= 1;
+        }
+
+        result
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+
+}
+
+impl ExactSizeIterator for LeavesIntoIterator {}
+
+impl IntoIterator for Tree {
+
+    type Item     = TreeLeafData;
+    type IntoIter = LeavesIntoIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        LeavesIntoIterator::new(self)
+    }
+
+}
+
+impl FromIterator<TreeLeafData> for Tree {
+
+    /// Builds the tree with `from_leaves`, i.e. hashing with the default SHA-256 `Hash`.
+    /// Use `from_leaves_with_hasher` to pick another algorithm.
+    ///
+    /// # Panics
+    ///
+    /// Panics if hashing fails, which only happens on an OpenSSL error.
+    /// Use `from_leaves` to handle it.
+    fn from_iter<I: IntoIterator<Item = TreeLeafData>>(iter: I) -> Self {
+        Tree::from_leaves(iter.into_iter().collect())
+            .expect("Can't hash tree leaves")
+    }
+
+}
+
+impl From<Tree> for Vec<TreeLeafData> {
+
+    /// Same as `Tree::into_values`.
+    fn from(tree: Tree) -> Self {
+        tree.into_values()
+    }
+
+}
+
+impl<'a> From<&'a Tree> for Vec<&'a TreeLeafData> {
+
+    /// Same as `Tree::values`.
+    fn from(tree: &'a Tree) -> Self {
+        tree.values()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::merkletree::MerkleTree;
+    use services::ledger::merkletree::consistency::ConsistencyVerifier;
+    use services::ledger::merkletree::proof::{verify_root_inclusion, verify_root_inclusion_with_hasher};
+    use utils::crypto::hash::{EMPTY_HASH, HASHBYTES, Sha512Hash, Sha384Hash};
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn from_leaves_works_for_empty() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+        assert_eq!(tree, Tree::empty(Hash::hash_empty().unwrap()));
+        assert_eq!(*tree.hash(), EMPTY_HASH.to_vec());
+        assert_eq!(MerkleTree::from_vec(vec![]).unwrap().root_hash(), &Hash::empty_hash());
+    }
+
+    #[test]
+    fn from_leaves_works_for_single_value() {
+        let tree = Tree::from_leaves(values(1)).unwrap();
+        assert_eq!(tree, Tree::new_leaf(values(1).remove(0)).unwrap());
+    }
+
+    #[test]
+    fn from_leaves_works_same_as_append() {
+        for count in 1..18 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let mut mt = MerkleTree::from_vec(vec![]).unwrap();
+            for value in values(count) {
+                mt.append(value).unwrap();
+            }
+
+            assert_eq!(tree.hash(), mt.root_hash());
+            assert_eq!(tree.get_count(), count);
+        }
+    }
+
+    #[test]
+    fn from_leaves_does_not_duplicate_odd_nodes() {
+        // with duplicate padding [a, b, c] and [a, b, c, c] would share their root
+        let mut padded = values(3);
+        padded.push(b"value2".to_vec());
+        assert_ne!(Tree::from_leaves(values(3)).unwrap().hash(), Tree::from_leaves(padded).unwrap().hash());
+
+        // same for an odd node on an upper level: [a..f] and [a..f, e, f]
+        let mut padded = values(6);
+        padded.extend(values(6).into_iter().skip(4));
+        assert_ne!(Tree::from_leaves(values(6)).unwrap().hash(), Tree::from_leaves(padded).unwrap().hash());
+
+        let tree = Tree::from_leaves(values(3)).unwrap();
+        let leaf2 = Tree::new_leaf(b"value2".to_vec()).unwrap();
+        match tree {
+            Tree::Node { ref right, .. } => assert_eq!(**right, leaf2),
+            _ => panic!("expected node")
+        }
+    }
+
+    #[test]
+    fn collect_works_same_as_from_leaves() {
+        for count in 0..10 {
+            let tree: Tree = values(count).into_iter().collect();
+            assert_eq!(tree, Tree::from_leaves(values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn from_leaves_with_hashes_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let hashes = tree.leaf_hashes().map(|hash| hash.to_vec()).collect::<Vec<Vec<u8>>>();
+
+            let hashed = Tree::from_leaves_with_hashes(hashes).unwrap();
+            assert_eq!(hashed.hash(), tree.hash());
+            assert_eq!(hashed, tree.to_hash_only());
+        }
+    }
+
+    #[test]
+    fn from_leaves_with_hashes_does_not_rehash() {
+        let hashes = values(3);
+        assert_ne!(Tree::from_leaves_with_hashes(hashes.clone()).unwrap().hash(), Tree::from_leaves(hashes).unwrap().hash());
+    }
+
+    #[test]
+    fn try_from_leaf_hashes_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let bytes = tree.leaf_hashes().fold(Vec::new(), |mut bytes, hash| {
+                bytes.extend_from_slice(hash);
+                bytes
+            });
+
+            assert_eq!(Tree::try_from_leaf_hashes(&bytes).unwrap(), tree.to_hash_only());
+        }
+    }
+
+    #[test]
+    fn try_from_leaf_hashes_works_for_wrong_length() {
+        assert!(Tree::try_from_leaf_hashes(&[0; 33]).is_err());
+        assert!(Tree::try_from_leaf_hashes(&[0; 31]).is_err());
+        assert!(Tree::try_from_leaf_hashes_with_hasher(&ShortHasher {}, &[0; 32]).is_ok());
+    }
+
+    #[test]
+    fn try_from_leaf_hashes_works_with_hasher() {
+        let tree = Tree::from_leaves_with_hasher(&ShortHasher {}, values(5)).unwrap();
+        let bytes = tree.leaf_hashes().fold(Vec::new(), |mut bytes, hash| {
+            bytes.extend_from_slice(hash);
+            bytes
+        });
+        assert_eq!(bytes.len(), 5 * 16);
+
+        let hashed = Tree::try_from_leaf_hashes_with_hasher(&ShortHasher {}, &bytes).unwrap();
+        assert_eq!(hashed, tree.to_hash_only());
+        assert!(Tree::try_from_leaf_hashes_with_hasher(&ShortHasher {}, &bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn from_leaves_works_for_sha512_known_answers() {
+        let roots = vec![
+            (0, "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"),
+            (1, "1a75a4a370c3b23898aeedca3b96fe9a0dd74cce0082c56ea58e8898596061cbe02c2a46e3051695a709e72bf30e4a46d62c26ca589f6f8cf6e2b267c1b24d22"),
+            (3, "6ac1c827017d929088c5e7f582e42bba7a2418bc1275e89a897559ca0ae10dea2091a6cf793f00292e670ac78b32fc3d57a4f8264e563667e0c8bf4cd779c086"),
+            (5, "9c69569c0dd703e304ced18ceab8ebb7b823c06a1c7ad9cc0bd7370644fd9cb01b98e6c1137a7d3c37f9e64f57dc42b8a5679fbefd263fa57dd88272f1733bdc")
+        ];
+
+        for (count, root) in roots {
+            let tree = Tree::from_leaves_with_hasher(&Sha512Hash {}, values(count)).unwrap();
+            assert_eq!(tree.hex_root(), root);
+        }
+    }
+
+    #[test]
+    fn from_leaves_works_for_sha384_known_answers() {
+        let roots = vec![
+            (0, "38b060a751ac96384cd9327eb1b1e36a21fdb71114be07434c0cc7bf63f6e1da274edebfe76f65fbd51ad2f14898b95b"),
+            (1, "234c551c9fe426705c3ae306c21f83f5b3ace6b507ffe18fa2852607eeb8ef547a6791462b53b34e665192d2ce4315cf"),
+            (3, "74935a15b7a5a8e2be9b1d08bcf54da79986d6320736940000da72eecacbc85a5d5a3e72b24c61957d1e207ef07d84d4"),
+            (5, "2c1bede0bc40c61846570ab905d33b0534e47570872ae09cef694a47fc052f2e6d345d4e06703b6c984348bc94c42541")
+        ];
+
+        for (count, root) in roots {
+            let tree = Tree::from_leaves_with_hasher(&Sha384Hash {}, values(count)).unwrap();
+            assert_eq!(tree.hex_root(), root);
+        }
+    }
+
+    fn check_proofs_with_hasher<H: MerkleHasher>(hasher: &H) {
+        for count in 1..12 {
+            let tree = Tree::from_leaves_with_hasher(hasher, values(count)).unwrap();
+            assert_eq!(tree.hash().len(), hasher.digest_len());
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let proof = tree.gen_proof_with_hasher(hasher, value.clone()).unwrap().unwrap();
+                assert_eq!(proof, tree.gen_proof_by_index(index).unwrap());
+
+                assert!(proof.validate_with_hasher(hasher, tree.hash()).unwrap());
+                assert!(proof.validate_value_with_hasher(hasher, &value, tree.hash()).unwrap());
+                // SHA-256 doesn't fold the siblings to the same root
+                assert_eq!(proof.validate_against(tree.hash()).unwrap(), count == 1);
+
+                let compact = proof.to_compact(index, count).unwrap();
+                assert_eq!(compact.len(), 8 + hasher.digest_len() * proof.lemma_len());
+                assert_eq!(Proof::from_compact_with_hasher(hasher, &compact, count, value).unwrap(), proof);
+
+                assert_eq!(Proof::from_bytes(&proof.to_bytes().unwrap()).unwrap(), proof);
+            }
+
+            let bytes = tree.leaf_hashes().fold(Vec::new(), |mut bytes, hash| {
+                bytes.extend_from_slice(hash);
+                bytes
+            });
+            assert_eq!(Tree::try_from_leaf_hashes_with_hasher(hasher, &bytes).unwrap(), tree.to_hash_only());
+        }
+    }
+
+    #[test]
+    fn proofs_work_with_sha512() {
+        check_proofs_with_hasher(&Sha512Hash {});
+    }
+
+    #[test]
+    fn proofs_work_with_sha384() {
+        check_proofs_with_hasher(&Sha384Hash {});
+    }
+
+    #[test]
+    fn json_works_with_sha512() {
+        let tree = Tree::from_leaves_with_hasher(&Sha512Hash {}, values(9)).unwrap();
+
+        assert_eq!(Tree::try_decode_json(&tree.to_json().unwrap()).unwrap(), tree);
+        assert_eq!(Tree::decode_compact_json(&tree.to_compact_json().unwrap()).unwrap(), tree);
+        assert!(Tree::try_decode_json(&tree.to_json().unwrap()).unwrap().verify_root_with_hasher(&Sha512Hash {}).unwrap());
+    }
+
+    #[test]
+    fn push_and_truncate_work_with_sha512() {
+        let mut tree = Tree::empty_with_hasher(&Sha512Hash {}).unwrap();
+
+        for count in 1..12 {
+            tree.push_with_hasher(&Sha512Hash {}, values(count).pop().unwrap()).unwrap();
+            assert_eq!(tree, Tree::from_leaves_with_hasher(&Sha512Hash {}, values(count)).unwrap());
+            assert_eq!(tree.truncate_with_hasher(&Sha512Hash {}, count / 2).unwrap(),
+                       Tree::from_leaves_with_hasher(&Sha512Hash {}, values(count / 2)).unwrap());
+        }
+    }
+
+    #[test]
+    fn multiproof_consistency_and_range_proofs_work_with_sha512() {
+        let hasher = Sha512Hash {};
+        let tree = Tree::from_leaves_with_hasher(&hasher, values(10)).unwrap();
+
+        let multiproof = tree.gen_multiproof(&[1, 4, 7]).unwrap();
+        let leaves = vec![values(10)[1].clone(), values(10)[4].clone(), values(10)[7].clone()];
+        assert!(multiproof.validate_with_hasher(&hasher, tree.hash(), &leaves).unwrap());
+        assert!(!multiproof.validate(tree.hash(), &leaves).unwrap());
+
+        for old_size in 0..11 {
+            let old_tree = Tree::from_leaves_with_hasher(&hasher, values(old_size)).unwrap();
+            let proof = tree.gen_consistency_proof(old_size).unwrap();
+            assert!(proof.verify_with_hasher(&hasher, old_tree.hash(), old_size, tree.hash(), 10).unwrap());
+
+            let mut verifier = ConsistencyVerifier::from_tree_with_hasher(Sha512Hash {}, &old_tree).unwrap();
+            verifier.expect_root(10, tree.hash().clone()).unwrap();
+            for value in &values(10)[old_size..] {
+                verifier.push(value).unwrap();
+            }
+        }
+
+        let range = tree.gen_range_proof(&b"value3".to_vec(), &b"value6".to_vec()).unwrap();
+        assert!(range.validate_with_hasher(&hasher, tree.hash(), 10).unwrap());
+        assert!(!range.validate(tree.hash(), 10).unwrap());
+    }
+
+    struct ReversedHasher {}
+
+    impl MerkleHasher for ReversedHasher {
+        fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_leaf(&leaf.iter().rev().cloned().collect::<Vec<u8>>())?.to_vec())
+        }
+
+        fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_nodes(&right, &left)?.to_vec())
+        }
+
+        fn hash_empty(&self) -> Result<Vec<u8>, CommonError> {
+            Ok(vec![0; 32])
+        }
+
+        fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_tagged_root(&root, &tag)?.to_vec())
+        }
+
+        fn algorithm(&self) -> &'static str {
+            "reversed"
+        }
+    }
+
+    #[test]
+    fn from_leaves_with_hasher_works() {
+        let tree = Tree::from_leaves_with_hasher(&ReversedHasher {}, values(3)).unwrap();
+
+        let leaf_hash = |v: &str| Hash::hash_leaf(&v.bytes().rev().collect::<Vec<u8>>()).unwrap().to_vec();
+        let node = Hash::hash_nodes(&leaf_hash("value1"), &leaf_hash("value0")).unwrap().to_vec();
+        let root = Hash::hash_nodes(&leaf_hash("value2"), &node).unwrap().to_vec();
+
+        assert_eq!(tree.hash(), &root);
+        assert_eq!(Tree::from_leaves_with_hasher(&ReversedHasher {}, vec![]).unwrap().hash(), &vec![0; 32]);
+    }
+
+    struct ShortHasher {}
+
+    impl MerkleHasher for ShortHasher {
+        fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_leaf(&leaf)?.to_vec()[..16].to_vec())
+        }
+
+        fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_nodes(&left, &right)?.to_vec()[..16].to_vec())
+        }
+
+        fn hash_empty(&self) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_empty()?.to_vec()[..16].to_vec())
+        }
+
+        fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError> {
+            Ok(Hash::hash_tagged_root(&tag, &root)?.to_vec()[..16].to_vec())
+        }
+
+        fn digest_len(&self) -> usize {
+            16
+        }
+
+        fn algorithm(&self) -> &'static str {
+            "short"
+        }
+    }
+
+    #[test]
+    fn push_with_hasher_works_same_as_from_leaves_with_hasher() {
+        let mut tree = Tree::empty_with_hasher(&ReversedHasher {}).unwrap();
+        assert_eq!(tree, Tree::from_leaves_with_hasher(&ReversedHasher {}, vec![]).unwrap());
+
+        for count in 1..12 {
+            tree.push_with_hasher(&ReversedHasher {}, format!("value{}", count - 1).into_bytes()).unwrap();
+            assert_eq!(tree, Tree::from_leaves_with_hasher(&ReversedHasher {}, values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn push_with_hasher_fails_for_other_digest_size() {
+        let mut tree = Tree::from_leaves(values(3)).unwrap();
+        assert!(tree.push_with_hasher(&ShortHasher {}, b"value3".to_vec()).is_err());
+        assert_eq!(tree, Tree::from_leaves(values(3)).unwrap());
+
+        let mut tree = Tree::from_leaves_with_hasher(&ShortHasher {}, values(3)).unwrap();
+        assert!(tree.push(b"value3".to_vec()).is_err());
+    }
+
+    #[test]
+    fn merge_fails_for_other_digest_size() {
+        let left = Tree::from_leaves(values(2)).unwrap();
+        let right = Tree::from_leaves_with_hasher(&ShortHasher {}, values(2)).unwrap();
+        assert!(Tree::merge(left, right).is_err());
+    }
+
+    #[test]
+    fn from_leaves_with_hasher_works_for_default_hasher() {
+        for count in 0..10 {
+            assert_eq!(Tree::from_leaves_with_hasher(&Hash {}, values(count)).unwrap(),
+                       Tree::from_leaves(values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn merge_works_same_as_from_leaves() {
+        let all = values(12);
+
+        let left = Tree::from_leaves(all[..8].to_vec()).unwrap();
+        let right = Tree::from_leaves(all[8..].to_vec()).unwrap();
+
+        let tree = Tree::merge(left, right).unwrap();
+        assert_eq!(tree, Tree::from_leaves(all).unwrap());
+        tree.assert_balanced();
+    }
+
+    #[test]
+    fn merge_works_for_empty() {
+        let tree = Tree::from_leaves(values(3)).unwrap();
+        let empty = Tree::from_leaves(vec![]).unwrap();
+
+        assert_eq!(Tree::merge(tree.clone(), empty.clone()).unwrap(), tree);
+        assert_eq!(Tree::merge(empty.clone(), tree.clone()).unwrap(), tree);
+        assert_eq!(Tree::merge(empty.clone(), empty.clone()).unwrap(), empty);
+    }
+
+    #[test]
+    fn verify_root_works() {
+        for count in 0..10 {
+            assert!(Tree::from_leaves(values(count)).unwrap().verify_root().unwrap());
+        }
+    }
+
+    #[test]
+    fn verify_root_works_for_corrupted_inner_hash() {
+        let mut tree = Tree::from_leaves(values(6)).unwrap();
+
+        if let Tree::Node { ref mut left, .. } = tree {
+            if let Tree::Node { ref mut hash, .. } = *Arc::make_mut(left) {
+                hash[0] ^= 0xff;
+            }
+        }
+
+        assert!(!tree.verify_root().unwrap());
+    }
+
+    #[test]
+    fn verify_root_works_for_corrupted_count() {
+        let mut tree = Tree::from_leaves(values(6)).unwrap();
+
+        if let Tree::Node { ref mut count, .. } = tree {
+            *count += 1;
+        }
+
+        assert!(!tree.verify_root().unwrap());
+    }
+
+    #[test]
+    fn verify_root_works_for_corrupted_leaf_value() {
+        let mut tree = Tree::from_leaves(values(3)).unwrap();
+
+        if let Tree::Node { ref mut right, .. } = tree {
+            if let Tree::Leaf { ref mut value, .. } = *Arc::make_mut(right) {
+                value.push(0);
+            }
+        }
+
+        assert!(!tree.verify_root().unwrap());
+    }
+
+    #[test]
+    fn rebuild_hashes_works() {
+        let mut tree = Tree::from_leaves(values(6)).unwrap();
+        let shared = tree.clone();
+
+        if let Tree::Node { ref mut right, .. } = tree {
+            if let Tree::Node { ref mut left, .. } = *Arc::make_mut(right) {
+                if let Tree::Leaf { ref mut value, .. } = *Arc::make_mut(left) {
+                    *value = b"edited".to_vec();
+                }
+            }
+        }
+        assert!(!tree.verify_root().unwrap());
+
+        let mut edited = values(6);
+        edited[4] = b"edited".to_vec();
+        let expected = Tree::from_leaves(edited).unwrap();
+
+        assert_eq!(&tree.rebuild_hashes().unwrap(), expected.hash());
+        assert_eq!(tree, expected);
+        assert_eq!(shared, Tree::from_leaves(values(6)).unwrap());
+    }
+
+    #[test]
+    fn rebuild_hashes_works_with_hasher() {
+        for count in 0..10 {
+            let mut tree = Tree::from_leaves(values(count)).unwrap();
+            tree.rebuild_hashes_with_hasher(&ReversedHasher {}).unwrap();
+            assert_eq!(tree, Tree::from_leaves_with_hasher(&ReversedHasher {}, values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn map_values_works_for_identity() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            assert_eq!(tree.clone().map_values(|value| value).unwrap(), tree);
+        }
+    }
+
+    #[test]
+    fn map_values_works() {
+        for count in 1..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let root = tree.hash().clone();
+
+            let mapped = tree.map_values(|mut value| {
+                value.insert(0, b'#');
+                value
+            }).unwrap();
+
+            let expected: Vec<TreeLeafData> = values(count).into_iter()
+                .map(|value| [b"#".to_vec(), value].concat())
+                .collect();
+
+            assert_ne!(mapped.hash(), &root);
+            assert_eq!(mapped, Tree::from_leaves(expected).unwrap());
+        }
+    }
+
+    #[test]
+    fn map_values_preserves_shape() {
+        let tree = Tree::merge(Tree::new_leaf(b"a".to_vec()).unwrap(), Tree::from_leaves(values(2)).unwrap()).unwrap();
+        let mapped = tree.clone().map_values(|_| b"b".to_vec()).unwrap();
+
+        assert_eq!(mapped.get_count(), 3);
+        assert_eq!(mapped.depth_of_leaf(0), Some(1));
+        assert_eq!(mapped.depth_of_leaf(2), Some(2));
+        assert_eq!(mapped.values(), vec![&b"b".to_vec(); 3]);
+        assert!(mapped.verify_root().unwrap());
+    }
+
+    #[test]
+    fn to_hash_only_works() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let skeleton = tree.to_hash_only();
+
+            assert_eq!(skeleton.hash(), tree.hash());
+            assert_eq!(skeleton.get_count(), count);
+            assert!(skeleton.iter().all(|value| value.is_empty()));
+            assert_eq!(skeleton.leaf_hashes().collect::<Vec<&[u8]>>(), tree.leaf_hashes().collect::<Vec<&[u8]>>());
+
+            for value in values(count) {
+                let proof = skeleton.gen_proof(value.clone()).unwrap().unwrap();
+                assert!(proof.validate_value(&value, tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn trees_work_as_hash_set_keys() {
+        let mut set = HashSet::new();
+
+        set.insert(Tree::from_leaves(values(3)).unwrap());
+        set.insert(Tree::from_leaves(values(3)).unwrap());
+        set.insert(Tree::from_leaves(values(4)).unwrap());
+
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&Tree::from_leaves(values(4)).unwrap()));
+        assert!(!set.contains(&Tree::from_leaves(values(5)).unwrap()));
+    }
+
+    #[test]
+    fn msgpack_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let serialized = tree.to_msgpack().unwrap();
+        assert_eq!(Tree::from_msgpack(&serialized).unwrap(), tree);
+
+        // hashes are packed as bin, not as arrays of numbers
+        assert!(serialized.len() < 9 * (32 + 16));
+    }
+
+    #[test]
+    fn deserialize_works_for_json_number_arrays() {
+        let tree = Tree::from_leaves(values(2)).unwrap();
+
+        let json = format!(r#"{{"Node":{{"hash":{:?},"left":{{"Leaf":{{"hash":{:?},"value":{:?}}}}},"right":{{"Leaf":{{"hash":{:?},"value":{:?}}}}}}}}}"#,
+                           tree.hash(),
+                           Tree::new_leaf(values(2)[0].clone()).unwrap().hash(), values(2)[0],
+                           Tree::new_leaf(values(2)[1].clone()).unwrap().hash(), values(2)[1]);
+
+        let deserialized: Tree = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, tree);
+        assert_eq!(serde_json::to_string(&tree).unwrap(), json.replace(" ", ""));
+    }
+
+    #[test]
+    fn deserialize_works_for_forged_cached_sizes() {
+        let tree = Tree::from_leaves(values(2)).unwrap();
+
+        let json = format!(r#"{{"Node":{{"hash":{:?},"left":{{"Leaf":{{"hash":{:?},"value":{:?}}}}},"right":{{"Leaf":{{"hash":{:?},"value":{:?}}}}},"count":1000,"height":50}}}}"#,
+                           tree.hash(),
+                           Tree::new_leaf(values(2)[0].clone()).unwrap().hash(), values(2)[0],
+                           Tree::new_leaf(values(2)[1].clone()).unwrap().hash(), values(2)[1]);
+
+        let deserialized: Tree = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, tree);
+        assert_eq!(deserialized.get_count(), 2);
+        assert_eq!(deserialized.get_height(), 1);
+    }
+
+    fn to_repr(tree: &Tree) -> TreeRepr {
+        match *tree {
+            Tree::Empty { ref hash } => TreeRepr::Empty { hash: hash.clone() },
+            Tree::Leaf { ref hash, ref value } => TreeRepr::Leaf { hash: hash.clone(), value: value.clone() },
+            Tree::Node { ref hash, ref left, ref right, .. } =>
+                TreeRepr::Node { hash: hash.clone(), left: Box::new(to_repr(left)), right: Box::new(to_repr(right)) }
+        }
+    }
+
+    #[test]
+    fn from_repr_recomputes_cached_sizes() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let rebuilt = Tree::from_repr(to_repr(&tree));
+
+            assert_eq!(rebuilt, tree);
+            assert_eq!(rebuilt.get_count(), count);
+            assert_eq!(rebuilt.get_height(), tree.get_height());
+        }
+    }
+
+    #[test]
+    fn to_json_works_for_multi_level_tree() {
+        let tree = Tree::from_leaves(values(7)).unwrap();
+        let json = tree.to_json().unwrap();
+
+        assert_eq!(json, serde_json::to_string(&tree).unwrap());
+        assert!(json.starts_with(r#"{"Node":{"#));
+        assert_eq!(Tree::try_decode_json(&json).unwrap(), tree);
+        assert!(Tree::try_decode_json(&json[1..]).is_err());
+    }
+
+    #[test]
+    fn compact_json_works() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let compact = tree.to_compact_json().unwrap();
+            let verbose = tree.to_json().unwrap();
+
+            assert_eq!(Tree::decode_compact_json(&compact).unwrap(), tree);
+            assert_eq!(Tree::try_decode_json(&verbose).unwrap(), tree);
+            assert!(compact.len() * 2 < verbose.len());
+        }
+    }
+
+    #[test]
+    fn decode_compact_json_works_for_invalid_base64() {
+        assert!(Tree::decode_compact_json(r#"{"Empty":{"hash":"not base64!"}}"#).is_err());
+        assert!(Tree::decode_compact_json(r#"{"Empty":{"hash":[1,2,3]}}"#).is_err());
+    }
+
+    #[test]
+    fn compact_tree_round_trips() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            assert_eq!(Tree::from_compact_tree(tree.to_compact_tree()).unwrap(), tree);
+        }
+    }
+
+    #[test]
+    fn decode_json_with_limit_works_for_deep_nesting() {
+        // 30 levels nest the JSON 63 deep, which serde_json still accepts
+        let mut tree = Tree::new_leaf(b"value0".to_vec()).unwrap();
+        for i in 1..31 {
+            tree = Tree::new_node(tree, Tree::new_leaf(format!("value{}", i).into_bytes()).unwrap()).unwrap();
+        }
+        let json = tree.to_json().unwrap();
+
+        assert_eq!(Tree::try_decode_json(&json).unwrap(), tree);
+        assert_eq!(Tree::decode_json_with_limit(&json, 30).unwrap(), tree);
+        assert!(Tree::decode_json_with_limit(&json, 29).is_err());
+        assert!(Tree::decode_json_with_limit(&json, 10).is_err());
+    }
+
+    #[test]
+    fn decode_json_checked_works_for_truncated_hash() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let json = tree.to_json().unwrap();
+        assert_eq!(Tree::decode_json_checked(&json, 4).unwrap(), tree);
+
+        let mut truncated = tree.clone();
+        if let Tree::Node { ref mut right, .. } = truncated {
+            *right = Arc::new(Tree::Leaf { hash: vec![0; HASHBYTES - 1], value: b"value4".to_vec() });
+        }
+        let json = truncated.to_json().unwrap();
+
+        assert!(Tree::try_decode_json(&json).is_ok());
+        assert!(Tree::decode_json_checked(&json, 4).is_err());
+
+        let json = Tree::from_leaves_with_hashes_with_hasher(&ShortHasher {}, vec![vec![1; 16], vec![2; 16]]).unwrap().to_json().unwrap();
+        assert!(Tree::decode_json_checked_with_hasher(&ShortHasher {}, &json, 4).is_ok());
+        assert!(Tree::decode_json_checked(&json, 4).is_err());
+    }
+
+    #[test]
+    fn decode_json_with_limit_works() {
+        let tree = Tree::from_leaves(values(9)).unwrap();
+        let json = serde_json::to_string(&tree).unwrap();
+
+        assert_eq!(Tree::decode_json_with_limit(&json, 4).unwrap(), tree);
+        assert!(Tree::decode_json_with_limit(&json, 3).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn par_from_leaves_works_same_as_from_leaves() {
+        // pseudo-random sizes and values, deterministic to be reproducible
+        let mut seed: u64 = 17;
+        for _ in 0..30 {
+            seed = (seed * 1103515245 + 12345) % (1 << 31);
+            let count = (seed % 300) as usize;
+            let leaves = (0..count).map(|i| format!("{}-{}", seed, i).into_bytes()).collect::<Vec<_>>();
+
+            assert_eq!(Tree::par_from_leaves(leaves.clone()).unwrap(), Tree::from_leaves(leaves).unwrap());
+        }
+
+        for count in 0..20 {
+            assert_eq!(Tree::par_from_leaves(values(count)).unwrap(), Tree::from_leaves(values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn root_hash_works() {
+        let tree = Tree::from_leaves(values(3)).unwrap();
+        let root_hash = tree.root_hash();
+
+        assert_eq!(root_hash.as_bytes(), tree.hash().as_slice());
+        assert_eq!(root_hash, Tree::from_leaves(values(3)).unwrap().root_hash());
+        assert_ne!(root_hash, Tree::from_leaves(values(4)).unwrap().root_hash());
+    }
+
+    #[test]
+    fn root_eq_ct_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        assert!(tree.root_eq_ct(tree.hash()));
+        assert!(!tree.root_eq_ct(other.hash()));
+        assert!(!tree.root_eq_ct(&tree.hash()[..16]));
+        assert!(!tree.root_eq_ct(&[]));
+    }
+
+    #[test]
+    fn hex_root_works() {
+        let tree = Tree::from_leaves(values(3)).unwrap();
+        assert_eq!(tree.hex_root(), tree.root_hash().to_hex());
+        assert_eq!(tree.hex_root().len(), 64);
+    }
+
+    #[test]
+    fn hex_root_works_for_empty() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+        assert_eq!(tree.hex_root(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn from_hex_leaves_works() {
+        let leaves = values(5).iter()
+            .map(|value| value.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+            .collect::<Vec<String>>();
+
+        assert_eq!(Tree::from_hex_leaves(&leaves).unwrap(), Tree::from_leaves(values(5)).unwrap());
+        assert_eq!(Tree::from_hex_leaves(&["00ff", "AB"]).unwrap(), Tree::from_leaves(vec![vec![0x00, 0xff], vec![0xab]]).unwrap());
+    }
+
+    #[test]
+    fn from_hex_leaves_works_for_invalid_hex() {
+        assert!(Tree::from_hex_leaves(&["0g"]).is_err());
+        assert!(Tree::from_hex_leaves(&["abc"]).is_err());
+    }
+
+    #[test]
+    fn base58_root_works() {
+        let tree = Tree::from_leaves(values(3)).unwrap();
+        assert_eq!(base58::decode(&tree.base58_root()).unwrap(), *tree.hash());
+    }
+
+    #[test]
+    fn base58_root_works_for_leading_zero() {
+        // the leaf hash of "value539" starts with a zero byte
+        let tree = Tree::from_leaves(vec![b"value539".to_vec()]).unwrap();
+        assert_eq!(tree.hash()[0], 0);
+        assert!(tree.base58_root().starts_with('1'));
+        assert_eq!(base58::decode(&tree.base58_root()).unwrap(), *tree.hash());
+    }
+
+    #[test]
+    fn from_base58_leaves_works() {
+        let leaves = values(5).iter().map(|value| base58::encode(value)).collect::<Vec<String>>();
+        assert_eq!(Tree::from_base58_leaves(&leaves).unwrap(), Tree::from_leaves(values(5)).unwrap());
+    }
+
+    #[test]
+    fn from_base58_leaves_works_for_leading_zeros() {
+        assert_eq!(base58::encode(&[0, 0, 1]), "112");
+
+        let tree = Tree::from_base58_leaves(&["112", "1", "11"]).unwrap();
+        assert_eq!(tree, Tree::from_leaves(vec![vec![0, 0, 1], vec![0], vec![0, 0]]).unwrap());
+    }
+
+    #[test]
+    fn from_base58_leaves_works_for_invalid_base58() {
+        assert!(Tree::from_base58_leaves(&["0OIl"]).is_err());
+    }
+
+    #[test]
+    fn get_height_works() {
+        for count in 1..18 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let expected = (0..).find(|height| 1 << height >= count).unwrap();
+
+            assert_eq!(tree.get_height(), expected);
+            assert!(!tree.is_empty());
+        }
+    }
+
+    #[test]
+    fn is_empty_works() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+        assert!(tree.is_empty());
+        assert_eq!(tree.get_height(), 0);
+
+        let tree = Tree::from_leaves(values(1)).unwrap();
+        assert!(!tree.is_empty());
+        assert_eq!(tree.get_height(), 0);
+    }
+
+    #[test]
+    fn get_leaf_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (i, value) in tree.iter().enumerate() {
+                assert_eq!(tree.get_leaf(i), Some(value));
+            }
+            assert_eq!(tree.get_leaf(count), None);
+        }
+    }
+
+    #[test]
+    fn leaf_index_works() {
+        let mut leaves = values(7);
+        leaves.push(b"value3".to_vec());
+        let tree = Tree::from_leaves(leaves).unwrap();
+
+        assert_eq!(tree.leaf_index(&b"value0".to_vec()), Some(0));
+        assert_eq!(tree.leaf_index(&b"value3".to_vec()), Some(3));
+        assert_eq!(tree.leaf_index(&b"value6".to_vec()), Some(6));
+        assert_eq!(tree.leaf_index(&b"unknown".to_vec()), None);
+    }
+
+    #[test]
+    fn leaf_index_by_hash_works() {
+        let mut leaves = values(7);
+        leaves.push(b"value3".to_vec());
+        let tree = Tree::from_leaves(leaves).unwrap();
+
+        for i in 0..7 {
+            let leaf_hash = Hash::hash_leaf(&values(7)[i]).unwrap().to_vec();
+            assert_eq!(tree.leaf_index_by_hash(&leaf_hash), Some(i));
+        }
+
+        let unknown_hash = Hash::hash_leaf(&b"unknown".to_vec()).unwrap().to_vec();
+        assert_eq!(tree.leaf_index_by_hash(&unknown_hash), None);
+        assert_eq!(Tree::from_leaves(vec![]).unwrap().leaf_index_by_hash(&unknown_hash), None);
+    }
+
+    #[test]
+    fn contains_works() {
+        let mut leaves = values(5);
+        leaves.push(b"value1".to_vec());
+        let tree = Tree::from_leaves(leaves).unwrap();
+
+        assert!(tree.contains(&b"value0".to_vec()).unwrap());
+        assert!(tree.contains(&b"value1".to_vec()).unwrap());
+        assert!(tree.contains(&b"value4".to_vec()).unwrap());
+        assert!(!tree.contains(&b"value5".to_vec()).unwrap());
+        assert!(!Tree::from_leaves(vec![]).unwrap().contains(&b"value0".to_vec()).unwrap());
+    }
+
+    #[test]
+    fn subtree_at_path_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        assert_eq!(tree.subtree_at_path(&[]), Some(&tree));
+
+        let left = tree.subtree_at_path(&[Positioned::Left(())]).unwrap();
+        assert_eq!(left.iter().cloned().collect::<Vec<TreeLeafData>>(), values(4));
+
+        let leaf = tree.subtree_at_path(&[Positioned::Left(()), Positioned::Right(()), Positioned::Left(())]).unwrap();
+        assert_eq!(leaf, &Tree::new_leaf(b"value2".to_vec()).unwrap());
+
+        let leaf = tree.subtree_at_path(&[Positioned::Right(())]).unwrap();
+        assert_eq!(leaf, &Tree::new_leaf(b"value4".to_vec()).unwrap());
+    }
+
+    #[test]
+    fn subtree_at_path_works_for_path_past_leaf() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert_eq!(tree.subtree_at_path(&[Positioned::Right(()), Positioned::Left(())]), None);
+
+        let empty = Tree::from_leaves(vec![]).unwrap();
+        assert_eq!(empty.subtree_at_path(&[]), None);
+        assert_eq!(empty.subtree_at_path(&[Positioned::Left(())]), None);
+    }
+
+    #[test]
+    fn diff_works_for_identical_trees() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            assert!(tree.diff(&tree.clone()).is_empty());
+        }
+    }
+
+    #[test]
+    fn diff_works_for_disjoint_trees() {
+        let tree = Tree::from_leaves(values(7)).unwrap();
+        let other = Tree::from_leaves((0..7).map(|i| format!("other{}", i).into_bytes()).collect()).unwrap();
+
+        assert_eq!(tree.diff(&other), (0..7).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn diff_works_for_changed_leaves() {
+        let tree = Tree::from_leaves(values(11)).unwrap();
+
+        let mut changed = values(11);
+        changed[2].push(0);
+        changed[9].push(0);
+        let other = Tree::from_leaves(changed).unwrap();
+
+        assert_eq!(tree.diff(&other), vec![2, 9]);
+        assert_eq!(other.diff(&tree), vec![2, 9]);
+    }
+
+    #[test]
+    fn diff_works_for_different_counts() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(9)).unwrap();
+
+        assert_eq!(tree.diff(&other), vec![5, 6, 7, 8]);
+        assert_eq!(other.diff(&tree), vec![5, 6, 7, 8]);
+        assert_eq!(Tree::from_leaves(vec![]).unwrap().diff(&tree), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn depth_of_leaf_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        assert_eq!(tree.depth_of_leaf(0), Some(3));
+        assert_eq!(tree.depth_of_leaf(3), Some(3));
+        assert_eq!(tree.depth_of_leaf(4), Some(1));
+        assert_eq!(tree.depth_of_leaf(5), None);
+
+        assert_eq!(Tree::from_leaves(values(1)).unwrap().depth_of_leaf(0), Some(0));
+        assert_eq!(Tree::from_leaves(vec![]).unwrap().depth_of_leaf(0), None);
+    }
+
+    #[test]
+    fn to_level_hashes_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        assert_eq!(tree.to_level_hashes(0), vec![tree.hash().clone()]);
+
+        let leaf4 = Tree::new_leaf(b"value4".to_vec()).unwrap().hash().clone();
+        let first4 = Tree::from_leaves(values(4)).unwrap().hash().clone();
+        assert_eq!(tree.to_level_hashes(1), vec![first4, leaf4.clone()]);
+
+        let leaf_hashes = tree.leaf_hashes().map(|hash| hash.to_vec()).collect::<Vec<Vec<u8>>>();
+        assert_eq!(tree.to_level_hashes(2).len(), 3);
+        assert_eq!(tree.to_level_hashes(2)[2], leaf4);
+        assert_eq!(tree.to_level_hashes(3), leaf_hashes);
+        assert_eq!(tree.to_level_hashes(10), leaf_hashes);
+
+        let empty = Tree::from_leaves(vec![]).unwrap();
+        assert_eq!(empty.to_level_hashes(2), vec![empty.hash().clone()]);
+    }
+
+    #[test]
+    fn assert_balanced_works() {
+        for count in 0..20 {
+            Tree::from_leaves(values(count)).unwrap().assert_balanced();
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn assert_balanced_fails_for_unbalanced_tree() {
+        let left = Tree::from_leaves(values(1)).unwrap();
+        let right = Tree::from_leaves(values(2)).unwrap();
+
+        Tree::new_node(left, right).unwrap().assert_balanced();
+    }
+
+    #[test]
+    fn push_works_same_as_from_leaves() {
+        let mut tree = Tree::from_leaves(vec![]).unwrap();
+
+        for count in 1..40 {
+            tree.push(values(count).remove(count - 1)).unwrap();
+            assert_eq!(tree, Tree::from_leaves(values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn append_subtree_works_same_as_from_leaves() {
+        for count in 0..24 {
+            for other_count in 0..24 {
+                let all = values(count + other_count);
+
+                let mut tree = Tree::from_leaves(all[..count].to_vec()).unwrap();
+                tree.append_subtree(Tree::from_leaves(all[count..].to_vec()).unwrap()).unwrap();
+
+                assert_eq!(tree, Tree::from_leaves(all).unwrap());
+                tree.assert_balanced();
+            }
+        }
+    }
+
+    #[test]
+    fn append_subtree_works_with_hasher() {
+        let all = values(13);
+
+        let mut tree = Tree::from_leaves_with_hasher(&ReversedHasher {}, all[..5].to_vec()).unwrap();
+        let other = Tree::from_leaves_with_hasher(&ReversedHasher {}, all[5..].to_vec()).unwrap();
+        tree.append_subtree_with_hasher(&ReversedHasher {}, other).unwrap();
+
+        assert_eq!(tree, Tree::from_leaves_with_hasher(&ReversedHasher {}, all).unwrap());
+    }
+
+    #[test]
+    fn append_subtree_shares_aligned_subtrees() {
+        let mut tree = Tree::from_leaves(values(8)).unwrap();
+        let other = Tree::from_leaves(values(16)[8..].to_vec()).unwrap();
+        tree.append_subtree(other.clone()).unwrap();
+
+        match (&tree, &other) {
+            (&Tree::Node { ref right, .. }, &Tree::Node { left: ref b, .. }) => match **right {
+                Tree::Node { left: ref a, .. } => assert!(Arc::ptr_eq(a, b)),
+                _ => panic!("expected nodes")
+            },
+            _ => panic!("expected nodes")
+        }
+    }
+
+    #[test]
+    fn append_subtree_fails_for_other_digest_size() {
+        let mut tree = Tree::from_leaves(values(3)).unwrap();
+        let other = Tree::from_leaves_with_hasher(&ShortHasher {}, values(2)).unwrap();
+
+        assert!(tree.append_subtree(other).is_err());
+        assert_eq!(tree, Tree::from_leaves(values(3)).unwrap());
+    }
+
+    #[test]
+    fn push_keeps_cached_sizes() {
+        let mut tree = Tree::from_leaves(vec![]).unwrap();
+
+        for count in 1..18 {
+            tree.push(format!("value{}", count - 1).into_bytes()).unwrap();
+            assert_eq!(tree.get_count(), count);
+            assert_eq!(tree.get_height(), Tree::from_leaves(values(count)).unwrap().get_height());
+            assert!(tree.verify_root().unwrap());
+        }
+    }
+
+    #[test]
+    fn extend_works_same_as_from_leaves() {
+        for start in 0..18 {
+            for added in 0..18 {
+                let mut tree = Tree::from_leaves(values(start)).unwrap();
+                tree.extend(values(start + added).into_iter().skip(start)).unwrap();
+
+                let expected = Tree::from_leaves(values(start + added)).unwrap();
+                assert_eq!(tree, expected);
+                assert!(tree.verify_root().unwrap());
+            }
+        }
+    }
+
+    /// Compares `extend` of 10k values with 10k calls to `push`, run it with
+    /// `cargo test measure_extend_against_push -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn measure_extend_against_push() {
+        let count = 10_000;
+
+        let start = Instant::now();
+        let mut pushed = Tree::from_leaves(vec![]).unwrap();
+        for value in values(count) {
+            pushed.push(value).unwrap();
+        }
+        let push_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut extended = Tree::from_leaves(vec![]).unwrap();
+        extended.extend(values(count)).unwrap();
+        let extend_time = start.elapsed();
+
+        assert_eq!(extended, pushed);
+
+        println!("push:   {} values in {:?}", count, push_time);
+        println!("extend: {} values in {:?}", count, extend_time);
+    }
+
+    #[test]
+    fn push_works_for_power_of_two_boundaries() {
+        for &count in [1, 2, 4, 8, 16].iter() {
+            let mut tree = Tree::from_leaves(values(count)).unwrap();
+            tree.push(values(count + 1).remove(count)).unwrap();
+
+            assert_eq!(tree, Tree::from_leaves(values(count + 1)).unwrap());
+            assert_eq!(tree.depth_of_leaf(count), Some(1));
+        }
+    }
+
+    #[test]
+    fn truncate_works_same_as_historical_roots() {
+        let mut tree = Tree::from_leaves(vec![]).unwrap();
+        let mut roots = vec![tree.hash().clone()];
+
+        for value in values(40) {
+            tree.push(value).unwrap();
+            roots.push(tree.hash().clone());
+        }
+
+        for (size, root) in roots.iter().enumerate() {
+            let truncated = tree.truncate(size).unwrap();
+            assert_eq!(truncated.hash(), root);
+            assert_eq!(truncated, Tree::from_leaves(values(size)).unwrap());
+            assert!(truncated.verify_root().unwrap());
+        }
+    }
+
+    #[test]
+    fn truncate_works_for_too_big_size() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert!(tree.truncate(6).is_err());
+    }
+
+    #[test]
+    fn truncate_works_with_hasher() {
+        let tree = Tree::from_leaves_with_hasher(&ReversedHasher {}, values(11)).unwrap();
+
+        for size in 0..12 {
+            assert_eq!(tree.truncate_with_hasher(&ReversedHasher {}, size).unwrap(),
+                       Tree::from_leaves_with_hasher(&ReversedHasher {}, values(size)).unwrap());
+        }
+    }
+
+    #[test]
+    fn is_perfect_works() {
+        for count in 0..40 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            assert_eq!(tree.is_perfect(), count.is_power_of_two(), "count {}", count);
+        }
+    }
+
+    #[test]
+    fn is_complete_works() {
+        for count in 0..40 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let depths = (0..count).map(|i| tree.depth_of_leaf(i).unwrap()).collect::<Vec<usize>>();
+
+            // the deepest leaves come first and are at most one level deeper than the others
+            let expected = depths.windows(2).all(|pair| pair[0] >= pair[1])
+                && depths.first().cloned().unwrap_or(0) <= depths.last().cloned().unwrap_or(0) + 1;
+
+            assert_eq!(tree.is_complete(), expected, "count {}", count);
+            assert!(!tree.is_perfect() || tree.is_complete());
+        }
+    }
+
+    #[test]
+    fn is_complete_works_for_right_heavy_tree() {
+        let right = Tree::from_leaves(values(2)).unwrap();
+        let tree = Tree::new_node(Tree::new_leaf(b"value".to_vec()).unwrap(), right).unwrap();
+
+        assert!(!tree.is_complete());
+        assert!(!tree.is_perfect());
+    }
+
+    #[test]
+    fn replace_leaf_works_same_as_rebuild() {
+        // deterministic pseudo-random positions and values
+        let mut seed: u64 = 42;
+        let mut next = move |bound: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % bound
+        };
+
+        for count in 1..20 {
+            let mut leaves = values(count);
+            let mut tree = Tree::from_leaves(leaves.clone()).unwrap();
+            let shared = tree.clone();
+
+            for _ in 0..10 {
+                let index = next(count);
+                let value = format!("replaced{}", next(1000)).into_bytes();
+                leaves[index] = value.clone();
+
+                let root = tree.replace_leaf(index, value).unwrap().unwrap();
+                let rebuilt = Tree::from_leaves(leaves.clone()).unwrap();
+
+                assert_eq!(&root, rebuilt.hash());
+                assert_eq!(tree, rebuilt);
+            }
+
+            assert_eq!(shared, Tree::from_leaves(values(count)).unwrap());
+        }
+    }
+
+    #[test]
+    fn replace_leaf_works_for_out_of_range_index() {
+        let mut tree = Tree::from_leaves(values(5)).unwrap();
+        assert_eq!(tree.replace_leaf(5, b"value".to_vec()).unwrap(), None);
+        assert_eq!(tree, Tree::from_leaves(values(5)).unwrap());
+
+        let mut empty = Tree::from_leaves(vec![]).unwrap();
+        assert_eq!(empty.replace_leaf(0, b"value".to_vec()).unwrap(), None);
+    }
+
+    #[test]
+    fn replace_leaf_works_with_hasher() {
+        let mut tree = Tree::from_leaves_with_hasher(&ReversedHasher {}, values(7)).unwrap();
+        let mut leaves = values(7);
+        leaves[3] = b"replaced".to_vec();
+
+        tree.replace_leaf_with_hasher(&ReversedHasher {}, 3, b"replaced".to_vec()).unwrap();
+        assert_eq!(tree, Tree::from_leaves_with_hasher(&ReversedHasher {}, leaves).unwrap());
+    }
+
+    #[test]
+    fn common_ancestor_hash_works() {
+        let tree = Tree::from_leaves(values(7)).unwrap();
+
+        assert_eq!(tree.common_ancestor_hash(0, 6), Some(tree.hash().as_slice()));
+        assert_eq!(tree.common_ancestor_hash(3, 4), Some(tree.hash().as_slice()));
+
+        // leaves 0 to 3 are under the left child, and 0 and 1 under its left child
+        let left = tree.subtree_at_path(&[Positioned::Left(())]).unwrap();
+        let left_left = tree.subtree_at_path(&[Positioned::Left(()), Positioned::Left(())]).unwrap();
+        assert_eq!(tree.common_ancestor_hash(0, 3), Some(left.hash().as_slice()));
+        assert_eq!(tree.common_ancestor_hash(1, 0), Some(left_left.hash().as_slice()));
+
+        let leaf_hash = Hash::hash_leaf(&values(7)[5]).unwrap().to_vec();
+        assert_eq!(tree.common_ancestor_hash(5, 5), Some(leaf_hash.as_slice()));
+    }
+
+    #[test]
+    fn common_ancestor_hash_works_same_as_merged_proofs() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for i in 0..count {
+                for j in 0..count {
+                    // the ancestor is the deepest node shared by the audit paths
+                    let path_i = Lemma::new_by_index(&tree, i).unwrap();
+                    let path_j = Lemma::new_by_index(&tree, j).unwrap();
+
+                    let (mut lemma_i, mut lemma_j) = (&path_i, &path_j);
+                    let mut expected = lemma_i.node_hash.clone();
+
+                    while lemma_i.node_hash == lemma_j.node_hash {
+                        expected = lemma_i.node_hash.clone();
+                        match (&lemma_i.sub_lemma, &lemma_j.sub_lemma) {
+                            (&Some(ref sub_i), &Some(ref sub_j)) => {
+                                lemma_i = sub_i;
+                                lemma_j = sub_j;
+                            }
+                            _ => break
+                        }
+                    }
+
+                    assert_eq!(tree.common_ancestor_hash(i, j), Some(expected.as_slice()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn common_ancestor_hash_works_for_out_of_range_index() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert_eq!(tree.common_ancestor_hash(1, 5), None);
+        assert_eq!(tree.common_ancestor_hash(5, 1), None);
+        assert_eq!(Tree::from_leaves(vec![]).unwrap().common_ancestor_hash(0, 0), None);
+    }
+
+    #[test]
+    fn prune_to_proof_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let pruned = tree.prune_to_proof(index).unwrap();
+
+                assert_eq!(pruned.hash(), tree.hash());
+                assert_eq!(pruned.get_count(), count);
+                assert_eq!(pruned.get_height(), tree.get_height());
+                assert_eq!(pruned.get_leaf(index), Some(&value));
+                assert_eq!(pruned.depth_of_leaf(index), tree.depth_of_leaf(index));
+                assert_eq!(pruned.audit_path(index), tree.audit_path(index));
+                assert_eq!(pruned.node_count(), tree.depth_of_leaf(index).unwrap());
+                assert!(pruned.verify_root().unwrap());
+
+                assert_eq!(pruned.iter().collect::<Vec<_>>(), vec![&value]);
+                assert_eq!(pruned.iter().rev().collect::<Vec<_>>(), vec![&value]);
+                assert_eq!(pruned.iter().len(), 1);
+                assert_eq!(pruned.iter_with_hashes().len(), 1);
+                assert_eq!(pruned.leaves_in_range(index..count).collect::<Vec<_>>(), vec![&value]);
+                assert_eq!(pruned.leaves_in_range(0..index).len(), 0);
+                assert_eq!(pruned.clone().into_iter().collect::<Vec<_>>(), vec![value.clone()]);
+                assert_eq!(pruned.build_proof_index().position(&Hash::hash_leaf(&value).unwrap().to_vec()), Some(index));
+
+                let proof = pruned.gen_proof(value.clone()).unwrap().unwrap();
+                assert_eq!(proof, tree.gen_proof_by_index(index).unwrap());
+                assert!(proof.validate(tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn prune_to_proof_works_for_out_of_range_index() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert!(tree.prune_to_proof(5).is_none());
+        assert!(Tree::from_leaves(vec![]).unwrap().prune_to_proof(0).is_none());
+    }
+
+    #[test]
+    fn verify_root_works_for_pruned_tree_with_forged_leaf() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        // the last leaf is right under the root
+        let mut forged = tree.prune_to_proof(4).unwrap();
+        if let Tree::Node { ref mut right, .. } = forged {
+            let hash = right.hash().clone();
+            *Arc::make_mut(right) = Tree::Leaf { hash: hash, value: b"forged".to_vec() };
+        }
+        assert_eq!(forged.hash(), tree.hash());
+        assert!(!forged.verify_root().unwrap());
+
+        let mut replaced = tree.prune_to_proof(4).unwrap();
+        assert_eq!(replaced.replace_leaf(0, b"forged".to_vec()).unwrap(), None);
+        assert!(replaced.replace_leaf(4, b"forged".to_vec()).unwrap().is_some());
+        assert!(replaced.verify_root().unwrap());
+        assert_ne!(replaced.hash(), tree.hash());
+    }
+
+    #[test]
+    fn split_at_works() {
+        for count in 0..14 {
+            for index in 0..count + 1 {
+                let (left, right) = Tree::from_leaves(values(count)).unwrap().split_at(index).unwrap();
+
+                assert_eq!(left, Tree::from_leaves(values(count)[..index].to_vec()).unwrap());
+                assert_eq!(right, Tree::from_leaves(values(count)[index..].to_vec()).unwrap());
+
+                let mut leaves = left.into_values();
+                leaves.extend(right.into_values());
+                assert_eq!(leaves, values(count));
+            }
+        }
+    }
+
+    #[test]
+    fn split_at_works_for_merge() {
+        let tree = Tree::from_leaves(values(6)).unwrap();
+
+        let (left, right) = tree.clone().split_at(4).unwrap();
+        assert_eq!(Tree::merge(left, right).unwrap(), tree);
+
+        let (left, right) = tree.clone().split_at(2).unwrap();
+        assert_ne!(Tree::merge(left, right).unwrap().hash(), tree.hash());
+    }
+
+    #[test]
+    fn split_at_works_for_too_big_index() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert!(tree.split_at(6).is_err());
+    }
+
+    #[test]
+    fn root_with_prefix_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        let pool = tree.root_with_prefix(b"pool").unwrap();
+        let domain = tree.root_with_prefix(b"domain").unwrap();
+
+        assert_eq!(pool, tree.root_with_prefix(b"pool").unwrap());
+        assert_ne!(pool, domain);
+        assert_ne!(&pool, tree.hash());
+        assert_ne!(&tree.root_with_prefix(b"").unwrap(), tree.hash());
+    }
+
+    #[test]
+    fn root_with_prefix_works_for_shifted_tags() {
+        // the tag and the root can't trade bytes to collide
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let other = Tree::from_leaves(values(6)).unwrap();
+
+        assert_ne!(tree.root_with_prefix(b"config").unwrap(), other.root_with_prefix(b"config").unwrap());
+        assert_ne!(tree.root_with_prefix(b"ab").unwrap(), tree.root_with_prefix(b"a").unwrap());
+        assert_ne!(tree.root_with_prefix(b"domain").unwrap(), tree.root_with_prefix(b"domaim").unwrap());
+    }
+
+    #[test]
+    fn from_leaves_tagged_works() {
+        let (tree, tagged) = Tree::from_leaves_tagged(&Hash {}, b"domain", values(5)).unwrap();
+
+        assert_eq!(tree, Tree::from_leaves(values(5)).unwrap());
+        assert_eq!(tagged, tree.root_with_prefix(b"domain").unwrap());
+        assert_ne!(&tagged, tree.hash());
+    }
+
+    #[test]
+    fn from_leaves_tagged_works_for_other_hashers() {
+        let hasher = Sha512Hash {};
+        let (tree, tagged) = Tree::from_leaves_tagged(&hasher, b"domain", values(5)).unwrap();
+        let (_, pool) = Tree::from_leaves_tagged(&hasher, b"pool", values(5)).unwrap();
+
+        assert_eq!(tagged.len(), hasher.digest_len());
+        assert_eq!(tagged, tree.root_with_prefix_with_hasher(&hasher, b"domain").unwrap());
+        assert_ne!(tagged, pool);
+        assert_ne!(&tagged, tree.hash());
+
+        // the tag is hashed with SHA-512 too
+        assert_ne!(tagged, tree.root_with_prefix(b"domain").unwrap());
+
+        for value in values(5) {
+            let proof = tree.gen_proof_with_hasher(&hasher, value).unwrap().unwrap();
+            assert!(proof.validate_tagged_with_hasher(&hasher, b"domain", &tagged).unwrap());
+            assert!(!proof.validate_tagged_with_hasher(&hasher, b"pool", &tagged).unwrap());
+            assert!(!proof.validate_tagged_with_hasher(&Hash {}, b"domain", &tagged).unwrap());
+        }
+    }
+
+    #[test]
+    fn stats_works() {
+        for count in 0..20 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let stats = tree.stats();
+
+            assert_eq!(stats.leaves, count);
+            assert_eq!(stats.nodes, count.saturating_sub(1));
+            assert_eq!(stats.empties, if count == 0 { 1 } else { 0 });
+            assert_eq!(stats.height, tree.get_height());
+            assert!(stats.balanced);
+
+            assert_eq!(tree.node_count(), stats.nodes);
+            assert_eq!(tree.total_node_count(), tree.nodes().count());
+        }
+    }
+
+    #[test]
+    fn stats_works_for_unbalanced_tree() {
+        let tree = Tree::merge(Tree::new_leaf(b"a".to_vec()).unwrap(), Tree::from_leaves(values(2)).unwrap()).unwrap();
+        assert_eq!(tree.stats(), TreeStats { leaves: 3, nodes: 2, empties: 0, height: 2, balanced: false });
+
+        let tree = Tree::new_node(Tree::new_leaf(b"a".to_vec()).unwrap(), Tree::from_leaves(vec![]).unwrap()).unwrap();
+        assert_eq!(tree.stats(), TreeStats { leaves: 1, nodes: 1, empties: 1, height: 1, balanced: false });
+        assert_eq!(tree.total_node_count(), 3);
+    }
+
+    #[test]
+    fn from_leaves_bounded_works() {
+        for count in 0..10 {
+            assert_eq!(Tree::from_leaves_bounded(values(count), 9).unwrap(), Tree::from_leaves(values(count)).unwrap());
+        }
+
+        assert!(Tree::from_leaves_bounded(values(10), 9).is_err());
+        assert!(Tree::from_leaves_bounded(values(1), 0).is_err());
+        assert!(Tree::from_leaves_bounded(vec![], 0).is_ok());
+    }
+
+    #[test]
+    fn from_leaves_bounded_works_for_endless_values() {
+        let endless = (0..).map(|i: usize| format!("value{}", i).into_bytes());
+        assert!(Tree::from_leaves_bounded(endless, 9).is_err());
+
+        let endless = (0..).map(|i: usize| format!("value{}", i).into_bytes());
+        assert_eq!(Tree::from_leaves_bounded(endless.take(9), 9).unwrap(), Tree::from_leaves(values(9)).unwrap());
+    }
+
+    #[test]
+    fn find_by_predicate_works() {
+        let tree = Tree::from_leaves(values(12)).unwrap();
+
+        let (index, proof) = tree.find_by_predicate(|value| value.ends_with(b"1")).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(proof.value, b"value1".to_vec());
+        assert!(tree.verify_proof(&proof).unwrap());
+
+        let (index, proof) = tree.find_by_predicate(|value| value.len() > 6).unwrap();
+        assert_eq!(index, 10);
+        assert!(proof.validate(tree.hash()).unwrap());
+
+        assert!(tree.find_by_predicate(|value| value.is_empty()).is_none());
+        assert!(Tree::from_leaves(vec![]).unwrap().find_by_predicate(|_| true).is_none());
+    }
+
+    #[test]
+    fn concat_roots_works() {
+        for count in 0..12 {
+            let roots = (0..count)
+                .map(|i| Tree::from_leaves(values(i + 1)).unwrap().hash().clone())
+                .collect::<Vec<Vec<u8>>>();
+            let aggregate = Tree::concat_roots(&roots).unwrap();
+
+            assert_eq!(aggregate, Tree::from_leaves_with_hashes(roots.clone()).unwrap());
+
+            for (index, root) in roots.iter().enumerate() {
+                let path = aggregate.gen_root_proof(index).unwrap();
+                assert!(verify_root_inclusion(root, index, count, &path, aggregate.hash()).unwrap());
+                if count > 1 {
+                    let other = &roots[(index + 1) % count];
+                    assert!(!verify_root_inclusion(other, index, count, &path, aggregate.hash()).unwrap());
+                }
+            }
+
+            assert_eq!(aggregate.gen_root_proof(count), None);
+        }
+    }
+
+    #[test]
+    fn concat_roots_works_for_other_hasher_and_invalid_roots() {
+        let roots = vec![vec![1; 16], vec![2; 16], vec![3; 16]];
+        let aggregate = Tree::concat_roots_with_hasher(&ShortHasher {}, &roots).unwrap();
+        let path = aggregate.gen_root_proof(1).unwrap();
+
+        assert!(verify_root_inclusion_with_hasher(&ShortHasher {}, &roots[1], 1, 3, &path, aggregate.hash()).unwrap());
+        assert!(!verify_root_inclusion_with_hasher(&ShortHasher {}, &roots[0], 1, 3, &path, aggregate.hash()).unwrap());
+
+        assert!(Tree::concat_roots(&roots).is_err());
+        assert!(Tree::concat_roots(&[vec![0; 32], vec![0; 31]]).is_err());
+    }
+
+    #[test]
+    fn clone_shares_subtrees() {
+        let tree = Tree::from_leaves(values(6)).unwrap();
+        let cloned = tree.clone();
+
+        match (&tree, &cloned) {
+            (&Tree::Node { left: ref a, .. }, &Tree::Node { left: ref b, .. }) => assert!(Arc::ptr_eq(a, b)),
+            _ => panic!("expected nodes")
+        }
+    }
+
+    #[test]
+    fn clone_shallow_works() {
+        let tree = Tree::from_leaves(values(6)).unwrap();
+        let shallow = tree.clone_shallow();
+
+        assert_eq!(shallow, tree);
+        assert_eq!(shallow.values(), values(6).iter().collect::<Vec<_>>());
+        assert!(shallow.gen_proof(b"value5".to_vec()).unwrap().unwrap().validate(tree.hash()).unwrap());
+
+        match (&tree, &shallow) {
+            (&Tree::Node { left: ref a, right: ref b, .. }, &Tree::Node { left: ref c, right: ref d, .. }) => {
+                assert!(Arc::ptr_eq(a, c));
+                assert!(Arc::ptr_eq(b, d));
+            }
+            _ => panic!("expected nodes")
+        }
+    }
+
+    #[test]
+    fn push_does_not_affect_shared_versions() {
+        let old = Tree::from_leaves(values(6)).unwrap();
+        let mut new = old.clone();
+        new.push(b"value6".to_vec()).unwrap();
+
+        assert_eq!(old, Tree::from_leaves(values(6)).unwrap());
+        assert_eq!(new, Tree::from_leaves(values(7)).unwrap());
+
+        match (&old, &new) {
+            (&Tree::Node { left: ref a, .. }, &Tree::Node { left: ref b, .. }) => assert!(Arc::ptr_eq(a, b)),
+            _ => panic!("expected nodes")
+        }
+    }
+
+    #[test]
+    fn into_iter_works_for_shared_tree() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let cloned = tree.clone();
+
+        assert_eq!(cloned.into_iter().collect::<Vec<TreeLeafData>>(), values(5));
+        assert_eq!(tree.iter().cloned().collect::<Vec<TreeLeafData>>(), values(5));
+    }
+
+    #[test]
+    fn gen_proof_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for value in values(count) {
+                let proof = tree.gen_proof(value).unwrap().unwrap();
+                assert!(proof.validate(tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn audit_path_works_same_as_gen_proof() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let mut expected = Vec::new();
+                let mut lemma = tree.gen_proof(value).unwrap().unwrap().lemma;
+                while let Some(sub) = lemma.sub_lemma {
+                    expected.push(lemma.sibling_hash.unwrap());
+                    lemma = *sub;
+                }
+                expected.reverse();
+
+                assert_eq!(tree.audit_path(index).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn audit_path_works_for_out_of_range() {
+        assert_eq!(Tree::from_leaves(values(5)).unwrap().audit_path(5), None);
+        assert_eq!(Tree::from_leaves(vec![]).unwrap().audit_path(0), None);
+        assert_eq!(Tree::from_leaves(values(1)).unwrap().audit_path(0), Some(vec![]));
+    }
+
+    #[test]
+    fn gen_proof_works_for_unknown_value() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert!(tree.gen_proof(b"unknown".to_vec()).unwrap().is_none());
+        assert!(Tree::from_leaves(vec![]).unwrap().gen_proof(b"unknown".to_vec()).unwrap().is_none());
+    }
+
+    #[test]
+    fn try_gen_proof_works() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        for value in values(5) {
+            let proof = tree.try_gen_proof(value.clone()).unwrap();
+            assert_eq!(proof.value, value);
+            assert!(proof.validate(tree.hash()).unwrap());
+        }
+    }
+
+    #[test]
+    fn try_gen_proof_works_for_unknown_value() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        match tree.try_gen_proof(b"unknown".to_vec()) {
+            Err(ProofError::ValueNotFound) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn try_gen_proof_works_for_empty_tree() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+
+        match tree.try_gen_proof(b"value0".to_vec()) {
+            Err(ProofError::EmptyTree) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn gen_proof_by_index_works() {
+        for count in 1..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for (index, value) in values(count).into_iter().enumerate() {
+                let proof = tree.gen_proof_by_index(index).unwrap();
+                assert_eq!(proof.lemma, tree.gen_proof(value.clone()).unwrap().unwrap().lemma);
+                assert_eq!(proof.value, value);
+                assert!(proof.validate(tree.hash()).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn gen_proof_by_index_works_for_duplicate_values() {
+        let tree = Tree::from_leaves(vec![b"dup".to_vec(), b"other".to_vec(), b"dup".to_vec()]).unwrap();
+
+        let proof = tree.gen_proof_by_index(2).unwrap();
+        assert!(proof.validate(tree.hash()).unwrap());
+        assert_eq!(proof.to_compact(2, 3).unwrap().len(), 8 + 32);
+    }
+
+    #[test]
+    fn gen_proof_by_index_works_for_out_of_range() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        match tree.gen_proof_by_index(5) {
+            Err(ProofError::IndexOutOfRange(5, 5)) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn gen_proof_by_index_works_for_empty_tree() {
+        match Tree::from_leaves(vec![]).unwrap().gen_proof_by_index(0) {
+            Err(ProofError::EmptyTree) => (),
+            res => panic!("unexpected result {:?}", res)
+        }
+    }
+
+    #[test]
+    fn leaves_iterators_report_exact_size() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let mut iter = tree.iter();
+            for remaining in (0..count).rev() {
+                assert!(iter.next().is_some());
+                assert_eq!(iter.len(), remaining);
+            }
+            assert_eq!(iter.next(), None);
+            assert_eq!(iter.len(), 0);
+
+            let mut into_iter = tree.clone().into_iter();
+            assert_eq!(into_iter.len(), count);
+            into_iter.next();
+            assert_eq!(into_iter.size_hint(), (count.saturating_sub(1), Some(count.saturating_sub(1))));
+        }
+    }
+
+    #[test]
+    fn enumerate_leaves_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let mut iter = tree.enumerate_leaves();
+            assert_eq!(iter.len(), count);
+
+            for (index, value) in values(count).iter().enumerate() {
+                assert_eq!(iter.next(), Some((index, value)));
+                assert_eq!(iter.size_hint(), (count - index - 1, Some(count - index - 1)));
+            }
+            assert_eq!(iter.next(), None);
+
+            if count > 0 {
+                assert_eq!(tree.enumerate_leaves().next_back(), Some((count - 1, &values(count)[count - 1])));
+            }
+        }
+    }
+
+    #[test]
+    fn values_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            assert_eq!(tree.values(), values(count).iter().collect::<Vec<&TreeLeafData>>());
+            assert_eq!(tree.into_values(), values(count));
+        }
+    }
+
+    #[test]
+    fn new_leaf_streaming_works() {
+        let documents = (0..5).map(|i| vec![i as u8; 100 * 1024 + i]).collect::<Vec<Vec<u8>>>();
+
+        let streamed = documents.iter().enumerate()
+            .map(|(i, document)| Tree::new_leaf_streaming(document.as_slice(), format!("doc{}", i).into_bytes()).unwrap())
+            .collect::<Vec<Tree>>();
+        assert_eq!(streamed[2].get_leaf(0), Some(&b"doc2".to_vec()));
+
+        let hashes = streamed.iter().map(|leaf| leaf.hash().clone()).collect();
+        let tree = Tree::from_leaves_with_hashes(hashes).unwrap();
+        let full = Tree::from_leaves(documents.clone()).unwrap();
+        assert_eq!(tree.hash(), full.hash());
+
+        let proof = full.gen_proof_by_index(3).unwrap();
+        assert_eq!(proof.leaf_hash(), streamed[3].hash());
+        assert!(proof.validate_value(&documents[3], tree.hash()).unwrap());
+    }
+
+    #[test]
+    fn verify_proof_works() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let other = Tree::from_leaves(values(count + 1)).unwrap();
+
+            for index in 0..count {
+                let proof = tree.gen_proof_by_index(index).unwrap();
+                assert!(tree.verify_proof(&proof).unwrap());
+                assert!(!other.verify_proof(&proof).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn verify_proof_works_for_forged_lemma() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        // same root, but the stored inner node hash doesn't match the tree
+        let mut proof = tree.gen_proof_by_index(1).unwrap();
+        proof.lemma.sub_lemma.as_mut().unwrap().node_hash = vec![0; 32];
+        assert!(proof.validate_against(tree.hash()).unwrap());
+        assert!(!tree.verify_proof(&proof).unwrap());
+
+        let mut proof = tree.gen_proof_by_index(4).unwrap();
+        proof.lemma.sibling_hash = None;
+        assert!(!tree.verify_proof(&proof).unwrap());
+    }
+
+    #[test]
+    fn into_vec_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let borrowed: Vec<&TreeLeafData> = (&tree).into();
+            assert_eq!(borrowed, values(count).iter().collect::<Vec<&TreeLeafData>>());
+
+            let owned: Vec<TreeLeafData> = tree.into();
+            assert_eq!(owned, values(count));
+        }
+    }
+
+    #[test]
+    fn leaf_hashes_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let expected = tree.iter()
+                .map(|value| Hash::hash_leaf(value).unwrap().to_vec())
+                .collect::<Vec<Vec<u8>>>();
+            let hashes = tree.leaf_hashes()
+                .map(|hash| hash.to_vec())
+                .collect::<Vec<Vec<u8>>>();
+
+            assert_eq!(hashes, expected);
+        }
+    }
+
+    #[test]
+    fn leaf_hashes_sorted_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let sorted = tree.leaf_hashes_sorted();
+
+            let mut expected = tree.leaf_hashes()
+                .map(|hash| hash.to_vec())
+                .collect::<Vec<Vec<u8>>>();
+            expected.sort();
+
+            assert_eq!(sorted, expected);
+            assert!(sorted.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+
+        let duplicated = Tree::from_leaves(vec![b"b".to_vec(), b"a".to_vec(), b"b".to_vec()]).unwrap();
+        assert_eq!(duplicated.leaf_hashes_sorted().len(), 3);
+    }
+
+    #[test]
+    fn iter_with_hashes_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let leaves = tree.iter_with_hashes();
+            assert_eq!(leaves.len(), count);
+
+            for ((value, hash), expected) in leaves.zip(values(count)) {
+                assert_eq!(*value, expected);
+                assert_eq!(hash, Hash::hash_leaf(&expected).unwrap().to_vec().as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_in_range_works() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            for start in 0..count + 1 {
+                for end in start..count + 1 {
+                    let leaves = tree.leaves_in_range(start..end);
+                    assert_eq!(leaves.len(), end - start);
+                    assert_eq!(leaves.cloned().collect::<Vec<TreeLeafData>>(), values(count)[start..end].to_vec());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_in_range_works_for_out_of_bounds() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+
+        assert_eq!(tree.leaves_in_range(3..10).cloned().collect::<Vec<TreeLeafData>>(), values(5)[3..].to_vec());
+        assert_eq!(tree.leaves_in_range(5..10).count(), 0);
+        assert_eq!(tree.leaves_in_range(4..2).count(), 0);
+        assert_eq!(tree.leaves_in_range(4..2).len(), 0);
+    }
+
+    #[test]
+    fn nodes_iterator_works() {
+        let tree = Tree::from_leaves(values(3)).unwrap();
+        let (left, right) = match tree {
+            Tree::Node { ref left, ref right, .. } => (left, right),
+            _ => panic!("expected node")
+        };
+        let (left_left, left_right) = match **left {
+            Tree::Node { ref left, ref right, .. } => (left, right),
+            _ => panic!("expected node")
+        };
+
+        let nodes = tree.nodes().collect::<Vec<&Tree>>();
+        let expected: Vec<&Tree> = vec![&tree, left, left_left, left_right, right];
+        assert_eq!(nodes, expected);
+    }
+
+    #[test]
+    fn nodes_iterator_reports_size_bounds() {
+        for count in 0..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let mut nodes = tree.nodes();
+            let mut remaining = if count == 0 { 1 } else { 2 * count - 1 };
+
+            loop {
+                let (lower, upper) = nodes.size_hint();
+                assert!(lower <= remaining && upper.map_or(true, |upper| remaining <= upper));
+
+                if nodes.next().is_none() {
+                    break;
+                }
+                remaining -= 1;
+            }
+            assert_eq!(remaining, 0);
+        }
+    }
+
+    #[test]
+    fn nodes_iterator_works_for_pruned_tree() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        let pruned = tree.prune_to_proof(2).unwrap();
+
+        // the path to leaf 2 holds 3 nodes, each with a stub sibling
+        assert_eq!(pruned.nodes().count(), 7);
+        assert_eq!(pruned.leaf_hashes().collect::<Vec<&[u8]>>(), vec![tree.leaf_hashes().nth(2).unwrap()]);
+        assert_eq!(pruned.iter_with_hashes().count(), 1);
+        assert!(pruned.clone().split_at(0).is_ok());
+    }
+
+    #[test]
+    fn leaves_iterator_works_backwards() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let mut expected = values(count);
+            expected.reverse();
+
+            assert_eq!(tree.iter().rev().cloned().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn leaves_iterator_works_from_both_ends() {
+        for count in 0..10 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+            let mut iter = tree.iter();
+            let mut front = Vec::new();
+            let mut back = Vec::new();
+
+            loop {
+                match iter.next() {
+                    Some(value) => front.push(value.clone()),
+                    None => break
+                }
+                match iter.next_back() {
+                    Some(value) => back.push(value.clone()),
+                    None => break
+                }
+            }
+
+            back.reverse();
+            front.extend(back);
+            assert_eq!(front, values(count));
+        }
+    }
+
+    #[test]
+    fn display_works() {
+        let tree = Tree::from_leaves(vec![vec![1, 2], vec![3, 4, 5, 6, 7], vec![8]]).unwrap();
+        let h = |tree: &Tree| short_hex(tree.hash());
+
+        let (left, right) = match tree {
+            Tree::Node { ref left, ref right, .. } => (left.clone(), right.clone()),
+            _ => panic!("expected node")
+        };
+        let (left_left, left_right) = match *left {
+            Tree::Node { ref left, ref right, .. } => (left.clone(), right.clone()),
+            _ => panic!("expected node")
+        };
+
+        let expected = format!("Node {}\n+-- Node {}\n|   +-- Leaf {} 0102\n|   `-- Leaf {} 03040506..\n`-- Leaf {} 08\n",
+                               h(&tree), h(&left), h(&left_left), h(&left_right), h(&right));
+        assert_eq!(format!("{}", tree), expected);
+    }
+
+    #[test]
+    fn display_works_for_empty() {
+        let tree = Tree::from_leaves(vec![]).unwrap();
+        assert_eq!(format!("{}", tree), "Empty e3b0c442..\n");
+    }
+
+    #[test]
+    fn display_works_for_deep_tree() {
+        let mut tree = Tree::new_leaf(b"leaf".to_vec()).unwrap();
+        for _ in 0..DISPLAY_MAX_DEPTH + 5 {
+            let leaf = Tree::new_leaf(b"leaf".to_vec()).unwrap();
+            tree = Tree::new_node(leaf, tree).unwrap();
+        }
+
+        let rendered = format!("{}", tree);
+        assert!(rendered.contains("..."));
+        assert_eq!(rendered.lines().count(), 2 * (DISPLAY_MAX_DEPTH + 1) + 1);
+    }
+
+    #[test]
+    fn serialize_works_for_three_level_tree() {
+        let tree = Tree::from_leaves(values(5)).unwrap();
+        assert_eq!(tree.get_height(), 3);
+
+        let serialized = serde_json::to_string(&tree).unwrap();
+        let deserialized: Tree = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(tree, deserialized);
+    }
+
+    #[test]
+    fn root_hash_to_hex_works() {
+        let mut mt = MerkleTree::from_vec(vec![]).unwrap();
+        for value in values(5) {
+            mt.append(value).unwrap();
+        }
+
+        assert_eq!(mt.root.root_hash().to_hex(), mt.root_hash_hex());
+        assert_eq!(format!("{}", mt.root.root_hash()), mt.root_hash_hex());
+    }
 }
@@ -0,0 +1,134 @@
+use services::ledger::merkletree::tree::TreeLeafData;
+
+/// Callbacks for `Tree::walk`, e.g. to aggregate over a tree without a dedicated iterator.
+///
+/// The walk is a depth-first traversal from the root: for a `Node`, `visit_node_pre`
+/// is called first, then the whole left subtree is walked, then the whole right
+/// subtree, and `visit_node_post` is called last. Leaves are thus visited in the
+/// same order as `Tree::iter()` yields them. An `Empty` tree is only met as the root
+/// of a tree without leaves. Every callback does nothing by default.
+pub trait Visitor {
+
+    /// Called on an `Empty` tree.
+    fn visit_empty(&mut self, _hash: &[u8]) {}
+
+    /// Called on a `Leaf`.
+    fn visit_leaf(&mut self, _hash: &[u8], _value: &TreeLeafData) {}
+
+    /// Called on a `Node` before walking its children.
+    fn visit_node_pre(&mut self, _hash: &[u8], _count: usize, _height: usize) {}
+
+    /// Called on a `Node` after walking its children.
+    fn visit_node_post(&mut self, _hash: &[u8], _count: usize, _height: usize) {}
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::ledger::merkletree::tree::{Tree, Positioned};
+
+    fn values(count: usize) -> Vec<TreeLeafData> {
+        (0..count).map(|i| format!("value{}", i).into_bytes()).collect()
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+        values: Vec<TreeLeafData>
+    }
+
+    impl Visitor for Recorder {
+        fn visit_empty(&mut self, _hash: &[u8]) {
+            self.events.push("empty".to_string());
+        }
+
+        fn visit_leaf(&mut self, _hash: &[u8], value: &TreeLeafData) {
+            self.events.push("leaf".to_string());
+            self.values.push(value.clone());
+        }
+
+        fn visit_node_pre(&mut self, _hash: &[u8], count: usize, _height: usize) {
+            self.events.push(format!("pre{}", count));
+        }
+
+        fn visit_node_post(&mut self, _hash: &[u8], count: usize, _height: usize) {
+            self.events.push(format!("post{}", count));
+        }
+    }
+
+    /// Records the path to every leaf, `true` going right.
+    #[derive(Default)]
+    struct PathCollector {
+        current: Vec<bool>,
+        paths: Vec<Vec<bool>>
+    }
+
+    impl PathCollector {
+        fn next_sibling(&mut self) {
+            if let Some(last) = self.current.last_mut() {
+                *last = true;
+            }
+        }
+    }
+
+    impl Visitor for PathCollector {
+        fn visit_leaf(&mut self, _hash: &[u8], _value: &TreeLeafData) {
+            self.paths.push(self.current.clone());
+            self.next_sibling();
+        }
+
+        fn visit_node_pre(&mut self, _hash: &[u8], _count: usize, _height: usize) {
+            self.current.push(false);
+        }
+
+        fn visit_node_post(&mut self, _hash: &[u8], _count: usize, _height: usize) {
+            self.current.pop();
+            self.next_sibling();
+        }
+    }
+
+    #[test]
+    fn walk_works() {
+        let mut recorder = Recorder::default();
+        Tree::from_leaves(values(3)).unwrap().walk(&mut recorder);
+
+        assert_eq!(recorder.events, vec!["pre3", "pre2", "leaf", "leaf", "post2", "leaf", "post3"]);
+        assert_eq!(recorder.values, values(3));
+    }
+
+    #[test]
+    fn walk_works_for_empty_tree() {
+        let mut recorder = Recorder::default();
+        Tree::from_leaves(vec![]).unwrap().walk(&mut recorder);
+
+        assert_eq!(recorder.events, vec!["empty"]);
+    }
+
+    #[test]
+    fn walk_visits_leaves_in_order() {
+        for count in 1..12 {
+            let tree = Tree::from_leaves(values(count)).unwrap();
+
+            let mut recorder = Recorder::default();
+            tree.walk(&mut recorder);
+            assert_eq!(recorder.values, values(count));
+
+            let mut collector = PathCollector::default();
+            tree.walk(&mut collector);
+
+            assert_eq!(collector.paths.len(), count);
+
+            for (index, path) in collector.paths.iter().enumerate() {
+                let path = path.iter()
+                    .map(|&right| if right { Positioned::Right(()) } else { Positioned::Left(()) })
+                    .collect::<Vec<_>>();
+
+                match *tree.subtree_at_path(&path).unwrap() {
+                    Tree::Leaf { ref value, .. } => assert_eq!(*value, values(count)[index]),
+                    _ => panic!("path {} doesn't lead to a leaf", index)
+                }
+            }
+        }
+    }
+}
@@ -712,6 +712,7 @@ pub mod tests {
     use services::pool::networker::MockNetworker;
     use services::pool::types::{ConsistencyProof, LedgerStatus, Reply, ReplyResultV1, ReplyTxnV1, ReplyV1, Response, ResponseMetadata, ResponseV1};
     use super::*;
+    use utils::crypto::hash::{Hash, MerkleHasher};
     use utils::test;
 
     const MESSAGE: &'static str = "message";
@@ -758,6 +759,7 @@ pub mod tests {
                 height: 0,
                 count: 0,
                 nodes_count: 0,
+                algorithm: Hash {}.algorithm(),
             }
         }
     }
@@ -1300,6 +1302,7 @@ pub mod tests {
                 height: 0,
                 count: 1,
                 nodes_count: 0,
+                algorithm: Hash {}.algorithm(),
             };
 
             request_handler.process_event(Some(RequestEvent::CatchupReq(mt, 2, vec![55, 104, 239, 91, 37, 160, 29, 25, 192, 253, 166, 135, 242, 53, 75, 41, 224, 4, 130, 27, 206, 133, 87, 231, 0, 133, 55, 159, 83, 105, 7, 237])));
@@ -1,20 +1,49 @@
 extern crate openssl;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 
 use errors::common::CommonError;
 
 use self::openssl::hash::{hash as openssl_hash, MessageDigest, Hasher, DigestBytes};
 use self::openssl::error::ErrorStack;
+use self::openssl::memcmp;
 
 use std::error::Error;
+use std::io::{self, Read};
+
+#[cfg(feature = "rayon")]
+use self::rayon::prelude::*;
 
 pub const HASHBYTES: usize = 32;
 
+/// Size of the chunks read by `Hash::hash_leaf_reader`.
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Length in bytes of the hashes of `Sha512Hash`.
+pub const SHA512_HASHBYTES: usize = 64;
+
+/// Length in bytes of the hashes of `Sha384Hash`.
+pub const SHA384_HASHBYTES: usize = 48;
+
+/// Root hash of a tree without leaves: the SHA-256 digest of the empty input,
+/// not `HASHBYTES` zero bytes, as in RFC 6962.
+pub const EMPTY_HASH: [u8; HASHBYTES] = [
+    0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+    0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55
+];
+
 pub fn hash(input: &[u8]) -> Result<Vec<u8>, CommonError> {
     let mut hasher = Hash::new_context()?;
     hasher.update(input)?;
     Ok(hasher.finish().map(|b| b.to_vec())?)
 }
 
+/// Compares two hashes in constant time with regard to their content.
+/// Hashes of different lengths are never equal.
+pub fn eq_ct(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
 pub struct Digest {
     data: DigestBytes
 }
@@ -29,8 +58,17 @@ impl Digest {
     pub fn to_vec(&self) -> Vec<u8> {
         self.data.to_vec()
     }
+
+    /// Copies the digest to a fixed-size array, without any heap allocation.
+    /// Digests of `Hash` are SHA-256 ones, so always `HASHBYTES` long.
+    pub fn to_array(&self) -> [u8; HASHBYTES] {
+        let mut array = [0; HASHBYTES];
+        array.copy_from_slice(&self.data);
+        array
+    }
 }
 
+#[derive(Clone, Debug)]
 pub struct Hash {}
 
 impl Hash {
@@ -38,11 +76,17 @@ impl Hash {
         Ok(Hasher::new(MessageDigest::sha256())?)
     }
 
+    /// Hashes the empty input, which gives `EMPTY_HASH`.
     pub fn hash_empty() -> Result<Digest, CommonError> {
         Ok(Digest::new(openssl_hash(MessageDigest::sha256(), &[])?))
+    }
 
+    /// Returns `EMPTY_HASH`, the root hash of a tree without leaves, without hashing.
+    pub fn empty_hash() -> Vec<u8> {
+        EMPTY_HASH.to_vec()
     }
 
+    /// Hashes a leaf value prefixed with `0x00`, per RFC 6962 domain separation.
     pub fn hash_leaf<T>(leaf: &T) -> Result<Digest, CommonError> where T: Hashable {
         let mut ctx = Hash::new_context()?;
         ctx.update(&[0x00])?;
@@ -50,6 +94,36 @@ impl Hash {
         Ok(Digest::new(ctx.finish()?))
     }
 
+    /// Same as `hash_leaf` for a value read from `reader` up to its end, chunk by chunk,
+    /// so the value never has to be held in memory as a whole.
+    pub fn hash_leaf_reader<R: Read>(reader: &mut R) -> Result<Digest, CommonError> {
+        let mut ctx = Hash::new_context()?;
+        ctx.update(&[0x00])?;
+
+        let mut chunk = vec![0; READ_CHUNK_BYTES];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => ctx.update(&chunk[..len])?,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(CommonError::from(err))
+            }
+        }
+
+        Ok(Digest::new(ctx.finish()?))
+    }
+
+    /// Hashes each of `leaves` as `hash_leaf` does, in the same order.
+    ///
+    /// With the `rayon` feature the leaves are hashed in parallel, for callers who only
+    /// need the leaf hashes of a big batch, e.g. to build a tree with `from_leaves_with_hashes`.
+    pub fn hash_leaves_batch<T: AsRef<[u8]> + Sync>(leaves: &[T]) -> Result<Vec<Vec<u8>>, CommonError> {
+        hash_leaves_batch_with_hasher(&Hash {}, leaves)
+    }
+
+    /// Combines the hashes of two children the way a tree node does:
+    /// `SHA-256(0x01 || left || right)`, with the left child first,
+    /// per RFC 6962 domain separation.
     pub fn hash_nodes<T>(left: &T, right: &T) -> Result<Digest, CommonError> where T: Hashable {
         let mut ctx = Hash::new_context()?;
         ctx.update(&[0x01])?;
@@ -58,6 +132,169 @@ impl Hash {
         Ok(Digest::new(ctx.finish()?))
     }
 
+    /// Namespaces a tree root under a domain `tag`: `SHA-256(0x02 || tag || root)`.
+    ///
+    /// The `0x02` prefix keeps tagged roots apart from leaf and node hashes, and
+    /// since the root always takes the last `HASHBYTES` bytes, distinct tags
+    /// never give the same input.
+    pub fn hash_tagged_root<T>(tag: &T, root: &T) -> Result<Digest, CommonError> where T: Hashable {
+        let mut ctx = Hash::new_context()?;
+        ctx.update(&[0x02])?;
+        tag.update_context(&mut ctx)?;
+        root.update_context(&mut ctx)?;
+        Ok(Digest::new(ctx.finish()?))
+    }
+
+}
+
+/// Hashing scheme of a Merkle tree.
+///
+/// `Hash` implements it with SHA-256 and RFC 6962 domain separation,
+/// other implementations allow building trees with another digest.
+pub trait MerkleHasher {
+
+    /// Hashes a leaf value.
+    fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError>;
+
+    /// Combines the hashes of two children nodes, left child first.
+    fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError>;
+
+    /// Returns the hash of a tree without leaves.
+    ///
+    /// By convention this is the digest of the empty input, e.g. `EMPTY_HASH` for SHA-256,
+    /// never a run of zero bytes, so that every party agrees on the root of an empty ledger.
+    fn hash_empty(&self) -> Result<Vec<u8>, CommonError>;
+
+    /// Namespaces a tree root under a domain `tag`, see `Hash::hash_tagged_root`.
+    fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError>;
+
+    /// Returns the length in bytes of the hashes, `HASHBYTES` by default.
+    fn digest_len(&self) -> usize {
+        HASHBYTES
+    }
+
+    /// Returns the name of the hashing scheme, e.g. `"sha256"` for `Hash`,
+    /// which tells apart trees built with different hashers.
+    fn algorithm(&self) -> &'static str;
+
+}
+
+impl MerkleHasher for Hash {
+
+    fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError> {
+        Ok(Hash::hash_leaf(&leaf)?.to_vec())
+    }
+
+    fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError> {
+        Ok(Hash::hash_nodes(&left, &right)?.to_vec())
+    }
+
+    fn hash_empty(&self) -> Result<Vec<u8>, CommonError> {
+        Ok(Hash::empty_hash())
+    }
+
+    fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError> {
+        Ok(Hash::hash_tagged_root(&tag, &root)?.to_vec())
+    }
+
+    fn algorithm(&self) -> &'static str {
+        "sha256"
+    }
+
+}
+
+/// `MerkleHasher` with SHA-512 and the same domain separation as `Hash`.
+#[derive(Clone, Debug)]
+pub struct Sha512Hash {}
+
+impl MerkleHasher for Sha512Hash {
+
+    fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hash_prefixed(MessageDigest::sha512(), 0x00, &[leaf])
+    }
+
+    fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hash_prefixed(MessageDigest::sha512(), 0x01, &[left, right])
+    }
+
+    fn hash_empty(&self) -> Result<Vec<u8>, CommonError> {
+        Ok(openssl_hash(MessageDigest::sha512(), &[])?.to_vec())
+    }
+
+    fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hash_prefixed(MessageDigest::sha512(), 0x02, &[tag, root])
+    }
+
+    fn digest_len(&self) -> usize {
+        SHA512_HASHBYTES
+    }
+
+    fn algorithm(&self) -> &'static str {
+        "sha512"
+    }
+
+}
+
+/// `MerkleHasher` with SHA-384 and the same domain separation as `Hash`.
+#[derive(Clone, Debug)]
+pub struct Sha384Hash {}
+
+impl MerkleHasher for Sha384Hash {
+
+    fn hash_leaf(&self, leaf: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hash_prefixed(MessageDigest::sha384(), 0x00, &[leaf])
+    }
+
+    fn hash_nodes(&self, left: &[u8], right: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hash_prefixed(MessageDigest::sha384(), 0x01, &[left, right])
+    }
+
+    fn hash_empty(&self) -> Result<Vec<u8>, CommonError> {
+        Ok(openssl_hash(MessageDigest::sha384(), &[])?.to_vec())
+    }
+
+    fn hash_tagged_root(&self, tag: &[u8], root: &[u8]) -> Result<Vec<u8>, CommonError> {
+        hash_prefixed(MessageDigest::sha384(), 0x02, &[tag, root])
+    }
+
+    fn digest_len(&self) -> usize {
+        SHA384_HASHBYTES
+    }
+
+    fn algorithm(&self) -> &'static str {
+        "sha384"
+    }
+
+}
+
+/// Same as `Hash::hash_leaves_batch` with any `hasher`.
+#[cfg(feature = "rayon")]
+pub fn hash_leaves_batch_with_hasher<H, T>(hasher: &H, leaves: &[T]) -> Result<Vec<Vec<u8>>, CommonError>
+    where H: MerkleHasher + Sync, T: AsRef<[u8]> + Sync
+{
+    leaves.par_iter()
+        .map(|leaf| hasher.hash_leaf(leaf.as_ref()))
+        .collect()
+}
+
+/// Same as `Hash::hash_leaves_batch` with any `hasher`.
+#[cfg(not(feature = "rayon"))]
+pub fn hash_leaves_batch_with_hasher<H, T>(hasher: &H, leaves: &[T]) -> Result<Vec<Vec<u8>>, CommonError>
+    where H: MerkleHasher + Sync, T: AsRef<[u8]> + Sync
+{
+    leaves.iter()
+        .map(|leaf| hasher.hash_leaf(leaf.as_ref()))
+        .collect()
+}
+
+/// Hashes `prefix || parts[0] || parts[1] ...` with `digest`.
+fn hash_prefixed(digest: MessageDigest, prefix: u8, parts: &[&[u8]]) -> Result<Vec<u8>, CommonError> {
+    let mut ctx = Hasher::new(digest)?;
+    ctx.update(&[prefix])?;
+    for part in parts {
+        ctx.update(part)?;
+    }
+    Ok(ctx.finish()?.to_vec())
 }
 
 /// The type of values stored in a `MerkleTree` must implement
@@ -102,3 +339,111 @@ impl From<ErrorStack> for CommonError {
         CommonError::InvalidStructure(err.description().to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_leaf_does_not_collide_with_hash_nodes() {
+        // A leaf crafted as the concatenation of two children hashes
+        // must not hash to their parent, or a tree could be forged.
+        let left = Hash::hash_leaf(&"left").unwrap().to_vec();
+        let right = Hash::hash_leaf(&"right").unwrap().to_vec();
+
+        let mut crafted = left.clone();
+        crafted.extend_from_slice(&right);
+
+        let parent = Hash::hash_nodes(&left, &right).unwrap().to_vec();
+        let crafted_leaf = Hash::hash_leaf(&crafted).unwrap().to_vec();
+
+        assert_ne!(parent, crafted_leaf);
+        assert_ne!(hash(&crafted).unwrap(), parent);
+    }
+
+    #[test]
+    fn hash_nodes_works() {
+        let left = Hash::hash_leaf(&"left").unwrap().to_vec();
+        let right = Hash::hash_leaf(&"right").unwrap().to_vec();
+
+        let mut input = vec![0x01];
+        input.extend_from_slice(&left);
+        input.extend_from_slice(&right);
+
+        assert_eq!(Hash::hash_nodes(&left, &right).unwrap().to_vec(), hash(&input).unwrap());
+        assert_ne!(Hash::hash_nodes(&right, &left).unwrap().to_vec(), hash(&input).unwrap());
+    }
+
+    #[test]
+    fn hash_tagged_root_works() {
+        let root = Hash::hash_leaf(&"leaf").unwrap().to_vec();
+
+        let mut input = vec![0x02];
+        input.extend_from_slice(b"domain");
+        input.extend_from_slice(&root);
+
+        assert_eq!(Hash::hash_tagged_root(&b"domain".to_vec(), &root).unwrap().to_vec(), hash(&input).unwrap());
+        assert_ne!(Hash::hash_tagged_root(&b"pool".to_vec(), &root).unwrap().to_vec(), hash(&input).unwrap());
+    }
+
+    #[test]
+    fn empty_hash_works() {
+        assert_eq!(Hash::hash_empty().unwrap().to_vec(), EMPTY_HASH.to_vec());
+        assert_eq!(hash(&[]).unwrap(), Hash::empty_hash());
+        assert_eq!(MerkleHasher::hash_empty(&Hash {}).unwrap(), Hash::empty_hash());
+    }
+
+    #[test]
+    fn sha512_and_sha384_hashers_work() {
+        let sha512 = Sha512Hash {};
+        let sha384 = Sha384Hash {};
+
+        assert_eq!(sha512.hash_empty().unwrap().len(), sha512.digest_len());
+        assert_eq!(sha384.hash_empty().unwrap().len(), sha384.digest_len());
+
+        let left = sha512.hash_leaf(b"left").unwrap();
+        let right = sha512.hash_leaf(b"right").unwrap();
+        assert_eq!(left.len(), SHA512_HASHBYTES);
+        assert_ne!(sha512.hash_nodes(&left, &right).unwrap(), sha512.hash_nodes(&right, &left).unwrap());
+        assert_eq!(sha384.hash_leaf(b"left").unwrap().len(), SHA384_HASHBYTES);
+    }
+
+    #[test]
+    fn hash_leaf_reader_works() {
+        let value = (0..3 * READ_CHUNK_BYTES + 5).map(|i| i as u8).collect::<Vec<u8>>();
+
+        let streamed = Hash::hash_leaf_reader(&mut io::Cursor::new(&value)).unwrap();
+        assert_eq!(streamed.to_vec(), Hash::hash_leaf(&value).unwrap().to_vec());
+
+        let empty = Hash::hash_leaf_reader(&mut io::empty()).unwrap();
+        assert_eq!(empty.to_vec(), Hash::hash_leaf(&"").unwrap().to_vec());
+    }
+
+    #[test]
+    fn hash_leaves_batch_works() {
+        let leaves = (0..300).map(|i| format!("value{}", i).into_bytes()).collect::<Vec<Vec<u8>>>();
+
+        let expected = leaves.iter()
+            .map(|leaf| Hash::hash_leaf(leaf).unwrap().to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        assert_eq!(Hash::hash_leaves_batch(&leaves).unwrap(), expected);
+
+        let expected = leaves.iter()
+            .map(|leaf| Sha512Hash {}.hash_leaf(leaf).unwrap())
+            .collect::<Vec<Vec<u8>>>();
+        assert_eq!(hash_leaves_batch_with_hasher(&Sha512Hash {}, &leaves).unwrap(), expected);
+
+        assert!(Hash::hash_leaves_batch::<Vec<u8>>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn eq_ct_works() {
+        let a = hash(b"a").unwrap();
+        let b = hash(b"b").unwrap();
+
+        assert!(eq_ct(&a, &a.clone()));
+        assert!(!eq_ct(&a, &b));
+        assert!(!eq_ct(&a, &a[..31]));
+        assert!(eq_ct(&[], &[]));
+    }
+}